@@ -18,13 +18,59 @@ mod inner {
             help = "output file path"
         )]
         output: std::path::PathBuf,
+        #[clap(
+            short = 'f',
+            long = "format",
+            default_value = "v2",
+            help = "dump format version"
+        )]
+        format: Format,
+        #[clap(
+            long = "sparse",
+            help = "only dump the memory used by a Linux guest, without its direct map"
+        )]
+        sparse: bool,
+    }
+
+    #[derive(clap::ValueEnum, Clone, Copy, Debug)]
+    enum Format {
+        V1,
+        V2,
     }
 
     pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         env_logger::init();
         let args = Args::parse();
         let vm = kvm::Kvm::with_default_qemu_mappings(args.pid)?;
-        kvm_dump::DumbDump::dump_vm(&vm)?.write(args.output)?;
+        let format = match args.format {
+            Format::V1 => kvm_dump::Format::V1,
+            Format::V2 => kvm_dump::Format::V2,
+        };
+
+        let metadata = kvm_dump::Metadata::detect(&vm);
+        let mut writer = kvm_dump::DumpWriter::new(&vm).with_format(format);
+
+        if args.sparse {
+            if metadata.os != Some(kvm_dump::OsKind::Linux) {
+                return Err("sparse dumps require a Linux guest".into());
+            }
+            let mappings =
+                kvm_dump::SparseCapture::new().collect_linux(&vm, metadata.os_builder())?;
+            writer = writer.with_mappings(mappings);
+        }
+
+        let mut last_percent = None;
+        writer
+            .with_metadata(metadata)
+            .with_progress(|done, total| {
+                let percent = done * 100 / total;
+                if last_percent != Some(percent) {
+                    eprint!("\rDumping memory: {percent}%");
+                    last_percent = Some(percent);
+                }
+            })
+            .write(args.output)?;
+        eprintln!();
         Ok(())
     }
 }
@@ -107,6 +107,36 @@ pub fn get_special_regs(vcpu_fd: i32) -> io::Result<special_regs> {
     })
 }
 
+const NAMED_REGS: [(u64, &str); 13] = [
+    (0x603000000010003e, "sp_el0"),
+    (0x6030000000100046, "elr_el1"),
+    (0x6030000000100048, "spsr_el1"),
+    (0x603000000013c080, "sctlr_el1"),
+    (0x603000000013c102, "tcr_el1"),
+    (0x603000000013c290, "esr_el1"),
+    (0x603000000013c300, "far_el1"),
+    (0x603000000013c510, "mair_el1"),
+    (0x603000000013c684, "tpidr_el1"),
+    (0x603000000013de82, "tpidr_el0"),
+    (0x603000000013de83, "tpidrro_el0"),
+    (0x603000000013c101, "ttbr1_el1"),
+    (0x603000000013c100, "ttbr0_el1"),
+];
+
+/// Reads registers that are not part of the fixed structures.
+///
+/// Registers that the host cannot provide are skipped.
+pub fn get_named_regs(vcpu_fd: i32) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let regs = NAMED_REGS
+        .iter()
+        .filter_map(|&(id, name)| {
+            let value = get_one_reg(vcpu_fd, id).ok()?;
+            Some((String::from(name), value.to_ne_bytes().to_vec()))
+        })
+        .collect();
+    Ok(regs)
+}
+
 fn get_one_reg(vcpu_fd: i32, id: u64) -> io::Result<u64> {
     unsafe {
         let mut reg = 0u64;
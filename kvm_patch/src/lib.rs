@@ -1,9 +1,13 @@
 #![cfg(target_os = "linux")]
 
 use std::{
-    io::{self, Write},
-    os::unix::net::UnixStream,
-    slice,
+    io::{self, Read, Write},
+    os::{
+        fd::AsRawFd,
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixStream},
+    },
+    slice, thread,
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -14,18 +18,75 @@ mod kvm;
 #[path = "aarch64.rs"]
 mod kvm;
 
+mod memslots;
+
+/// Asks the agent for the registers of all vCPUs.
+///
+/// This must be kept in sync with vminer's KVM backend.
+const REQUEST_REGISTERS: u8 = 1;
+
+/// Asks the agent for the memory slots of the VM.
+const REQUEST_MEMORY_SLOTS: u8 = 2;
+
+/// Asks the agent for the named registers of all vCPUs.
+const REQUEST_NAMED_REGISTERS: u8 = 3;
+
+/// Starts a thread that serves requests until the socket is closed.
+///
+/// The socket is in the abstract namespace, and must have been bound by the
+/// process `vminer_pid`. The library must stay loaded as long as the agent
+/// runs.
 #[unsafe(no_mangle)]
 #[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn vminer_payload(vcpus: *const i32, n: usize) -> libc::c_int {
-    let vcpus = unsafe { slice::from_raw_parts(vcpus, n) };
-    match send_fds(vcpus) {
+pub unsafe extern "C" fn vminer_agent(
+    vcpus: *const i32,
+    n: usize,
+    socket_name: *const u8,
+    socket_name_len: usize,
+    vminer_pid: libc::pid_t,
+) -> libc::c_int {
+    let vcpus = unsafe { slice::from_raw_parts(vcpus, n) }.to_vec();
+    let socket_name = unsafe { slice::from_raw_parts(socket_name, socket_name_len) };
+    match start_agent(vcpus, socket_name, vminer_pid) {
         Ok(()) => 0,
         Err(e) => e.raw_os_error().unwrap_or(777),
     }
 }
 
-fn send_fds(vcpus: &[i32]) -> io::Result<()> {
-    let mut socket = UnixStream::connect("/tmp/get_fds")?;
+/// Connects to vminer, making sure that we talk to the process that injected
+/// us.
+fn connect(socket_name: &[u8], vminer_pid: libc::pid_t) -> io::Result<UnixStream> {
+    let addr = SocketAddr::from_abstract_name(socket_name)?;
+    let socket = UnixStream::connect_addr(&addr)?;
+
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if cred.pid != vminer_pid {
+        return Err(io::Error::from_raw_os_error(libc::EPERM));
+    }
+
+    Ok(socket)
+}
+
+fn get_registers(vcpus: &[i32]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
 
     #[cfg(target_arch = "x86_64")]
     for &vcpu in vcpus {
@@ -33,9 +94,9 @@ fn send_fds(vcpus: &[i32]) -> io::Result<()> {
         let sregs = kvm::get_sregs(vcpu)?;
         let msrs = kvm::get_msrs(vcpu)?;
 
-        socket.write_all(bytemuck::bytes_of(&regs))?;
-        socket.write_all(bytemuck::bytes_of(&sregs))?;
-        socket.write_all(bytemuck::bytes_of(&msrs))?;
+        buf.extend_from_slice(bytemuck::bytes_of(&regs));
+        buf.extend_from_slice(bytemuck::bytes_of(&sregs));
+        buf.extend_from_slice(bytemuck::bytes_of(&msrs));
     }
 
     #[cfg(target_arch = "aarch64")]
@@ -43,9 +104,91 @@ fn send_fds(vcpus: &[i32]) -> io::Result<()> {
         let regs = kvm::get_regs(vcpu)?;
         let sregs = kvm::get_special_regs(vcpu)?;
 
-        socket.write_all(bytemuck::bytes_of(&regs))?;
-        socket.write_all(bytemuck::bytes_of(&sregs))?;
+        buf.extend_from_slice(bytemuck::bytes_of(&regs));
+        buf.extend_from_slice(bytemuck::bytes_of(&sregs));
     }
 
+    Ok(buf)
+}
+
+/// For each vCPU, returns the number of registers followed by the registers.
+///
+/// Each register is encoded as the length of its name (u8), its name, the
+/// size of its value (u32) and its value, so that registers can be added
+/// without breaking the protocol.
+fn get_named_registers(vcpus: &[i32]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    for &vcpu in vcpus {
+        let regs = kvm::get_named_regs(vcpu)?;
+        buf.extend_from_slice(&(regs.len() as u32).to_ne_bytes());
+
+        for (name, value) in regs {
+            buf.push(name.len() as u8);
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(&(value.len() as u32).to_ne_bytes());
+            buf.extend_from_slice(&value);
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Returns the number of slots followed by the slots.
+fn get_memory_slots() -> Vec<u8> {
+    let slots = memslots::memory_slots();
+    let mut buf = (slots.len() as u64).to_ne_bytes().to_vec();
+    buf.extend_from_slice(bytemuck::cast_slice(&slots));
+    buf
+}
+
+fn start_agent(vcpus: Vec<i32>, socket_name: &[u8], vminer_pid: libc::pid_t) -> io::Result<()> {
+    // Connect before returning, so that errors are reported to the caller
+    let socket = connect(socket_name, vminer_pid)?;
+
+    thread::Builder::new()
+        .name(String::from("vminer-agent"))
+        .spawn(move || run_agent(socket, &vcpus))?;
+
     Ok(())
 }
+
+/// Sends the ID of the agent's thread, then answers each request with a
+/// status (0 or an errno) followed by the data.
+fn run_agent(mut socket: UnixStream, vcpus: &[i32]) {
+    // This thread must keep running when vminer pauses the VM
+    let tid = unsafe { libc::gettid() };
+    if socket.write_all(&tid.to_ne_bytes()).is_err() {
+        return;
+    }
+
+    let mut request = [0];
+
+    loop {
+        match socket.read(&mut request) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+
+        let response = match request[0] {
+            REQUEST_REGISTERS => get_registers(vcpus),
+            REQUEST_MEMORY_SLOTS => Ok(get_memory_slots()),
+            REQUEST_NAMED_REGISTERS => get_named_registers(vcpus),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        let result = match response {
+            Ok(data) => socket
+                .write_all(&0i32.to_ne_bytes())
+                .and_then(|()| socket.write_all(&data)),
+            Err(err) => {
+                let errno: i32 = err.raw_os_error().unwrap_or(777);
+                socket.write_all(&errno.to_ne_bytes())
+            }
+        };
+
+        if result.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Records the memory slots that the VMM gives to KVM.
+//!
+//! KVM has no way to query memory slots, so calls to `ioctl` are intercepted
+//! to keep track of them. This only works if this library is loaded when the
+//! VMM starts (with `LD_PRELOAD`), as slots are usually set up at this time.
+
+use bytemuck::{Pod, Zeroable};
+use std::sync::{Mutex, OnceLock};
+
+pub const KVM_SET_USER_MEMORY_REGION: libc::c_ulong = 1075883590;
+pub const KVM_SET_USER_MEMORY_REGION2: libc::c_ulong = 1084272201;
+
+/// Common header of `kvm_userspace_memory_region` and
+/// `kvm_userspace_memory_region2`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct kvm_userspace_memory_region {
+    slot: u32,
+    flags: u32,
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+}
+
+/// This must be kept in sync with vminer's KVM backend.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct MemorySlot {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+}
+
+/// Memory slots by ID
+static SLOTS: Mutex<Vec<(u32, MemorySlot)>> = Mutex::new(Vec::new());
+
+/// Returns the known memory slots, sorted by guest address.
+pub fn memory_slots() -> Vec<MemorySlot> {
+    let slots = SLOTS.lock().unwrap_or_else(|err| err.into_inner());
+    let mut slots: Vec<_> = slots.iter().map(|&(_, slot)| slot).collect();
+    slots.sort_unstable_by_key(|slot| slot.guest_phys_addr);
+    slots
+}
+
+fn record(region: &kvm_userspace_memory_region) {
+    // Other address spaces are for System Management Mode
+    if region.slot >> 16 != 0 {
+        return;
+    }
+
+    let mut slots = SLOTS.lock().unwrap_or_else(|err| err.into_inner());
+    slots.retain(|&(id, _)| id != region.slot);
+
+    // A slot with a size of zero is deleted
+    if region.memory_size != 0 {
+        let slot = MemorySlot {
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            userspace_addr: region.userspace_addr,
+        };
+        slots.push((region.slot, slot));
+    }
+}
+
+type IoctlFn = unsafe extern "C" fn(libc::c_int, libc::c_ulong, *mut libc::c_void) -> libc::c_int;
+
+/// Replaces the `ioctl` of the libc.
+///
+/// `ioctl` is variadic, but in practice it is always called with one argument,
+/// which is passed the same way as with a regular function.
+#[unsafe(no_mangle)]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn ioctl(
+    fd: libc::c_int,
+    request: libc::c_ulong,
+    arg: *mut libc::c_void,
+) -> libc::c_int {
+    static REAL_IOCTL: OnceLock<Option<IoctlFn>> = OnceLock::new();
+
+    let real_ioctl = REAL_IOCTL.get_or_init(|| unsafe {
+        let real = libc::dlsym(libc::RTLD_NEXT, c"ioctl".as_ptr());
+        (!real.is_null()).then(|| std::mem::transmute::<*mut libc::c_void, IoctlFn>(real))
+    });
+    let Some(real_ioctl) = real_ioctl else {
+        unsafe { *libc::__errno_location() = libc::ENOSYS };
+        return -1;
+    };
+
+    let ret = unsafe { real_ioctl(fd, request, arg) };
+
+    if ret == 0
+        && matches!(
+            request,
+            KVM_SET_USER_MEMORY_REGION | KVM_SET_USER_MEMORY_REGION2
+        )
+    {
+        record(unsafe { &*arg.cast() });
+    }
+
+    ret
+}
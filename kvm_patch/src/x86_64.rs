@@ -143,3 +143,126 @@ pub fn get_msrs(vcpu_fd: i32) -> io::Result<msrs> {
         kernel_gs_base: msrs.entries[1].data,
     })
 }
+
+pub const KVM_GET_FPU: u64 = 2174791308;
+pub const KVM_GET_DEBUGREGS: u64 = 2155916961;
+pub const KVM_GET_XSAVE: u64 = 2415963812;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct kvm_fpu {
+    fpr: [[u8; 16]; 8],
+    fcw: u16,
+    fsw: u16,
+    ftwx: u8,
+    pad1: u8,
+    last_opcode: u16,
+    last_ip: u64,
+    last_dp: u64,
+    xmm: [[u8; 16]; 16],
+    mxcsr: u32,
+    pad2: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct kvm_debugregs {
+    db: [u64; 4],
+    dr6: u64,
+    dr7: u64,
+    flags: u64,
+    reserved: [u64; 9],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct kvm_xsave {
+    region: [u32; 1024],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct kvm_named_msrs {
+    nmsrs: u32,
+    pad: u32,
+
+    entries: [kvm_msr_entry; NAMED_MSRS.len()],
+}
+
+const NAMED_MSRS: [(u32, &str); 12] = [
+    (0xc0000080, "efer"),
+    (0xc0000081, "star"),
+    (0xc0000082, "lstar"),
+    (0xc0000083, "cstar"),
+    (0xc0000084, "sfmask"),
+    (0xc0000100, "fs_base"),
+    (0xc0000101, "gs_base"),
+    (0xc0000102, "kernel_gs_base"),
+    (0xc0000103, "tsc_aux"),
+    (0x174, "sysenter_cs"),
+    (0x175, "sysenter_esp"),
+    (0x176, "sysenter_eip"),
+];
+
+/// Reads registers that are not part of the fixed structures.
+///
+/// Registers that the host cannot provide are skipped.
+pub fn get_named_regs(vcpu_fd: i32) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut regs = Vec::new();
+    let mut push = |name: &str, value: &[u8]| regs.push((String::from(name), value.to_vec()));
+
+    let mut start = 0;
+    while start < NAMED_MSRS.len() {
+        let remaining = &NAMED_MSRS[start..];
+        let mut msrs = kvm_named_msrs::zeroed();
+        msrs.nmsrs = remaining.len() as u32;
+        for (entry, &(index, _)) in msrs.entries.iter_mut().zip(remaining) {
+            entry.index = index;
+        }
+        // This returns the number of MSRs read before the first failure
+        let read = unsafe { libc::ioctl(vcpu_fd, KVM_GET_MSRS, &mut msrs) };
+        check!(read)?;
+        for (entry, &(_, name)) in msrs.entries.iter().zip(remaining).take(read as usize) {
+            push(name, &entry.data.to_ne_bytes());
+        }
+
+        // Skip the MSR that failed, and read the next ones again
+        start += read as usize + 1;
+    }
+
+    let mut debugregs = kvm_debugregs::zeroed();
+    if unsafe { libc::ioctl(vcpu_fd, KVM_GET_DEBUGREGS, &mut debugregs) } != -1 {
+        for (i, db) in debugregs.db.iter().enumerate() {
+            push(&format!("dr{i}"), &db.to_ne_bytes());
+        }
+        push("dr6", &debugregs.dr6.to_ne_bytes());
+        push("dr7", &debugregs.dr7.to_ne_bytes());
+    }
+
+    // This fails for guests with protected state
+    let mut fpu = kvm_fpu::zeroed();
+    if unsafe { libc::ioctl(vcpu_fd, KVM_GET_FPU, &mut fpu) } != -1 {
+        push("fcw", &fpu.fcw.to_ne_bytes());
+        push("fsw", &fpu.fsw.to_ne_bytes());
+        push("ftw", &fpu.ftwx.to_ne_bytes());
+        push("fop", &fpu.last_opcode.to_ne_bytes());
+        push("fip", &fpu.last_ip.to_ne_bytes());
+        push("fdp", &fpu.last_dp.to_ne_bytes());
+        push("mxcsr", &fpu.mxcsr.to_ne_bytes());
+        for (i, st) in fpu.fpr.iter().enumerate() {
+            // x87 registers are 80 bits wide
+            push(&format!("st{i}"), &st[..10]);
+        }
+        for (i, xmm) in fpu.xmm.iter().enumerate() {
+            push(&format!("xmm{i}"), xmm);
+        }
+    }
+
+    // XSAVE is not available on all hosts
+    let mut xsave = Box::new(kvm_xsave::zeroed());
+    if unsafe { libc::ioctl(vcpu_fd, KVM_GET_XSAVE, &mut *xsave) } != -1 {
+        push("xsave", bytemuck::bytes_of(&*xsave));
+    }
+
+    Ok(regs)
+}
@@ -14,14 +14,23 @@ pub struct MemoryMapping {
     len: usize,
 }
 
-pub struct Backend(pub Arc<dyn vmc::Backend<Arch = vmc::arch::RuntimeArchitecture> + Send + Sync>);
+pub struct Backend(
+    pub Arc<dyn vmc::Backend<Arch = vmc::arch::RuntimeArchitecture> + Send + Sync>,
+    /// What a dump knows about its guest OS
+    #[cfg(feature = "std")]
+    pub Option<vminer::backends::kvm_dump::Metadata>,
+);
 
 impl Backend {
     fn new<B>(backend: B) -> Box<Self>
     where
         B: vmc::Backend + Send + Sync + 'static,
     {
-        Box::new(Self(Arc::new(vmc::RuntimeBackend(backend))))
+        Box::new(Self(
+            Arc::new(vmc::RuntimeBackend(backend)),
+            #[cfg(feature = "std")]
+            None,
+        ))
     }
 }
 
@@ -233,7 +242,10 @@ pub unsafe extern "C" fn read_dump(path: *const c_char) -> Option<Box<Backend>>
     error::wrap_box(|| {
         let path = unsafe { CStr::from_ptr(path) };
         let dump = vminer::backends::kvm_dump::DumbDump::read(path.to_str()?)?;
-        Ok(Backend::new(dump))
+        let metadata = dump.metadata().os.map(|_| dump.metadata().clone());
+        let mut backend = Backend::new(dump);
+        backend.1 = metadata;
+        Ok(backend)
     })
 }
 
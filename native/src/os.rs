@@ -104,6 +104,31 @@ impl Os {
     fn new(backend: Backend, symbols: vmc::SymbolsIndexer) -> VmResult<Box<Self>> {
         use vminer::os::Buildable;
 
+        // Dumps may have recorded their guest OS, so there is no need to
+        // search for it
+        #[cfg(feature = "std")]
+        if let Some(metadata) = &backend.1 {
+            use vminer::backends::kvm_dump::OsKind;
+
+            match metadata.os {
+                Some(OsKind::Linux) => {
+                    let linux = metadata
+                        .os_builder()
+                        .with_symbols(symbols)
+                        .build::<_, vminer::os::Linux<_>>(backend.0)?;
+                    return Ok(Box::new(Self(Box::new(linux))));
+                }
+                Some(OsKind::Windows) => {
+                    let windows = metadata
+                        .os_builder()
+                        .with_symbols(symbols)
+                        .build::<_, vminer::os::Windows<_>>(backend.0)?;
+                    return Ok(Box::new(Self(Box::new(windows))));
+                }
+                None => (),
+            }
+        }
+
         if let Some(builder) = vminer::os::Linux::quick_check(&backend.0) {
             let linux = builder
                 .with_symbols(symbols)
@@ -8,6 +8,7 @@ use pyo3::{
 };
 use std::{convert::Infallible, ops::ControlFlow, sync::Arc};
 use vmc::{Backend as _, ResultExt, VmError, VmResult};
+use vminer::backends::kvm_dump::{Metadata, OsKind};
 
 pyo3::create_exception!(vminer, VminerError, pyo3::exceptions::PyException);
 
@@ -123,7 +124,11 @@ impl<T: pyo3::PyClass> PyOwned<T> {
 
 #[pyclass(subclass)]
 #[derive(Clone)]
-struct Backend(Arc<dyn vmc::Backend<Arch = vmc::arch::RuntimeArchitecture> + Send + Sync>);
+struct Backend(
+    Arc<dyn vmc::Backend<Arch = vmc::arch::RuntimeArchitecture> + Send + Sync>,
+    /// What a dump knows about its guest OS
+    Option<Metadata>,
+);
 
 #[pymethods]
 impl Backend {
@@ -175,7 +180,7 @@ impl Kvm {
         #[cfg(target_os = "linux")]
         {
             let kvm = vminer::backends::kvm::Kvm::connect(_pid).convert_err()?;
-            Ok((Kvm, Backend(Arc::new(vmc::RuntimeBackend(kvm)))))
+            Ok((Kvm, Backend(Arc::new(vmc::RuntimeBackend(kvm)), None)))
         }
 
         #[cfg(not(target_os = "linux"))]
@@ -235,11 +240,12 @@ impl Dump {
     #[new]
     fn new(path: &str) -> PyResult<(Self, Backend)> {
         let dump = vminer::backends::kvm_dump::DumbDump::read(path).convert_err()?;
-        Ok((Dump, Backend(Arc::new(dump))))
+        let metadata = dump.metadata().os.map(|_| dump.metadata().clone());
+        Ok((Dump, Backend(Arc::new(dump), metadata)))
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum RequestedOs {
     Linux,
     Windows,
@@ -250,7 +256,7 @@ struct RawOs(Box<dyn vmc::Os<Arch = vmc::arch::RuntimeArchitecture> + Send + Syn
 
 impl RawOs {
     fn new(backend: Backend, path: Option<&str>, os: Option<RequestedOs>) -> VmResult<Self> {
-        let (mut builder, os) = Self::detect(&backend.0, os).context("failed to guess guest OS")?;
+        let (mut builder, os) = Self::detect(&backend, os).context("failed to guess guest OS")?;
 
         if let Some(path) = path {
             let mut symbols = vmc::SymbolsIndexer::new();
@@ -269,11 +275,26 @@ impl RawOs {
     }
 
     fn detect(
-        backend: &impl vmc::Backend,
+        backend: &Backend,
         os: Option<RequestedOs>,
     ) -> Option<(vminer::os::OsBuilder, RequestedOs)> {
         use vminer::os::Buildable;
 
+        // Dumps may have recorded their guest OS, so there is no need to
+        // search for it
+        if let Some(metadata) = &backend.1 {
+            let saved = match metadata.os {
+                Some(OsKind::Linux) => Some(RequestedOs::Linux),
+                Some(OsKind::Windows) => Some(RequestedOs::Windows),
+                None => None,
+            };
+            if let Some(saved) = saved.filter(|&saved| os.is_none_or(|os| os == saved)) {
+                return Some((metadata.os_builder(), saved));
+            }
+        }
+
+        let backend = &backend.0;
+
         if let Some(RequestedOs::Linux) | None = os {
             if let Some(builder) = vminer::os::Linux::quick_check(backend) {
                 return Some((builder, RequestedOs::Linux));
@@ -26,6 +26,16 @@ impl super::MmuDesc for MmuDesc {
     fn is_large(mmu_entry: crate::addr::MmuEntry) -> bool {
         mmu_entry.0 & 0b10 == 0
     }
+
+    /// TTBR1 maps the top of the address space, and TTBR0 the bottom.
+    #[inline]
+    fn canonical_address(addr: u64, kernel: bool) -> VirtualAddress {
+        if kernel {
+            VirtualAddress(addr | !crate::mask(Self::ADDR_BITS))
+        } else {
+            VirtualAddress(addr)
+        }
+    }
 }
 
 impl super::Architecture for Aarch64 {
@@ -54,6 +64,15 @@ impl super::Architecture for Aarch64 {
         super::virtual_to_physical::<MmuDesc, M>(memory, mmu_addr, addr)
     }
 
+    fn translate_page<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> crate::TranslationResult<(PhysicalAddress, u64)> {
+        super::translate_page::<MmuDesc, M>(memory, mmu_addr, addr)
+    }
+
     fn find_kernel_pgd<M: crate::Memory + ?Sized>(
         &self,
         memory: &M,
@@ -113,6 +132,24 @@ impl super::Architecture for Aarch64 {
         super::find_in_kernel_memory::<MmuDesc, M>(memory, mmu_addr, needle, self.kernel_base())
     }
 
+    fn find_kernel_pgd_mapping<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        targets: &[PhysicalAddress],
+    ) -> crate::MemoryAccessResult<Option<(PhysicalAddress, VirtualAddress)>> {
+        super::find_kernel_pgd_mapping::<MmuDesc, M>(memory, targets, self.kernel_base())
+    }
+
+    fn walk_page_tables<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(super::PageWalkEntry) -> bool,
+    ) -> crate::MemoryAccessResult<()> {
+        super::walk_page_tables::<MmuDesc, M>(memory, mmu_addr, kernel, f)
+    }
+
     fn register_by_name<Vcpus: super::HasVcpus<Arch = Self> + ?Sized>(
         &self,
         vcpus: &Vcpus,
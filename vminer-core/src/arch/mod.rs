@@ -320,6 +320,15 @@ pub trait Architecture {
         addr: VirtualAddress,
     ) -> TranslationResult<PhysicalAddress>;
 
+    /// Translates a virtual address, and also returns the size of the page
+    /// that maps it, which may be a large one.
+    fn translate_page<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> TranslationResult<(PhysicalAddress, u64)>;
+
     fn find_kernel_pgd<M: crate::Memory + ?Sized>(
         &self,
         memory: &M,
@@ -344,6 +353,36 @@ pub trait Architecture {
         needle: &[u8],
     ) -> MemoryAccessResult<Option<VirtualAddress>>;
 
+    /// Scans memory for a page directory that maps one of `targets` in kernel
+    /// space.
+    ///
+    /// This does not need any register, so it can be used with backends that
+    /// only have physical memory. Returns the page directory and the virtual
+    /// address of the first mapped target.
+    fn find_kernel_pgd_mapping<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        targets: &[PhysicalAddress],
+    ) -> MemoryAccessResult<Option<(PhysicalAddress, VirtualAddress)>>;
+
+    /// Walks the translation tables of the page directory at `mmu_addr`.
+    ///
+    /// `f` is called with each table, starting with the page directory
+    /// itself, and each mapped page. Returning `false` for a table skips the
+    /// entries it contains.
+    ///
+    /// `kernel` tells whether `mmu_addr` is the page directory of the kernel.
+    /// Some architectures have one page directory for each half of the
+    /// address space (eg TTBR0 and TTBR1 on aarch64), so this is required to
+    /// get the right virtual addresses.
+    fn walk_page_tables<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(PageWalkEntry) -> bool,
+    ) -> MemoryAccessResult<()>;
+
     fn kernel_base(&self) -> VirtualAddress;
 
     fn register_by_name<Vcpus: HasVcpus<Arch = Self> + ?Sized>(
@@ -384,6 +423,24 @@ pub trait Architecture {
     ) -> VcpuResult<Option<VirtualAddress>>;
 }
 
+/// An entry found while walking translation tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageWalkEntry {
+    /// A translation table, which maps `size` bytes starting at
+    /// `virtual_addr`
+    Table {
+        physical_addr: PhysicalAddress,
+        virtual_addr: VirtualAddress,
+        size: u64,
+    },
+    /// A mapped page, which may be a large one
+    Page {
+        physical_addr: PhysicalAddress,
+        virtual_addr: VirtualAddress,
+        size: u64,
+    },
+}
+
 /// The description of how a MMU works
 ///
 /// All architechtures have similar MMU with multiple tables, so this trait
@@ -409,13 +466,17 @@ trait MmuDesc {
     /// This is required to support 2M pages for example. If a large page is
     /// encountered, address translation stops here.
     fn is_large(mmu_entry: MmuEntry) -> bool;
+
+    /// Returns the canonical form of an address of `ADDR_BITS` bits, mapped
+    /// by a kernel page directory or not.
+    fn canonical_address(addr: u64, kernel: bool) -> VirtualAddress;
 }
 
-fn virtual_to_physical<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
+fn translate_page<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
     memory: &M,
     mmu_addr: PhysicalAddress,
     addr: VirtualAddress,
-) -> TranslationResult<PhysicalAddress> {
+) -> TranslationResult<(PhysicalAddress, u64)> {
     let mut mmu_entry = MmuEntry(mmu_addr.0);
 
     // This loop is generally unrolled and values are calculated at compile time
@@ -438,12 +499,21 @@ fn virtual_to_physical<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
         if has_huge && Mmu::is_large(mmu_entry) {
             let base = mmu_entry.take_bits(shift, Mmu::ADDR_BITS);
             let phys_addr = base + (addr.0 & mask(shift));
-            return Ok(phys_addr);
+            return Ok((phys_addr, 1 << shift));
         }
     }
 
     let phys_addr = mmu_entry.take_bits(12, Mmu::ADDR_BITS) + (addr.0 & mask(12));
-    Ok(phys_addr)
+    Ok((phys_addr, 1 << 12))
+}
+
+#[inline]
+fn virtual_to_physical<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
+    memory: &M,
+    mmu_addr: PhysicalAddress,
+    addr: VirtualAddress,
+) -> TranslationResult<PhysicalAddress> {
+    translate_page::<Mmu, M>(memory, mmu_addr, addr).map(|(phys_addr, _)| phys_addr)
 }
 
 /// This is a recursive function to walk the translation table.
@@ -556,3 +626,193 @@ fn find_in_kernel_memory<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
 
     find_in_kernel_memory_raw::<Mmu, M>(memory, mmu_addr, base_search_addr, &finder, &mut buf)
 }
+
+/// Maximum number of tables read when checking if a page directory candidate
+/// maps an address.
+///
+/// Most pages are not page directories, and walking garbage can lead to read
+/// a lot of pages, so we stop early.
+const MAPPING_SEARCH_BUDGET: u32 = 256;
+
+/// This is a recursive function to walk the translation table, looking for
+/// a virtual address that maps one of the (sorted) targets.
+fn find_mapping_inner<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
+    memory: &M,
+    table_addr: PhysicalAddress,
+    base_search_addr: VirtualAddress,
+    targets: &[PhysicalAddress],
+    levels: &[(u32, bool)],
+    budget: &mut u32,
+) -> MemoryAccessResult<Option<VirtualAddress>> {
+    let (shift, has_large, rest) = match levels {
+        [] => return Ok(None),
+        [(shift, has_large), rest @ ..] => (*shift, *has_large, rest),
+    };
+
+    if *budget == 0 {
+        return Ok(None);
+    }
+    *budget -= 1;
+
+    let prefix = VirtualAddress(base_search_addr.0 & !mask(shift + 9));
+    let base_index = ((base_search_addr.0 >> shift) & mask(9)) as usize;
+    let search_rest = base_search_addr.0 & mask(shift);
+    let page_size = 1 << shift;
+
+    // Only read entries that we are going to look at
+    let mut table = [MmuEntry(0u64); 512];
+    let entries = &mut table[base_index..];
+    match memory.read_physical(
+        table_addr + 8 * base_index as u64,
+        bytemuck::cast_slice_mut(entries),
+    ) {
+        Err(crate::MemoryAccessError::OutOfBounds) => return Ok(None),
+        Err(err) => return Err(err),
+        _ => (),
+    }
+
+    for (index, entry) in table
+        .into_iter()
+        .enumerate()
+        .skip(base_index)
+        .filter(|(_, mmu_entry)| Mmu::is_valid(*mmu_entry))
+    {
+        let base_addr = prefix + index as u64 * page_size;
+        let offset = if index == base_index { search_rest } else { 0 };
+
+        if rest.is_empty() || (has_large && Mmu::is_large(entry)) {
+            // Check if a target is within this page
+            let start = entry.take_bits(shift, Mmu::ADDR_BITS) + offset;
+            let end = start + (page_size - offset);
+            let i = targets.partition_point(|&target| target < start);
+            if let Some(&target) = targets.get(i).filter(|&&target| target < end) {
+                return Ok(Some(base_addr + offset + (target - start) as u64));
+            }
+        } else {
+            let table_addr = entry.take_bits(12, Mmu::ADDR_BITS);
+            let result = find_mapping_inner::<Mmu, M>(
+                memory,
+                table_addr,
+                base_addr + offset,
+                targets,
+                rest,
+                budget,
+            )?;
+            if result.is_some() {
+                return Ok(result);
+            }
+        }
+
+        if *budget == 0 {
+            break;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Tries all pages in memory as page directories, looking for one that maps
+/// one of the targets.
+fn find_kernel_pgd_mapping<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
+    memory: &M,
+    targets: &[PhysicalAddress],
+    base_search_addr: VirtualAddress,
+) -> MemoryAccessResult<Option<(PhysicalAddress, VirtualAddress)>> {
+    log::debug!("Trying all pages to find a kernel PGD");
+
+    let mut targets = targets.to_vec();
+    targets.sort_unstable();
+
+    for mapping in memory.memory_mappings() {
+        let start = (mapping.start.0 + 0xfff) & !0xfff;
+        for addr in (start..mapping.end.0).step_by(0x1000) {
+            let mut budget = MAPPING_SEARCH_BUDGET;
+            let result = find_mapping_inner::<Mmu, M>(
+                memory,
+                PhysicalAddress(addr),
+                base_search_addr,
+                &targets,
+                Mmu::LEVELS,
+                &mut budget,
+            )?;
+            if let Some(vaddr) = result {
+                return Ok(Some((PhysicalAddress(addr), vaddr)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// This is a recursive function to walk the translation table, calling `f`
+/// with every entry.
+fn walk_page_tables_inner<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
+    memory: &M,
+    table_addr: PhysicalAddress,
+    base_addr: u64,
+    levels: &[(u32, bool)],
+    kernel: bool,
+    f: &mut dyn FnMut(PageWalkEntry) -> bool,
+) -> MemoryAccessResult<()> {
+    let (shift, has_large, rest) = match levels {
+        [] => return Ok(()),
+        [(shift, has_large), rest @ ..] => (*shift, *has_large, rest),
+    };
+
+    let mut table = [MmuEntry(0u64); 512];
+    match memory.read_physical(table_addr, bytemuck::bytes_of_mut(&mut table)) {
+        Err(crate::MemoryAccessError::OutOfBounds) => return Ok(()),
+        Err(err) => return Err(err),
+        _ => (),
+    }
+    let page_size = 1 << shift;
+
+    for (index, entry) in table
+        .into_iter()
+        .enumerate()
+        .filter(|(_, mmu_entry)| Mmu::is_valid(*mmu_entry))
+    {
+        let addr = base_addr + index as u64 * page_size;
+        let virtual_addr = Mmu::canonical_address(addr, kernel);
+
+        if rest.is_empty() || (has_large && Mmu::is_large(entry)) {
+            f(PageWalkEntry::Page {
+                physical_addr: entry.take_bits(shift, Mmu::ADDR_BITS),
+                virtual_addr,
+                size: page_size,
+            });
+        } else {
+            let table_addr = entry.take_bits(12, Mmu::ADDR_BITS);
+            let descend = f(PageWalkEntry::Table {
+                physical_addr: table_addr,
+                virtual_addr,
+                size: page_size,
+            });
+            if descend {
+                walk_page_tables_inner::<Mmu, M>(memory, table_addr, addr, rest, kernel, f)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn walk_page_tables<Mmu: MmuDesc, M: crate::Memory + ?Sized>(
+    memory: &M,
+    mmu_addr: PhysicalAddress,
+    kernel: bool,
+    f: &mut dyn FnMut(PageWalkEntry) -> bool,
+) -> MemoryAccessResult<()> {
+    let table_addr = MmuEntry(mmu_addr.0).take_bits(12, Mmu::ADDR_BITS);
+
+    let descend = f(PageWalkEntry::Table {
+        physical_addr: table_addr,
+        virtual_addr: Mmu::canonical_address(0, kernel),
+        size: 1 << Mmu::ADDR_BITS,
+    });
+    if descend {
+        walk_page_tables_inner::<Mmu, M>(memory, table_addr, 0, Mmu::LEVELS, kernel, f)?;
+    }
+
+    Ok(())
+}
@@ -73,6 +73,16 @@ impl arch::Architecture for Architecture {
         dispatch!(self => |arch| arch.virtual_to_physical(memory, mmu_addr, addr))
     }
 
+    #[inline]
+    fn translate_page<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> crate::TranslationResult<(PhysicalAddress, u64)> {
+        dispatch!(self => |arch| arch.translate_page(memory, mmu_addr, addr))
+    }
+
     #[inline]
     fn find_kernel_pgd<M: crate::Memory + ?Sized>(
         &self,
@@ -106,6 +116,26 @@ impl arch::Architecture for Architecture {
         dispatch!(self => |arch| arch.find_in_kernel_memory(memory, mmu_addr, needle))
     }
 
+    #[inline]
+    fn find_kernel_pgd_mapping<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        targets: &[PhysicalAddress],
+    ) -> crate::MemoryAccessResult<Option<(PhysicalAddress, VirtualAddress)>> {
+        dispatch!(self => |arch| arch.find_kernel_pgd_mapping(memory, targets))
+    }
+
+    #[inline]
+    fn walk_page_tables<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(arch::PageWalkEntry) -> bool,
+    ) -> crate::MemoryAccessResult<()> {
+        dispatch!(self => |arch| arch.walk_page_tables(memory, mmu_addr, kernel, f))
+    }
+
     #[inline]
     fn kernel_base(&self) -> VirtualAddress {
         dispatch!(self => |arch| arch.kernel_base())
@@ -25,6 +25,13 @@ impl super::MmuDesc for MmuDesc {
     fn is_large(mmu_entry: crate::addr::MmuEntry) -> bool {
         mmu_entry.0 & (1 << 7) != 0
     }
+
+    /// Addresses of the upper half are sign-extended.
+    #[inline]
+    fn canonical_address(addr: u64, _kernel: bool) -> VirtualAddress {
+        let shift = 64 - Self::ADDR_BITS;
+        VirtualAddress((((addr << shift) as i64) >> shift) as u64)
+    }
 }
 
 impl super::Architecture for X86_64 {
@@ -53,6 +60,15 @@ impl super::Architecture for X86_64 {
         super::virtual_to_physical::<MmuDesc, M>(memory, mmu_addr, addr)
     }
 
+    fn translate_page<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> crate::TranslationResult<(PhysicalAddress, u64)> {
+        super::translate_page::<MmuDesc, M>(memory, mmu_addr, addr)
+    }
+
     fn find_kernel_pgd<M: crate::Memory + ?Sized>(
         &self,
         memory: &M,
@@ -109,6 +125,24 @@ impl super::Architecture for X86_64 {
         super::find_in_kernel_memory::<MmuDesc, M>(memory, mmu_addr, needle, self.kernel_base())
     }
 
+    fn find_kernel_pgd_mapping<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        targets: &[PhysicalAddress],
+    ) -> crate::MemoryAccessResult<Option<(PhysicalAddress, VirtualAddress)>> {
+        super::find_kernel_pgd_mapping::<MmuDesc, M>(memory, targets, self.kernel_base())
+    }
+
+    fn walk_page_tables<M: crate::Memory + ?Sized>(
+        &self,
+        memory: &M,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(super::PageWalkEntry) -> bool,
+    ) -> crate::MemoryAccessResult<()> {
+        super::walk_page_tables::<MmuDesc, M>(memory, mmu_addr, kernel, f)
+    }
+
     #[inline]
     fn kernel_base(&self) -> VirtualAddress {
         VirtualAddress(0xffff_f800_0000_0000)
@@ -1,6 +1,6 @@
 use crate::{
-    Architecture, Memory, MemoryAccessResult, PhysicalAddress, TranslationResult, VirtualAddress,
-    VmResult, arch, mem::MemoryMap,
+    Architecture, Memory, MemoryAccessError, MemoryAccessResult, MemoryMut, PhysicalAddress,
+    TranslationResult, VirtualAddress, VmResult, arch, mem::MemoryMap,
 };
 
 pub fn default_read_virtual_memory<B: Backend + ?Sized>(
@@ -14,6 +14,35 @@ pub fn default_read_virtual_memory<B: Backend + ?Sized>(
     Ok(())
 }
 
+/// Writes virtual memory by translating each page, for backends that
+/// implement [`MemoryMut`].
+pub fn default_write_virtual_memory<B: Backend + MemoryMut + ?Sized>(
+    backend: &mut B,
+    mmu_addr: PhysicalAddress,
+    addr: VirtualAddress,
+    buf: &[u8],
+) -> TranslationResult<()> {
+    crate::write_virtual_memory(addr, buf, |addr, buf| {
+        let addr = backend.virtual_to_physical(mmu_addr, addr)?;
+        backend.write_physical(addr, buf)?;
+        Ok(())
+    })
+}
+
+/// Maximum number of occurrences used to find a page table.
+const MAX_PHYSICAL_OCCURRENCES: usize = 64;
+
+pub(crate) fn default_find_kernel_pgd_from_content<B: Backend + ?Sized>(
+    backend: &B,
+    needle: &[u8],
+) -> VmResult<Option<(PhysicalAddress, VirtualAddress)>> {
+    let targets = crate::mem::find_in_physical_memory(backend, needle, MAX_PHYSICAL_OCCURRENCES)?;
+    if targets.is_empty() {
+        return Ok(None);
+    }
+    Ok(backend.arch().find_kernel_pgd_mapping(backend, &targets)?)
+}
+
 pub trait Backend: Memory + arch::HasVcpus {
     #[inline]
     fn read_virtual_memory(
@@ -39,6 +68,21 @@ pub trait Backend: Memory + arch::HasVcpus {
         Ok(value)
     }
 
+    /// Writes virtual memory, which may span several pages.
+    ///
+    /// Backends that can be written to should implement this with
+    /// [`default_write_virtual_memory`]. By default, this returns
+    /// [`MemoryAccessError::Unsupported`].
+    #[inline]
+    fn write_virtual_memory(
+        &mut self,
+        _mmu_addr: PhysicalAddress,
+        _addr: VirtualAddress,
+        _buf: &[u8],
+    ) -> TranslationResult<()> {
+        Err(MemoryAccessError::Unsupported.into())
+    }
+
     #[inline]
     fn virtual_to_physical(
         &self,
@@ -48,6 +92,19 @@ pub trait Backend: Memory + arch::HasVcpus {
         self.arch().virtual_to_physical(self, mmu_addr, addr)
     }
 
+    /// Translates a virtual address, and also returns the size of the page
+    /// that maps it.
+    ///
+    /// See [`Architecture::translate_page`] for details.
+    #[inline]
+    fn translate_page(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> TranslationResult<(PhysicalAddress, u64)> {
+        self.arch().translate_page(self, mmu_addr, addr)
+    }
+
     #[inline]
     fn find_kernel_pgd(
         &self,
@@ -59,6 +116,18 @@ pub trait Backend: Memory + arch::HasVcpus {
             .ok_or_else(|| "could not find kernel page directory".into())
     }
 
+    /// Finds the kernel page directory without using vCPUs registers.
+    ///
+    /// `needle` is first searched in physical memory, then memory is scanned
+    /// for a page directory that maps one of its occurrences in kernel space.
+    /// Returns this page directory and the virtual address of the needle.
+    fn find_kernel_pgd_from_content(
+        &self,
+        needle: &[u8],
+    ) -> VmResult<Option<(PhysicalAddress, VirtualAddress)>> {
+        default_find_kernel_pgd_from_content(self, needle)
+    }
+
     #[inline]
     fn find_in_kernel_memory(
         &self,
@@ -68,6 +137,19 @@ pub trait Backend: Memory + arch::HasVcpus {
         self.arch().find_in_kernel_memory(self, mmu_addr, needle)
     }
 
+    /// Walks the translation tables of the page directory at `mmu_addr`.
+    ///
+    /// See [`Architecture::walk_page_tables`] for details.
+    #[inline]
+    fn walk_page_tables(
+        &self,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(arch::PageWalkEntry) -> bool,
+    ) -> MemoryAccessResult<()> {
+        self.arch().walk_page_tables(self, mmu_addr, kernel, f)
+    }
+
     #[inline]
     fn iter_in_kernel_memory<'a, 'b>(
         &'a self,
@@ -98,6 +180,19 @@ impl<B: Backend + ?Sized> Backend for alloc::sync::Arc<B> {
         (**self).read_virtual_memory(mmu_addr, addr, buf)
     }
 
+    /// Writing is only possible if this is the only reference to the backend.
+    #[inline]
+    fn write_virtual_memory(
+        &mut self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+        buf: &[u8],
+    ) -> TranslationResult<()> {
+        alloc::sync::Arc::get_mut(self)
+            .ok_or(MemoryAccessError::Unsupported)?
+            .write_virtual_memory(mmu_addr, addr, buf)
+    }
+
     #[inline]
     fn virtual_to_physical(
         &self,
@@ -107,6 +202,15 @@ impl<B: Backend + ?Sized> Backend for alloc::sync::Arc<B> {
         (**self).virtual_to_physical(mmu_addr, addr)
     }
 
+    #[inline]
+    fn translate_page(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> TranslationResult<(PhysicalAddress, u64)> {
+        (**self).translate_page(mmu_addr, addr)
+    }
+
     #[inline]
     fn find_kernel_pgd(
         &self,
@@ -116,6 +220,14 @@ impl<B: Backend + ?Sized> Backend for alloc::sync::Arc<B> {
         (**self).find_kernel_pgd(use_per_cpu, additional)
     }
 
+    #[inline]
+    fn find_kernel_pgd_from_content(
+        &self,
+        needle: &[u8],
+    ) -> VmResult<Option<(PhysicalAddress, VirtualAddress)>> {
+        (**self).find_kernel_pgd_from_content(needle)
+    }
+
     #[inline]
     fn find_in_kernel_memory(
         &self,
@@ -124,6 +236,16 @@ impl<B: Backend + ?Sized> Backend for alloc::sync::Arc<B> {
     ) -> MemoryAccessResult<Option<VirtualAddress>> {
         (**self).find_in_kernel_memory(mmu_addr, needle)
     }
+
+    #[inline]
+    fn walk_page_tables(
+        &self,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(arch::PageWalkEntry) -> bool,
+    ) -> MemoryAccessResult<()> {
+        (**self).walk_page_tables(mmu_addr, kernel, f)
+    }
 }
 
 #[derive(Debug)]
@@ -157,6 +279,13 @@ impl<B: Backend> Memory for RuntimeBackend<B> {
     }
 }
 
+impl<B: Backend + MemoryMut> MemoryMut for RuntimeBackend<B> {
+    #[inline]
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+        self.0.write_physical(addr, buf)
+    }
+}
+
 impl<B: Backend> arch::HasVcpus for RuntimeBackend<B> {
     type Arch = arch::RuntimeArchitecture;
 
@@ -211,6 +340,16 @@ impl<B: Backend> arch::HasVcpus for RuntimeBackend<B> {
 }
 
 impl<B: Backend> Backend for RuntimeBackend<B> {
+    #[inline]
+    fn write_virtual_memory(
+        &mut self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+        buf: &[u8],
+    ) -> TranslationResult<()> {
+        self.0.write_virtual_memory(mmu_addr, addr, buf)
+    }
+
     #[inline]
     fn read_virtual_memory(
         &self,
@@ -239,6 +378,15 @@ impl<B: Backend> Backend for RuntimeBackend<B> {
         self.0.virtual_to_physical(mmu_addr, addr)
     }
 
+    #[inline]
+    fn translate_page(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> TranslationResult<(PhysicalAddress, u64)> {
+        self.0.translate_page(mmu_addr, addr)
+    }
+
     #[inline]
     fn find_kernel_pgd(
         &self,
@@ -248,6 +396,14 @@ impl<B: Backend> Backend for RuntimeBackend<B> {
         self.0.find_kernel_pgd(use_per_cpu, additional)
     }
 
+    #[inline]
+    fn find_kernel_pgd_from_content(
+        &self,
+        needle: &[u8],
+    ) -> VmResult<Option<(PhysicalAddress, VirtualAddress)>> {
+        self.0.find_kernel_pgd_from_content(needle)
+    }
+
     #[inline]
     fn find_in_kernel_memory(
         &self,
@@ -256,6 +412,16 @@ impl<B: Backend> Backend for RuntimeBackend<B> {
     ) -> MemoryAccessResult<Option<VirtualAddress>> {
         self.0.find_in_kernel_memory(mmu_addr, needle)
     }
+
+    #[inline]
+    fn walk_page_tables(
+        &self,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(arch::PageWalkEntry) -> bool,
+    ) -> MemoryAccessResult<()> {
+        self.0.walk_page_tables(mmu_addr, kernel, f)
+    }
 }
 
 #[derive(Debug)]
@@ -295,3 +461,430 @@ impl<B: Backend + ?Sized> Iterator for KernelSearchIterator<'_, '_, B> {
         })
     }
 }
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct TlbEntries {
+    /// Physical base of pages, by page directory, virtual base and size
+    pages: crate::utils::Lru<(u64, u64, u64), PhysicalAddress>,
+    /// Sizes of the pages in the cache
+    sizes: alloc::vec::Vec<u64>,
+}
+
+#[cfg(feature = "std")]
+impl TlbEntries {
+    fn get(&mut self, mmu_addr: PhysicalAddress, addr: VirtualAddress) -> Option<PhysicalAddress> {
+        let Self { pages, sizes } = self;
+        sizes.iter().find_map(|&size| {
+            let offset = addr.0 & (size - 1);
+            let base = pages.get(&(mmu_addr.0, addr.0 - offset, size))?;
+            Some(*base + offset)
+        })
+    }
+
+    fn clear(&mut self) {
+        self.pages.clear();
+        self.sizes.clear();
+    }
+}
+
+/// A backend wrapper that caches virtual to physical translations, like a
+/// TLB does.
+///
+/// Translations are cached by page directory and by page, and large pages
+/// are remembered as a single entry. When the cache is full, the least
+/// recently used translation is dropped.
+///
+/// Writes through this wrapper flush the cache, as they may change page
+/// tables. If page tables may change otherwise (eg for a running VM), the
+/// cache has to be flushed explicitly.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct TlbBackend<B> {
+    backend: B,
+    entries: std::sync::Mutex<TlbEntries>,
+}
+
+#[cfg(feature = "std")]
+impl<B: Backend> TlbBackend<B> {
+    /// Default number of cached translations.
+    pub const DEFAULT_CAPACITY: usize = 4096;
+
+    #[inline]
+    pub fn new(backend: B) -> Self {
+        Self::with_capacity(backend, Self::DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(backend: B, capacity: usize) -> Self {
+        Self {
+            backend,
+            entries: std::sync::Mutex::new(TlbEntries {
+                pages: crate::utils::Lru::new(capacity),
+                sizes: alloc::vec::Vec::new(),
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+
+    /// Drops all cached translations.
+    pub fn flush(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Drops cached translations of a page directory.
+    pub fn flush_pgd(&self, mmu_addr: PhysicalAddress) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.pages.retain(|&(pgd, _, _)| pgd != mmu_addr.0);
+    }
+
+    /// Drops the cached translation of an address in a page directory.
+    pub fn flush_address(&self, mmu_addr: PhysicalAddress, addr: VirtualAddress) {
+        let mut entries = self.entries.lock().unwrap();
+        let TlbEntries { pages, sizes } = &mut *entries;
+        for &size in sizes.iter() {
+            pages.remove(&(mmu_addr.0, addr.0 & !(size - 1), size));
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<B: Backend> Memory for TlbBackend<B> {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.backend.memory_mappings()
+    }
+
+    #[inline]
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        self.backend.is_valid(addr, size)
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        self.backend.read_physical(addr, buf)
+    }
+
+    #[inline]
+    fn search(
+        &self,
+        addr: PhysicalAddress,
+        page_size: u64,
+        finder: &memchr::memmem::Finder,
+        buf: &mut [u8],
+    ) -> MemoryAccessResult<Option<u64>> {
+        self.backend.search(addr, page_size, finder, buf)
+    }
+
+    #[inline]
+    fn dump(&self, writer: &mut dyn std::io::Write) -> std::io::Result<()> {
+        self.backend.dump(writer)
+    }
+}
+
+/// Writes may change page tables, so they flush the cache.
+#[cfg(feature = "std")]
+impl<B: Backend + MemoryMut> MemoryMut for TlbBackend<B> {
+    #[inline]
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+        self.flush();
+        self.backend.write_physical(addr, buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<B: Backend> arch::HasVcpus for TlbBackend<B> {
+    type Arch = B::Arch;
+
+    #[inline]
+    fn arch(&self) -> Self::Arch {
+        self.backend.arch()
+    }
+
+    #[inline]
+    fn vcpus_count(&self) -> usize {
+        self.backend.vcpus_count()
+    }
+
+    #[inline]
+    fn registers(
+        &self,
+        vcpu: arch::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as Architecture>::Registers> {
+        self.backend.registers(vcpu)
+    }
+
+    #[inline]
+    fn special_registers(
+        &self,
+        vcpu: arch::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as Architecture>::SpecialRegisters> {
+        self.backend.special_registers(vcpu)
+    }
+
+    #[inline]
+    fn other_registers(
+        &self,
+        vcpu: arch::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as Architecture>::OtherRegisters> {
+        self.backend.other_registers(vcpu)
+    }
+
+    #[inline]
+    fn register_by_name(&self, vcpu: arch::VcpuId, name: &str) -> crate::VcpuResult<u64> {
+        self.backend.register_by_name(vcpu, name)
+    }
+
+    #[inline]
+    fn instruction_pointer(&self, vcpu: arch::VcpuId) -> crate::VcpuResult<VirtualAddress> {
+        self.backend.instruction_pointer(vcpu)
+    }
+
+    #[inline]
+    fn stack_pointer(&self, vcpu: arch::VcpuId) -> crate::VcpuResult<VirtualAddress> {
+        self.backend.stack_pointer(vcpu)
+    }
+
+    #[inline]
+    fn base_pointer(&self, vcpu: arch::VcpuId) -> crate::VcpuResult<Option<VirtualAddress>> {
+        self.backend.base_pointer(vcpu)
+    }
+
+    #[inline]
+    fn pgd(&self, vcpu: arch::VcpuId) -> crate::VcpuResult<PhysicalAddress> {
+        self.backend.pgd(vcpu)
+    }
+
+    #[inline]
+    fn kernel_per_cpu(&self, vcpu: arch::VcpuId) -> crate::VcpuResult<Option<VirtualAddress>> {
+        self.backend.kernel_per_cpu(vcpu)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<B: Backend> Backend for TlbBackend<B> {
+    #[inline]
+    fn write_virtual_memory(
+        &mut self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+        buf: &[u8],
+    ) -> TranslationResult<()> {
+        // The written pages may be page tables
+        self.flush();
+        self.backend.write_virtual_memory(mmu_addr, addr, buf)
+    }
+
+    #[inline]
+    fn translate_page(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> TranslationResult<(PhysicalAddress, u64)> {
+        self.backend.translate_page(mmu_addr, addr)
+    }
+
+    fn virtual_to_physical(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+    ) -> TranslationResult<PhysicalAddress> {
+        if let Some(phys_addr) = self.entries.lock().unwrap().get(mmu_addr, addr) {
+            return Ok(phys_addr);
+        }
+
+        let (phys_addr, size) = self.backend.translate_page(mmu_addr, addr)?;
+
+        let mut entries = self.entries.lock().unwrap();
+        let offset = addr.0 & (size - 1);
+        entries
+            .pages
+            .insert((mmu_addr.0, addr.0 - offset, size), phys_addr - offset);
+        if !entries.sizes.contains(&size) {
+            entries.sizes.push(size);
+        }
+
+        Ok(phys_addr)
+    }
+
+    #[inline]
+    fn find_kernel_pgd(
+        &self,
+        use_per_cpu: bool,
+        additional: &[VirtualAddress],
+    ) -> VmResult<PhysicalAddress> {
+        self.backend.find_kernel_pgd(use_per_cpu, additional)
+    }
+
+    #[inline]
+    fn find_kernel_pgd_from_content(
+        &self,
+        needle: &[u8],
+    ) -> VmResult<Option<(PhysicalAddress, VirtualAddress)>> {
+        self.backend.find_kernel_pgd_from_content(needle)
+    }
+
+    #[inline]
+    fn find_in_kernel_memory(
+        &self,
+        mmu_addr: PhysicalAddress,
+        needle: &[u8],
+    ) -> MemoryAccessResult<Option<VirtualAddress>> {
+        self.backend.find_in_kernel_memory(mmu_addr, needle)
+    }
+
+    #[inline]
+    fn walk_page_tables(
+        &self,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(arch::PageWalkEntry) -> bool,
+    ) -> MemoryAccessResult<()> {
+        self.backend.walk_page_tables(mmu_addr, kernel, f)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{VcpuError, VcpuResult, arch::x86_64, mem::RawMemory};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PGD: PhysicalAddress = PhysicalAddress(0x1000);
+
+    /// x86_64 page tables that map a 1G page, a 2M page and a 4K page at the
+    /// same virtual and physical addresses.
+    #[derive(Debug)]
+    struct PageTables {
+        mem: RawMemory<Vec<u8>>,
+        reads: AtomicUsize,
+    }
+
+    impl PageTables {
+        fn new() -> Self {
+            let mut mem = RawMemory::new(vec![0; 0x6000]);
+            for (addr, entry) in [
+                (0x1000, 0x2000 | 1),
+                (0x2000, 0x3000 | 1),
+                (0x2008, 0x4000_0000 | 0x81),
+                (0x3000, 0x4000 | 1),
+                (0x3008, 0x20_0000 | 0x81),
+                (0x4028, 0x5000 | 1),
+            ] {
+                mem.write_physical(PhysicalAddress(addr), &u64::to_le_bytes(entry))
+                    .unwrap();
+            }
+            Self {
+                mem,
+                reads: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Memory for PageTables {
+        fn memory_mappings(&self) -> &[MemoryMap] {
+            self.mem.memory_mappings()
+        }
+
+        fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.mem.read_physical(addr, buf)
+        }
+    }
+
+    impl MemoryMut for PageTables {
+        fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+            self.mem.write_physical(addr, buf)
+        }
+    }
+
+    impl arch::HasVcpus for PageTables {
+        type Arch = x86_64::X86_64;
+
+        fn arch(&self) -> Self::Arch {
+            x86_64::X86_64
+        }
+
+        fn vcpus_count(&self) -> usize {
+            0
+        }
+
+        fn registers(&self, _vcpu: arch::VcpuId) -> VcpuResult<x86_64::Registers> {
+            Err(VcpuError::InvalidId)
+        }
+
+        fn special_registers(&self, _vcpu: arch::VcpuId) -> VcpuResult<x86_64::SpecialRegisters> {
+            Err(VcpuError::InvalidId)
+        }
+
+        fn other_registers(&self, _vcpu: arch::VcpuId) -> VcpuResult<x86_64::OtherRegisters> {
+            Err(VcpuError::InvalidId)
+        }
+    }
+
+    impl Backend for PageTables {}
+
+    /// Translates an address, returning the number of reads of page tables.
+    fn translate(tlb: &TlbBackend<PageTables>, addr: u64) -> usize {
+        let phys_addr = tlb.virtual_to_physical(PGD, VirtualAddress(addr)).unwrap();
+        assert_eq!(phys_addr, PhysicalAddress(addr));
+        tlb.inner().reads.swap(0, Ordering::Relaxed)
+    }
+
+    #[test]
+    fn large_pages() {
+        let tlb = TlbBackend::new(PageTables::new());
+
+        assert_eq!(translate(&tlb, 0x5010), 4);
+        assert_eq!(translate(&tlb, 0x5ff8), 0);
+
+        assert_eq!(translate(&tlb, 0x20_1234), 3);
+        assert_eq!(translate(&tlb, 0x3f_ff00), 0);
+
+        assert_eq!(translate(&tlb, 0x4000_0005), 2);
+        assert_eq!(translate(&tlb, 0x7fff_0000), 0);
+        assert_eq!(translate(&tlb, 0x5000), 0);
+    }
+
+    #[test]
+    fn flush() {
+        let mut tlb = TlbBackend::new(PageTables::new());
+        translate(&tlb, 0x5000);
+
+        tlb.flush_pgd(PhysicalAddress(0x2000));
+        assert_eq!(translate(&tlb, 0x5000), 0);
+        tlb.flush_pgd(PGD);
+        assert_eq!(translate(&tlb, 0x5000), 4);
+        tlb.flush();
+        assert_eq!(translate(&tlb, 0x5000), 4);
+
+        // Writes may change page tables
+        tlb.write_physical(PhysicalAddress(0x4030), &u64::to_le_bytes(0x5000 | 1))
+            .unwrap();
+        assert_eq!(translate(&tlb, 0x5000), 4);
+        let phys_addr = tlb.virtual_to_physical(PGD, VirtualAddress(0x6000));
+        assert_eq!(phys_addr.unwrap(), PhysicalAddress(0x5000));
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let tlb = TlbBackend::with_capacity(PageTables::new(), 2);
+
+        assert_eq!(translate(&tlb, 0x5000), 4);
+        assert_eq!(translate(&tlb, 0x20_0000), 3);
+        assert_eq!(translate(&tlb, 0x5000), 0);
+
+        // The 2M page is the least recently used one
+        assert_eq!(translate(&tlb, 0x4000_0000), 2);
+        assert_eq!(translate(&tlb, 0x5000), 0);
+        assert_eq!(translate(&tlb, 0x20_0000), 3);
+    }
+}
@@ -10,6 +10,8 @@ pub mod arch;
 pub use arch::{Architecture, HasVcpus, VcpuId};
 
 pub mod backend;
+#[cfg(feature = "std")]
+pub use backend::TlbBackend;
 pub use backend::{Backend, RuntimeBackend};
 
 pub mod endian;
@@ -22,7 +24,7 @@ pub use error::{
 };
 
 pub mod mem;
-pub use mem::Memory;
+pub use mem::{Memory, MemoryMut};
 
 mod os;
 pub use os::{Module, Os, Process, StackFrame, Thread, Vma, VmaFlags};
@@ -73,6 +75,30 @@ pub fn read_virtual_memory<E>(
     }
 }
 
+#[inline]
+pub fn write_virtual_memory<E>(
+    mut addr: VirtualAddress,
+    mut buf: &[u8],
+    mut write_memory: impl FnMut(VirtualAddress, &[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut next_page = VirtualAddress(addr.0 & !0xfff);
+
+    loop {
+        next_page = VirtualAddress(next_page.0.wrapping_add(0x1000));
+        let diff = (next_page - addr) as usize;
+
+        if diff >= buf.len() {
+            return write_memory(addr, buf);
+        }
+
+        let (start, end) = buf.split_at(diff);
+        write_memory(addr, start)?;
+
+        buf = end;
+        addr = next_page;
+    }
+}
+
 #[inline]
 pub fn try_read_virtual_memory(
     addr: VirtualAddress,
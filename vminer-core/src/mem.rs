@@ -1,9 +1,9 @@
-use super::{MemoryAccessError, MemoryAccessResult, PhysicalAddress};
+use super::{Architecture, HasVcpus, MemoryAccessError, MemoryAccessResult, PhysicalAddress};
 use alloc::vec::Vec;
 #[cfg(feature = "std")]
 use std::{fs, io, path::Path};
 
-#[derive(Debug, Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bytemuck::Zeroable, bytemuck::Pod)]
 #[repr(C)]
 pub struct MemoryMap {
     pub start: PhysicalAddress,
@@ -81,6 +81,79 @@ pub trait Memory {
     }
 }
 
+/// Finds the physical addresses where `needle` appears in memory.
+///
+/// The search stops after `max` occurrences.
+pub fn find_in_physical_memory<M: Memory + ?Sized>(
+    memory: &M,
+    needle: &[u8],
+    max: usize,
+) -> MemoryAccessResult<Vec<PhysicalAddress>> {
+    let finder = memchr::memmem::Finder::new(needle);
+    let mut buf = alloc::vec![0; (1 << 21) + needle.len()];
+    let step = (buf.len() - needle.len()) as u64;
+    let mut found = Vec::new();
+
+    for mapping in memory.memory_mappings() {
+        for offset in (mapping.start.0..mapping.end.0).step_by(step as usize) {
+            let size = core::cmp::min(buf.len() as u64, mapping.end.0 - offset) as usize;
+            match memory.read_physical(PhysicalAddress(offset), &mut buf[..size]) {
+                Ok(()) => (),
+                Err(MemoryAccessError::OutOfBounds) => continue,
+                Err(err) => return Err(err),
+            }
+
+            // Matches past `step` will be found again with the next chunk
+            for i in finder
+                .find_iter(&buf[..size])
+                .filter(|&i| (i as u64) < step)
+            {
+                found.push(PhysicalAddress(offset + i as u64));
+                if found.len() >= max {
+                    return Ok(found);
+                }
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Sorts and merges ranges of physical memory, and keeps only their parts
+/// that are within `mappings`.
+pub fn clamp_to_mappings(mut frames: Vec<MemoryMap>, mappings: &[MemoryMap]) -> Vec<MemoryMap> {
+    frames.sort_unstable_by_key(|frame| frame.start);
+
+    let mut mappings = mappings.to_vec();
+    mappings.sort_unstable_by_key(|mapping| mapping.start);
+
+    let mut result: Vec<MemoryMap> = Vec::new();
+    let mut mappings = mappings.iter().peekable();
+
+    for frame in frames {
+        while mappings
+            .next_if(|mapping| mapping.end <= frame.start)
+            .is_some()
+        {}
+
+        for mapping in mappings.clone() {
+            if mapping.start >= frame.end {
+                break;
+            }
+
+            let start = core::cmp::max(frame.start, mapping.start);
+            let end = core::cmp::min(frame.end, mapping.end);
+
+            match result.last_mut() {
+                Some(last) if last.end >= start => last.end = core::cmp::max(last.end, end),
+                _ => result.push(MemoryMap { start, end }),
+            }
+        }
+    }
+
+    result
+}
+
 impl<M: Memory + ?Sized> Memory for alloc::sync::Arc<M> {
     #[inline]
     fn memory_mappings(&self) -> &[MemoryMap] {
@@ -115,6 +188,18 @@ impl<M: Memory + ?Sized> Memory for alloc::sync::Arc<M> {
     }
 }
 
+/// A trait to specify how to write physical memory of a guest
+pub trait MemoryMut: Memory {
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()>;
+}
+
+impl<M: MemoryMut + ?Sized> MemoryMut for alloc::boxed::Box<M> {
+    #[inline]
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+        (**self).write_physical(addr, buf)
+    }
+}
+
 #[derive(Debug)]
 pub struct RawMemory<T: ?Sized> {
     mapping: MemoryMap,
@@ -174,6 +259,19 @@ impl<T: AsRef<[u8]> + ?Sized> Memory for RawMemory<T> {
     }
 }
 
+impl<T: AsRef<[u8]> + AsMut<[u8]> + ?Sized> MemoryMut for RawMemory<T> {
+    #[inline]
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+        (|| {
+            let offset = addr.0.try_into().ok()?;
+            let this = self.bytes.as_mut().get_mut(offset..)?;
+            let len = buf.len();
+            (this.len() >= len).then(|| this[..len].copy_from_slice(buf))
+        })()
+        .ok_or(MemoryAccessError::OutOfBounds)
+    }
+}
+
 #[derive(Debug)]
 pub struct MemRemap<M: ?Sized> {
     mappings: Vec<MemoryMap>,
@@ -191,32 +289,44 @@ impl<M: Memory> MemRemap<M> {
     }
 }
 
-impl<M: Memory + ?Sized> Memory for MemRemap<M> {
-    fn memory_mappings(&self) -> &[MemoryMap] {
-        &self.mappings
-    }
-
-    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+impl<M: ?Sized> MemRemap<M> {
+    fn remap(&self, addr: PhysicalAddress, size: usize) -> MemoryAccessResult<PhysicalAddress> {
         assert!(self.mappings.len() == self.remap_at.len());
 
         let mut i = 0;
-        let addr = loop {
+        loop {
             if i >= self.mappings.len() {
                 return Err(MemoryAccessError::OutOfBounds);
             }
 
             let mapping = self.mappings[i];
-            if mapping.start <= addr && addr + (buf.len() as u64) <= mapping.end {
-                break self.remap_at[i] + (addr - mapping.start);
+            if mapping.start <= addr && addr + (size as u64) <= mapping.end {
+                return Ok(self.remap_at[i] + (addr - mapping.start));
             }
 
             i += 1;
-        };
+        }
+    }
+}
+
+impl<M: Memory + ?Sized> Memory for MemRemap<M> {
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        &self.mappings
+    }
 
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        let addr = self.remap(addr, buf.len())?;
         self.inner.read_physical(addr, buf)
     }
 }
 
+impl<M: MemoryMut + ?Sized> MemoryMut for MemRemap<M> {
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+        let addr = self.remap(addr, buf.len())?;
+        self.inner.write_physical(addr, buf)
+    }
+}
+
 impl<M: Memory + ?Sized> Memory for &'_ M {
     #[inline]
     fn memory_mappings(&self) -> &[MemoryMap] {
@@ -305,6 +415,13 @@ impl File {
         Ok(Self::new(file, start, end))
     }
 
+    /// Opens a file with write access, so that it implements [`MemoryMut`].
+    #[inline]
+    pub fn open_writable<P: AsRef<Path>>(path: P, start: u64, end: u64) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::new(file, start, end))
+    }
+
     #[inline]
     pub fn size(&self) -> u64 {
         self.mapping.end.0
@@ -331,3 +448,946 @@ impl Memory for File {
         Ok(())
     }
 }
+
+#[cfg(feature = "std")]
+impl MemoryMut for File {
+    #[inline]
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+        use sync_file::WriteAt;
+
+        if !self.is_valid(addr, buf.len()) {
+            return Err(MemoryAccessError::OutOfBounds);
+        }
+
+        let offset = self.start + addr.0;
+        self.file.write_all_at(buf, offset)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+const CACHE_PAGE_SIZE: usize = 0x1000;
+
+/// Reads larger than this bypass the cache, to avoid evicting everything.
+#[cfg(feature = "std")]
+const CACHE_MAX_READ: usize = 16 * CACHE_PAGE_SIZE;
+
+/// Cached pages, by page frame number.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct PageCache {
+    pages: crate::utils::Lru<u64, alloc::boxed::Box<[u8; CACHE_PAGE_SIZE]>>,
+    /// Bumped when pages are invalidated, so that pages read concurrently are
+    /// not inserted with stale data
+    generation: u64,
+}
+
+#[cfg(feature = "std")]
+impl PageCache {
+    fn remove(&mut self, pfn: u64) {
+        self.generation = self.generation.wrapping_add(1);
+        self.pages.remove(&pfn);
+    }
+
+    fn clear(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.pages.clear();
+    }
+}
+
+/// A wrapper that keeps recently read pages of memory in a bounded cache.
+///
+/// This makes repeated small reads much faster for backends where each read
+/// is expensive, such as files or memory of other processes. It can also be
+/// used as a whole backend, so that virtual memory is read through the cache
+/// too.
+///
+/// If the underlying memory may change (eg for a running VM), the cache has to
+/// be invalidated explicitly with [`CachedMemory::invalidate`].
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct CachedMemory<M> {
+    inner: M,
+    cache: std::sync::Mutex<PageCache>,
+}
+
+#[cfg(feature = "std")]
+impl<M: Memory> CachedMemory<M> {
+    /// Default number of cached pages (4 MiB).
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    #[inline]
+    pub fn new(inner: M) -> Self {
+        Self::with_capacity(inner, Self::DEFAULT_CAPACITY)
+    }
+
+    /// Creates a cache that can hold `capacity` pages of 4 KiB.
+    pub fn with_capacity(inner: M, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: std::sync::Mutex::new(PageCache {
+                pages: crate::utils::Lru::new(capacity),
+                generation: 0,
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// Drops all cached pages.
+    pub fn invalidate(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Drops cached pages that overlap with a range of memory.
+    pub fn invalidate_range(&self, addr: PhysicalAddress, size: u64) {
+        let start = addr.0 / CACHE_PAGE_SIZE as u64;
+        let end = (addr.0 + size).div_ceil(CACHE_PAGE_SIZE as u64);
+        let mut cache = self.cache.lock().unwrap();
+
+        if end - start > cache.pages.len() as u64 {
+            let pfns: Vec<u64> = cache
+                .pages
+                .keys()
+                .copied()
+                .filter(|pfn| (start..end).contains(pfn))
+                .collect();
+            pfns.into_iter().for_each(|pfn| cache.remove(pfn));
+        } else {
+            (start..end).for_each(|pfn| cache.remove(pfn));
+        }
+    }
+
+    fn read_page(&self, pfn: u64, offset: usize, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        let generation = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(page) = cache.pages.get(&pfn) {
+                buf.copy_from_slice(&page[offset..offset + buf.len()]);
+                return Ok(());
+            }
+            cache.generation
+        };
+
+        // Do not hold the lock while reading
+        let mut page = alloc::boxed::Box::new([0; CACHE_PAGE_SIZE]);
+        self.inner
+            .read_physical(PhysicalAddress(pfn * CACHE_PAGE_SIZE as u64), &mut *page)?;
+        buf.copy_from_slice(&page[offset..offset + buf.len()]);
+
+        // The page may have been invalidated while we were reading it
+        let mut cache = self.cache.lock().unwrap();
+        if cache.generation == generation {
+            cache.pages.insert(pfn, page);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<M: Memory> Memory for CachedMemory<M> {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.inner.memory_mappings()
+    }
+
+    #[inline]
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        self.inner.is_valid(addr, size)
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        if buf.len() > CACHE_MAX_READ {
+            return self.inner.read_physical(addr, buf);
+        }
+
+        let mut addr = addr.0;
+        let mut buf = buf;
+
+        while !buf.is_empty() {
+            let pfn = addr / CACHE_PAGE_SIZE as u64;
+            let offset = (addr % CACHE_PAGE_SIZE as u64) as usize;
+            let len = core::cmp::min(buf.len(), CACHE_PAGE_SIZE - offset);
+            let (start, rest) = buf.split_at_mut(len);
+
+            // Pages at the edge of a mapping cannot be read as a whole
+            let page_addr = PhysicalAddress(pfn * CACHE_PAGE_SIZE as u64);
+            if self.inner.is_valid(page_addr, CACHE_PAGE_SIZE) {
+                self.read_page(pfn, offset, start)?;
+            } else {
+                self.inner.read_physical(PhysicalAddress(addr), start)?;
+            }
+
+            buf = rest;
+            addr += len as u64;
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn search(
+        &self,
+        addr: PhysicalAddress,
+        page_size: u64,
+        finder: &memchr::memmem::Finder,
+        buf: &mut [u8],
+    ) -> MemoryAccessResult<Option<u64>> {
+        // Searches scan a lot of memory, so they would only evict useful pages
+        self.inner.search(addr, page_size, finder, buf)
+    }
+
+    #[inline]
+    fn dump(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        self.inner.dump(writer)
+    }
+}
+
+/// Writes go to the inner memory and drop the cached pages they overlap.
+#[cfg(feature = "std")]
+impl<M: MemoryMut> MemoryMut for CachedMemory<M> {
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+        self.invalidate_range(addr, buf.len() as u64);
+        self.inner.write_physical(addr, buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<M: crate::HasVcpus> crate::HasVcpus for CachedMemory<M> {
+    type Arch = M::Arch;
+
+    #[inline]
+    fn arch(&self) -> Self::Arch {
+        self.inner.arch()
+    }
+
+    #[inline]
+    fn vcpus_count(&self) -> usize {
+        self.inner.vcpus_count()
+    }
+
+    #[inline]
+    fn registers(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as crate::Architecture>::Registers> {
+        self.inner.registers(vcpu)
+    }
+
+    #[inline]
+    fn special_registers(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as crate::Architecture>::SpecialRegisters> {
+        self.inner.special_registers(vcpu)
+    }
+
+    #[inline]
+    fn other_registers(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as crate::Architecture>::OtherRegisters> {
+        self.inner.other_registers(vcpu)
+    }
+
+    #[inline]
+    fn register_by_name(&self, vcpu: crate::VcpuId, name: &str) -> crate::VcpuResult<u64> {
+        self.inner.register_by_name(vcpu, name)
+    }
+
+    #[inline]
+    fn instruction_pointer(&self, vcpu: crate::VcpuId) -> crate::VcpuResult<crate::VirtualAddress> {
+        self.inner.instruction_pointer(vcpu)
+    }
+
+    #[inline]
+    fn stack_pointer(&self, vcpu: crate::VcpuId) -> crate::VcpuResult<crate::VirtualAddress> {
+        self.inner.stack_pointer(vcpu)
+    }
+
+    #[inline]
+    fn base_pointer(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<Option<crate::VirtualAddress>> {
+        self.inner.base_pointer(vcpu)
+    }
+
+    #[inline]
+    fn pgd(&self, vcpu: crate::VcpuId) -> crate::VcpuResult<PhysicalAddress> {
+        self.inner.pgd(vcpu)
+    }
+
+    #[inline]
+    fn kernel_per_cpu(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<Option<crate::VirtualAddress>> {
+        self.inner.kernel_per_cpu(vcpu)
+    }
+}
+
+/// Translations and searches are forwarded to the inner backend, but the data
+/// of virtual memory is read through the cache.
+///
+/// Page tables are read by the inner backend, so wrapping it in a
+/// [`TlbBackend`](crate::TlbBackend) keeps translations fast.
+#[cfg(feature = "std")]
+impl<B: crate::Backend> crate::Backend for CachedMemory<B> {
+    #[inline]
+    fn read_virtual_memory(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: crate::VirtualAddress,
+        buf: &mut [u8],
+    ) -> crate::TranslationResult<()> {
+        crate::backend::default_read_virtual_memory(self, mmu_addr, addr, buf)
+    }
+
+    fn write_virtual_memory(
+        &mut self,
+        mmu_addr: PhysicalAddress,
+        addr: crate::VirtualAddress,
+        buf: &[u8],
+    ) -> crate::TranslationResult<()> {
+        crate::write_virtual_memory(addr, buf, |addr, buf| {
+            if let Ok(addr) = self.virtual_to_physical(mmu_addr, addr) {
+                self.invalidate_range(addr, buf.len() as u64);
+            }
+            crate::TranslationResult::Ok(())
+        })?;
+
+        self.inner.write_virtual_memory(mmu_addr, addr, buf)
+    }
+
+    #[inline]
+    fn virtual_to_physical(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: crate::VirtualAddress,
+    ) -> crate::TranslationResult<PhysicalAddress> {
+        self.inner.virtual_to_physical(mmu_addr, addr)
+    }
+
+    #[inline]
+    fn translate_page(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: crate::VirtualAddress,
+    ) -> crate::TranslationResult<(PhysicalAddress, u64)> {
+        self.inner.translate_page(mmu_addr, addr)
+    }
+
+    #[inline]
+    fn find_kernel_pgd(
+        &self,
+        use_per_cpu: bool,
+        additional: &[crate::VirtualAddress],
+    ) -> crate::VmResult<PhysicalAddress> {
+        self.inner.find_kernel_pgd(use_per_cpu, additional)
+    }
+
+    #[inline]
+    fn find_kernel_pgd_from_content(
+        &self,
+        needle: &[u8],
+    ) -> crate::VmResult<Option<(PhysicalAddress, crate::VirtualAddress)>> {
+        self.inner.find_kernel_pgd_from_content(needle)
+    }
+
+    #[inline]
+    fn find_in_kernel_memory(
+        &self,
+        mmu_addr: PhysicalAddress,
+        needle: &[u8],
+    ) -> MemoryAccessResult<Option<crate::VirtualAddress>> {
+        self.inner.find_in_kernel_memory(mmu_addr, needle)
+    }
+
+    #[inline]
+    fn walk_page_tables(
+        &self,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(crate::arch::PageWalkEntry) -> bool,
+    ) -> MemoryAccessResult<()> {
+        self.inner.walk_page_tables(mmu_addr, kernel, f)
+    }
+}
+
+const OVERLAY_PAGE_SIZE: usize = 0x1000;
+
+type OverlayPage = alloc::sync::Arc<[u8; OVERLAY_PAGE_SIZE]>;
+
+/// Reads a page of `memory`, with zeros where it is not mapped.
+fn read_overlay_page<M: Memory + ?Sized>(memory: &M, pfn: u64) -> MemoryAccessResult<OverlayPage> {
+    let mut page = alloc::sync::Arc::new([0; OVERLAY_PAGE_SIZE]);
+    let data = alloc::sync::Arc::get_mut(&mut page).unwrap();
+    let start = PhysicalAddress(pfn * OVERLAY_PAGE_SIZE as u64);
+    let end = start + OVERLAY_PAGE_SIZE as u64;
+
+    for mapping in memory.memory_mappings() {
+        let map_start = core::cmp::max(start, mapping.start);
+        let map_end = core::cmp::min(end, mapping.end);
+        if map_start < map_end {
+            let range = (map_start - start) as usize..(map_end - start) as usize;
+            memory.read_physical(map_start, &mut data[range])?;
+        }
+    }
+
+    Ok(page)
+}
+
+/// A copy-on-write layer on top of memory.
+///
+/// Writes are recorded in a sparse map of pages and never reach the
+/// underlying memory, which is read for everything else. This makes it
+/// possible to patch a read-only dump and to run queries on the modified
+/// view.
+///
+/// The state of the overlay can be saved and restored cheaply, as pages are
+/// shared between snapshots until they are written again.
+#[derive(Debug)]
+pub struct Overlay<M> {
+    inner: M,
+    pages: hashbrown::HashMap<u64, OverlayPage>,
+}
+
+/// A saved state of an [`Overlay`].
+#[derive(Debug, Clone, Default)]
+pub struct OverlaySnapshot {
+    pages: hashbrown::HashMap<u64, OverlayPage>,
+}
+
+impl<M: Memory> Overlay<M> {
+    #[inline]
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            pages: hashbrown::HashMap::new(),
+        }
+    }
+
+    #[inline]
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Returns the underlying memory, dropping all writes.
+    #[inline]
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// Returns `true` if memory was written since the creation of the
+    /// overlay or its last reset.
+    #[inline]
+    pub fn is_modified(&self) -> bool {
+        !self.pages.is_empty()
+    }
+
+    /// Returns the sorted addresses of the pages that were written.
+    pub fn modified_pages(&self) -> Vec<PhysicalAddress> {
+        let mut pages: Vec<_> = self
+            .pages
+            .keys()
+            .map(|pfn| PhysicalAddress(pfn * OVERLAY_PAGE_SIZE as u64))
+            .collect();
+        pages.sort_unstable();
+        pages
+    }
+
+    /// Saves the current state of the overlay.
+    #[inline]
+    pub fn save(&self) -> OverlaySnapshot {
+        OverlaySnapshot {
+            pages: self.pages.clone(),
+        }
+    }
+
+    /// Restores a state previously returned by [`Overlay::save`].
+    #[inline]
+    pub fn restore(&mut self, snapshot: &OverlaySnapshot) {
+        self.pages.clone_from(&snapshot.pages);
+    }
+
+    /// Drops all writes.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.pages.clear();
+    }
+
+    /// Returns the sorted ranges of memory that differ from the underlying
+    /// memory.
+    ///
+    /// Writes that left a byte to its original value are not reported.
+    pub fn diff(&self) -> MemoryAccessResult<Vec<MemoryMap>> {
+        let mut ranges: Vec<MemoryMap> = Vec::new();
+
+        for addr in self.modified_pages() {
+            let pfn = addr.0 / OVERLAY_PAGE_SIZE as u64;
+            let page = &self.pages[&pfn];
+            let original = read_overlay_page(&self.inner, pfn)?;
+
+            let mut i = 0;
+            while i < OVERLAY_PAGE_SIZE {
+                if page[i] == original[i] {
+                    i += 1;
+                    continue;
+                }
+
+                let start = addr + i as u64;
+                while i < OVERLAY_PAGE_SIZE && page[i] != original[i] {
+                    i += 1;
+                }
+                let end = addr + i as u64;
+
+                match ranges.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => ranges.push(MemoryMap { start, end }),
+                }
+            }
+        }
+
+        Ok(ranges)
+    }
+}
+
+impl<M: Memory> Memory for Overlay<M> {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.inner.memory_mappings()
+    }
+
+    #[inline]
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        self.inner.is_valid(addr, size)
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        if self.pages.is_empty() {
+            return self.inner.read_physical(addr, buf);
+        }
+
+        if !self.inner.is_valid(addr, buf.len()) {
+            return Err(MemoryAccessError::OutOfBounds);
+        }
+
+        // Contiguous unmodified pages are read at once
+        let mut unmodified = 0;
+        let mut offset = 0;
+
+        while offset < buf.len() {
+            let cur = addr.0 + offset as u64;
+            let pfn = cur / OVERLAY_PAGE_SIZE as u64;
+            let page_offset = (cur % OVERLAY_PAGE_SIZE as u64) as usize;
+            let len = core::cmp::min(buf.len() - offset, OVERLAY_PAGE_SIZE - page_offset);
+
+            if let Some(page) = self.pages.get(&pfn) {
+                if unmodified < offset {
+                    self.inner
+                        .read_physical(addr + unmodified as u64, &mut buf[unmodified..offset])?;
+                }
+                buf[offset..offset + len].copy_from_slice(&page[page_offset..page_offset + len]);
+                unmodified = offset + len;
+            }
+
+            offset += len;
+        }
+
+        if unmodified < buf.len() {
+            self.inner
+                .read_physical(addr + unmodified as u64, &mut buf[unmodified..])?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<M: Memory> MemoryMut for Overlay<M> {
+    fn write_physical(&mut self, addr: PhysicalAddress, buf: &[u8]) -> MemoryAccessResult<()> {
+        if !self.inner.is_valid(addr, buf.len()) {
+            return Err(MemoryAccessError::OutOfBounds);
+        }
+
+        let mut offset = 0;
+
+        while offset < buf.len() {
+            let cur = addr.0 + offset as u64;
+            let pfn = cur / OVERLAY_PAGE_SIZE as u64;
+            let page_offset = (cur % OVERLAY_PAGE_SIZE as u64) as usize;
+            let len = core::cmp::min(buf.len() - offset, OVERLAY_PAGE_SIZE - page_offset);
+
+            let page = match self.pages.entry(pfn) {
+                hashbrown::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                hashbrown::hash_map::Entry::Vacant(entry) => {
+                    entry.insert(read_overlay_page(&self.inner, pfn)?)
+                }
+            };
+            // This copies the page if it is shared with a snapshot
+            alloc::sync::Arc::make_mut(page)[page_offset..page_offset + len]
+                .copy_from_slice(&buf[offset..offset + len]);
+
+            offset += len;
+        }
+
+        Ok(())
+    }
+}
+
+impl<M: crate::HasVcpus> crate::HasVcpus for Overlay<M> {
+    type Arch = M::Arch;
+
+    #[inline]
+    fn arch(&self) -> Self::Arch {
+        self.inner.arch()
+    }
+
+    #[inline]
+    fn vcpus_count(&self) -> usize {
+        self.inner.vcpus_count()
+    }
+
+    #[inline]
+    fn registers(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as crate::Architecture>::Registers> {
+        self.inner.registers(vcpu)
+    }
+
+    #[inline]
+    fn special_registers(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as crate::Architecture>::SpecialRegisters> {
+        self.inner.special_registers(vcpu)
+    }
+
+    #[inline]
+    fn other_registers(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<<Self::Arch as crate::Architecture>::OtherRegisters> {
+        self.inner.other_registers(vcpu)
+    }
+
+    #[inline]
+    fn register_by_name(&self, vcpu: crate::VcpuId, name: &str) -> crate::VcpuResult<u64> {
+        self.inner.register_by_name(vcpu, name)
+    }
+
+    #[inline]
+    fn instruction_pointer(&self, vcpu: crate::VcpuId) -> crate::VcpuResult<crate::VirtualAddress> {
+        self.inner.instruction_pointer(vcpu)
+    }
+
+    #[inline]
+    fn stack_pointer(&self, vcpu: crate::VcpuId) -> crate::VcpuResult<crate::VirtualAddress> {
+        self.inner.stack_pointer(vcpu)
+    }
+
+    #[inline]
+    fn base_pointer(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<Option<crate::VirtualAddress>> {
+        self.inner.base_pointer(vcpu)
+    }
+
+    #[inline]
+    fn pgd(&self, vcpu: crate::VcpuId) -> crate::VcpuResult<PhysicalAddress> {
+        self.inner.pgd(vcpu)
+    }
+
+    #[inline]
+    fn kernel_per_cpu(
+        &self,
+        vcpu: crate::VcpuId,
+    ) -> crate::VcpuResult<Option<crate::VirtualAddress>> {
+        self.inner.kernel_per_cpu(vcpu)
+    }
+}
+
+/// While nothing is written, everything is forwarded to the inner backend.
+/// Then, virtual memory is read and written with the default implementations,
+/// so that page tables and data go through the overlay.
+impl<B: crate::Backend> crate::Backend for Overlay<B> {
+    #[inline]
+    fn read_virtual_memory(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: crate::VirtualAddress,
+        buf: &mut [u8],
+    ) -> crate::TranslationResult<()> {
+        if self.pages.is_empty() {
+            return self.inner.read_virtual_memory(mmu_addr, addr, buf);
+        }
+        crate::backend::default_read_virtual_memory(self, mmu_addr, addr, buf)
+    }
+
+    #[inline]
+    fn write_virtual_memory(
+        &mut self,
+        mmu_addr: PhysicalAddress,
+        addr: crate::VirtualAddress,
+        buf: &[u8],
+    ) -> crate::TranslationResult<()> {
+        crate::backend::default_write_virtual_memory(self, mmu_addr, addr, buf)
+    }
+
+    #[inline]
+    fn virtual_to_physical(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: crate::VirtualAddress,
+    ) -> crate::TranslationResult<PhysicalAddress> {
+        if self.pages.is_empty() {
+            return self.inner.virtual_to_physical(mmu_addr, addr);
+        }
+        self.arch().virtual_to_physical(self, mmu_addr, addr)
+    }
+
+    #[inline]
+    fn translate_page(
+        &self,
+        mmu_addr: PhysicalAddress,
+        addr: crate::VirtualAddress,
+    ) -> crate::TranslationResult<(PhysicalAddress, u64)> {
+        if self.pages.is_empty() {
+            return self.inner.translate_page(mmu_addr, addr);
+        }
+        self.arch().translate_page(self, mmu_addr, addr)
+    }
+
+    fn find_kernel_pgd(
+        &self,
+        use_per_cpu: bool,
+        additional: &[crate::VirtualAddress],
+    ) -> crate::VmResult<PhysicalAddress> {
+        if self.pages.is_empty() {
+            return self.inner.find_kernel_pgd(use_per_cpu, additional);
+        }
+        self.arch()
+            .find_kernel_pgd(self, self, use_per_cpu, additional)?
+            .ok_or_else(|| "could not find kernel page directory".into())
+    }
+
+    fn find_kernel_pgd_from_content(
+        &self,
+        needle: &[u8],
+    ) -> crate::VmResult<Option<(PhysicalAddress, crate::VirtualAddress)>> {
+        if self.pages.is_empty() {
+            return self.inner.find_kernel_pgd_from_content(needle);
+        }
+        crate::backend::default_find_kernel_pgd_from_content(self, needle)
+    }
+
+    fn find_in_kernel_memory(
+        &self,
+        mmu_addr: PhysicalAddress,
+        needle: &[u8],
+    ) -> MemoryAccessResult<Option<crate::VirtualAddress>> {
+        if self.pages.is_empty() {
+            return self.inner.find_in_kernel_memory(mmu_addr, needle);
+        }
+        self.arch().find_in_kernel_memory(self, mmu_addr, needle)
+    }
+
+    fn walk_page_tables(
+        &self,
+        mmu_addr: PhysicalAddress,
+        kernel: bool,
+        f: &mut dyn FnMut(crate::arch::PageWalkEntry) -> bool,
+    ) -> MemoryAccessResult<()> {
+        if self.pages.is_empty() {
+            return self.inner.walk_page_tables(mmu_addr, kernel, f);
+        }
+        self.arch().walk_page_tables(self, mmu_addr, kernel, f)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::sync::{
+        Arc, OnceLock, Weak,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Memory that counts reads, where each page is filled with its number.
+    #[derive(Debug)]
+    struct CountingMemory {
+        raw: RawMemory<Vec<u8>>,
+        reads: AtomicUsize,
+        /// Invalidated in the middle of each read
+        cache: OnceLock<Weak<CachedMemory<CountingMemory>>>,
+    }
+
+    impl CountingMemory {
+        fn new(pages: u8) -> Self {
+            let bytes = (0..pages).flat_map(|i| [i; 0x1000]).collect();
+            Self {
+                raw: RawMemory::new(bytes),
+                reads: AtomicUsize::new(0),
+                cache: OnceLock::new(),
+            }
+        }
+    }
+
+    impl Memory for CountingMemory {
+        fn memory_mappings(&self) -> &[MemoryMap] {
+            self.raw.memory_mappings()
+        }
+
+        fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            if let Some(cache) = self.cache.get().and_then(Weak::upgrade) {
+                cache.invalidate();
+            }
+            self.raw.read_physical(addr, buf)
+        }
+    }
+
+    fn read_page(mem: &CachedMemory<CountingMemory>, pfn: u8) -> usize {
+        let mut buf = [0; 8];
+        mem.read_physical(PhysicalAddress(pfn as u64 * 0x1000 + 8), &mut buf)
+            .unwrap();
+        assert_eq!(buf, [pfn; 8]);
+        mem.inner().reads.swap(0, Ordering::Relaxed)
+    }
+
+    #[test]
+    fn lru_eviction_order() {
+        let mem = CachedMemory::with_capacity(CountingMemory::new(4), 2);
+
+        assert_eq!(read_page(&mem, 0), 1);
+        assert_eq!(read_page(&mem, 1), 1);
+        assert_eq!(read_page(&mem, 0), 0);
+
+        // Page 1 is the least recently used one
+        assert_eq!(read_page(&mem, 2), 1);
+        assert_eq!(read_page(&mem, 0), 0);
+        assert_eq!(read_page(&mem, 1), 1);
+
+        // Then page 2
+        assert_eq!(read_page(&mem, 0), 0);
+        assert_eq!(read_page(&mem, 2), 1);
+    }
+
+    #[test]
+    fn invalidate_range() {
+        let mem = CachedMemory::with_capacity(CountingMemory::new(4), 4);
+        for pfn in 0..4 {
+            read_page(&mem, pfn);
+        }
+
+        mem.invalidate_range(PhysicalAddress(0x1ff0), 0x20);
+        assert_eq!(read_page(&mem, 0), 0);
+        assert_eq!(read_page(&mem, 1), 1);
+        assert_eq!(read_page(&mem, 2), 1);
+        assert_eq!(read_page(&mem, 3), 0);
+    }
+
+    #[test]
+    fn invalidate_during_read() {
+        let mem = Arc::new(CachedMemory::new(CountingMemory::new(1)));
+        mem.inner().cache.set(Arc::downgrade(&mem)).unwrap();
+
+        // The page read while the cache was invalidated must not be kept
+        assert_eq!(read_page(&mem, 0), 1);
+        assert_eq!(read_page(&mem, 0), 1);
+    }
+
+    fn overlay(pages: u8) -> Overlay<RawMemory<Vec<u8>>> {
+        Overlay::new(RawMemory::new(
+            (0..pages).flat_map(|i| [i; 0x1000]).collect(),
+        ))
+    }
+
+    fn read<M: Memory>(mem: &M, addr: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        mem.read_physical(PhysicalAddress(addr), &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn overlay_copy_on_write() {
+        let mut mem = overlay(3);
+        assert_eq!(read(&mem, 0x1ffe, 4), [1, 1, 2, 2]);
+        assert!(!mem.is_modified());
+
+        mem.write_physical(PhysicalAddress(0xfff), &[9, 9]).unwrap();
+        assert_eq!(
+            mem.modified_pages(),
+            [PhysicalAddress(0), PhysicalAddress(0x1000)]
+        );
+        assert_eq!(read(&mem, 0xffe, 4), [0, 9, 9, 1]);
+        assert_eq!(read(mem.inner(), 0xffe, 4), [0, 0, 1, 1]);
+
+        // Modified pages are mixed with unmodified ones
+        let buf = read(&mem, 0xfff, 0x2001);
+        assert_eq!(buf[..2], [9, 9]);
+        assert!(buf[2..0x1001].iter().all(|&b| b == 1));
+        assert!(buf[0x1001..].iter().all(|&b| b == 2));
+
+        assert!(
+            mem.write_physical(PhysicalAddress(0x2fff), &[0, 0])
+                .is_err()
+        );
+        assert!(
+            mem.read_physical(PhysicalAddress(0x2fff), &mut [0, 0])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn overlay_snapshots() {
+        let mut mem = overlay(3);
+        mem.write_physical(PhysicalAddress(0x10), &[7]).unwrap();
+        let snapshot = mem.save();
+
+        mem.write_physical(PhysicalAddress(0x10), &[8]).unwrap();
+        mem.write_physical(PhysicalAddress(0x2000), &[9]).unwrap();
+        assert_eq!(read(&mem, 0x10, 1), [8]);
+
+        // Later writes do not change the snapshot
+        mem.restore(&snapshot);
+        assert_eq!(read(&mem, 0x10, 1), [7]);
+        assert_eq!(read(&mem, 0x2000, 1), [2]);
+        assert_eq!(mem.modified_pages(), [PhysicalAddress(0)]);
+
+        mem.reset();
+        assert!(!mem.is_modified());
+        assert_eq!(read(&mem, 0x10, 1), [0]);
+
+        mem.restore(&snapshot);
+        assert_eq!(read(&mem, 0x10, 1), [7]);
+    }
+
+    #[test]
+    fn overlay_diff() {
+        let mut mem = overlay(3);
+        mem.write_physical(PhysicalAddress(0x10), &[1, 0, 1])
+            .unwrap();
+        mem.write_physical(PhysicalAddress(0xffe), &[5; 4]).unwrap();
+        mem.write_physical(PhysicalAddress(0x2000), &[2; 4])
+            .unwrap();
+
+        let range = |start, end| MemoryMap {
+            start: PhysicalAddress(start),
+            end: PhysicalAddress(end),
+        };
+        assert_eq!(
+            mem.diff().unwrap(),
+            [range(0x10, 0x11), range(0x12, 0x13), range(0xffe, 0x1002)]
+        );
+    }
+}
@@ -122,6 +122,32 @@ pub trait Os: crate::HasVcpus {
         self.try_read_virtual_memory(mmu_addr, addr, buf)
     }
 
+    /// Writes virtual memory.
+    ///
+    /// By default, this returns [`MemoryAccessError::Unsupported`], like
+    /// [`Backend::write_virtual_memory`](crate::Backend::write_virtual_memory).
+    ///
+    /// [`MemoryAccessError::Unsupported`]: crate::MemoryAccessError::Unsupported
+    #[inline]
+    fn write_virtual_memory(
+        &mut self,
+        _mmu_addr: PhysicalAddress,
+        _addr: VirtualAddress,
+        _buf: &[u8],
+    ) -> VmResult<()> {
+        Err(crate::MemoryAccessError::Unsupported.into())
+    }
+
+    fn write_process_memory(
+        &mut self,
+        _proc: Process,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+        buf: &[u8],
+    ) -> VmResult<()> {
+        self.write_virtual_memory(mmu_addr, addr, buf)
+    }
+
     fn read_kernel_memory(&self, addr: VirtualAddress, buf: &mut [u8]) -> VmResult<()> {
         self.read_virtual_memory(self.kernel_pgd(), addr, buf)
     }
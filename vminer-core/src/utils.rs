@@ -55,3 +55,155 @@ impl<T> Default for OnceCell<T> {
         Self::new()
     }
 }
+
+#[cfg(feature = "std")]
+const NIL: usize = usize::MAX;
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct LruEntry<K, V> {
+    key: K,
+    value: V,
+    prev: usize,
+    next: usize,
+}
+
+/// A bounded map that evicts its least recently used entry when full.
+///
+/// Entries are stored in a slab and linked by indices.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct Lru<K, V> {
+    indices: hashbrown::HashMap<K, usize>,
+    entries: alloc::vec::Vec<LruEntry<K, V>>,
+    /// Most recently used entry
+    head: usize,
+    /// Least recently used entry
+    tail: usize,
+    capacity: usize,
+}
+
+#[cfg(feature = "std")]
+impl<K: Copy + Eq + core::hash::Hash, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            indices: hashbrown::HashMap::new(),
+            entries: alloc::vec::Vec::new(),
+            head: NIL,
+            tail: NIL,
+            capacity: capacity.max(1),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.indices.keys()
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let LruEntry { prev, next, .. } = self.entries[i];
+        match prev {
+            NIL => self.head = next,
+            prev => self.entries[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.entries[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.entries[i].prev = NIL;
+        self.entries[i].next = self.head;
+        match self.head {
+            NIL => self.tail = i,
+            head => self.entries[head].prev = i,
+        }
+        self.head = i;
+    }
+
+    /// Gets an entry and marks it as the most recently used one.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let i = *self.indices.get(key)?;
+        if self.head != i {
+            self.unlink(i);
+            self.push_front(i);
+        }
+        Some(&self.entries[i].value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if let Some(&i) = self.indices.get(&key) {
+            self.entries[i].value = value;
+            self.unlink(i);
+            self.push_front(i);
+            return;
+        }
+
+        let i = if self.entries.len() < self.capacity {
+            self.entries.push(LruEntry {
+                key,
+                value,
+                prev: NIL,
+                next: NIL,
+            });
+            self.entries.len() - 1
+        } else {
+            // Reuse the least recently used slot
+            let i = self.tail;
+            self.unlink(i);
+            self.indices.remove(&self.entries[i].key);
+            self.entries[i].key = key;
+            self.entries[i].value = value;
+            i
+        };
+
+        self.indices.insert(key, i);
+        self.push_front(i);
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let i = self.indices.remove(key)?;
+        self.unlink(i);
+
+        // Move the last entry to the freed slot
+        let last = self.entries.len() - 1;
+        let entry = self.entries.swap_remove(i);
+        if i != last {
+            let LruEntry {
+                key, prev, next, ..
+            } = self.entries[i];
+            self.indices.insert(key, i);
+            match prev {
+                NIL => self.head = i,
+                prev => self.entries[prev].next = i,
+            }
+            match next {
+                NIL => self.tail = i,
+                next => self.entries[next].prev = i,
+            }
+        }
+
+        Some(entry.value)
+    }
+
+    /// Removes the entries for which `f` returns `false`.
+    pub fn retain(&mut self, mut f: impl FnMut(&K) -> bool) {
+        let keys: alloc::vec::Vec<K> = self.keys().copied().filter(|key| !f(key)).collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.indices.clear();
+        self.entries.clear();
+        self.head = NIL;
+        self.tail = NIL;
+    }
+}
@@ -0,0 +1,74 @@
+//! Bitmaps of pages present in a dump file.
+
+/// A bitmap of pages, with precomputed ranks to quickly find the index of a
+/// page among the pages present in the file.
+#[derive(Debug)]
+pub(crate) struct Bitmap {
+    words: Vec<u64>,
+    ranks: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn new(bytes: &[u8]) -> Self {
+        let words: Vec<u64> = bytes
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+
+        let mut rank = 0;
+        let ranks = words
+            .iter()
+            .map(|word| {
+                let current = rank;
+                rank += word.count_ones() as u64;
+                current
+            })
+            .collect();
+
+        Self { words, ranks }
+    }
+
+    #[cfg(feature = "kdump")]
+    #[inline]
+    pub fn is_set(&self, pfn: u64) -> bool {
+        match self.words.get((pfn / 64) as usize) {
+            Some(word) => word & (1 << (pfn % 64)) != 0,
+            None => false,
+        }
+    }
+
+    /// Returns the number of set bits before this one, if it is set.
+    #[inline]
+    pub fn rank(&self, pfn: u64) -> Option<u64> {
+        let index = (pfn / 64) as usize;
+        let word = *self.words.get(index)?;
+        let bit = 1 << (pfn % 64);
+
+        (word & bit != 0).then(|| self.ranks[index] + (word & (bit - 1)).count_ones() as u64)
+    }
+
+    /// Iterates over ranges of set bits
+    #[cfg(feature = "kdump")]
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let max = self.words.len() as u64 * 64;
+        let mut pfn = 0;
+
+        core::iter::from_fn(move || {
+            while pfn < max && !self.is_set(pfn) {
+                pfn += 1;
+            }
+            if pfn == max {
+                return None;
+            }
+            let start = pfn;
+            while pfn < max && self.is_set(pfn) {
+                pfn += 1;
+            }
+            Some((start, pfn))
+        })
+    }
+}
@@ -0,0 +1,327 @@
+//! Cloud Hypervisor snapshots.
+//!
+//! A snapshot directory contains a `state.json` file, which is a tree of
+//! snapshots of the VM components, and a `memory-ranges` file with the content
+//! of guest memory ranges listed in the state of the memory manager, one after
+//! the other.
+//!
+//! The state of each component is itself serialized JSON, embedded in the tree
+//! as a string (or as an array of bytes in older versions).
+
+use super::{
+    one_reg,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+use serde_json::Value;
+use std::{fs, path::Path};
+use vmc::{
+    MemoryAccessResult, PhysicalAddress, ResultExt, VmError, VmResult,
+    arch::{aarch64, x86_64},
+    mem::{MemRemap, MemoryMap},
+};
+
+const MSR_LSTAR: u64 = 0xc000_0082;
+const MSR_KERNEL_GS_BASE: u64 = 0xc000_0102;
+
+/// Finds the snapshot of a component in the tree.
+fn find_snapshot<'a>(snapshot: &'a Value, id: &str) -> Option<&'a Value> {
+    let children = snapshot.get("snapshots")?.as_object()?;
+
+    match children.get(id) {
+        Some(child) => Some(child),
+        None => children.values().find_map(|child| find_snapshot(child, id)),
+    }
+}
+
+/// Deserializes the state of a component.
+fn component_state(snapshot: &Value) -> VmResult<Value> {
+    let data = snapshot
+        .get("snapshot_data")
+        .context("missing snapshot data")?;
+
+    // Older versions have a map of sections instead of a single state
+    let state = match data.get("state") {
+        Some(state) => state,
+        None => data
+            .as_object()
+            .and_then(|sections| sections.values().find_map(|section| section.get("state")))
+            .context("missing state in snapshot data")?,
+    };
+
+    let state = match state {
+        Value::String(state) => serde_json::from_str(state),
+        Value::Array(_) => serde_json::from_slice(&as_bytes(state).context("invalid state")?),
+        _ => return Err(VmError::new("invalid state")),
+    };
+    state.map_err(VmError::new)
+}
+
+fn as_bytes(value: &Value) -> Option<Vec<u8>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+        .collect()
+}
+
+/// Gets an integer field, which may be missing.
+fn field(value: &Value, name: &str) -> u64 {
+    value.get(name).and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Removes the hypervisor tag from registers, if any.
+fn untag(value: &Value) -> &Value {
+    match value.as_object() {
+        Some(map) if map.len() == 1 => match map.get("Kvm").or_else(|| map.get("kvm")) {
+            Some(inner) => inner,
+            None => value,
+        },
+        _ => value,
+    }
+}
+
+/// Reads a structure that may be serialized as raw bytes.
+fn from_bytes<T: bytemuck::Pod>(value: &Value) -> Option<T> {
+    let bytes = as_bytes(value)?;
+    (bytes.len() == core::mem::size_of::<T>()).then(|| bytemuck::pod_read_unaligned(&bytes))
+}
+
+fn x86_64_segment(value: &Value) -> x86_64::Segment {
+    x86_64::Segment {
+        base: field(value, "base"),
+        limit: field(value, "limit") as u32,
+        selector: field(value, "selector") as u16,
+        type_: field(value, "type_") as u8,
+        present: field(value, "present") as u8,
+        dpl: field(value, "dpl") as u8,
+        db: field(value, "db") as u8,
+        s: field(value, "s") as u8,
+        l: field(value, "l") as u8,
+        g: field(value, "g") as u8,
+        avl: field(value, "avl") as u8,
+        unusable: field(value, "unusable") as u8,
+        padding: 0,
+    }
+}
+
+fn x86_64_dtable(value: &Value) -> x86_64::Dtable {
+    x86_64::Dtable {
+        base: field(value, "base"),
+        limit: field(value, "limit") as u16,
+        padding: [0; 3],
+    }
+}
+
+fn x86_64_vcpu(state: &Value) -> VmResult<x86_64::Vcpu> {
+    let regs = untag(state.get("regs").context("missing registers")?);
+    let registers = match from_bytes(regs) {
+        Some(regs) => regs,
+        None => x86_64::Registers {
+            rax: field(regs, "rax"),
+            rbx: field(regs, "rbx"),
+            rcx: field(regs, "rcx"),
+            rdx: field(regs, "rdx"),
+            rsi: field(regs, "rsi"),
+            rdi: field(regs, "rdi"),
+            rsp: field(regs, "rsp"),
+            rbp: field(regs, "rbp"),
+            r8: field(regs, "r8"),
+            r9: field(regs, "r9"),
+            r10: field(regs, "r10"),
+            r11: field(regs, "r11"),
+            r12: field(regs, "r12"),
+            r13: field(regs, "r13"),
+            r14: field(regs, "r14"),
+            r15: field(regs, "r15"),
+            rip: field(regs, "rip"),
+            rflags: field(regs, "rflags"),
+        },
+    };
+
+    let sregs = untag(state.get("sregs").context("missing special registers")?);
+    let special_registers = match from_bytes(sregs) {
+        Some(sregs) => sregs,
+        None => {
+            let segment = |name| x86_64_segment(&sregs[name]);
+            let mut interrupt_bitmap = [0; 4];
+            if let Some(bitmap) = sregs.get("interrupt_bitmap").and_then(Value::as_array) {
+                for (word, value) in interrupt_bitmap.iter_mut().zip(bitmap) {
+                    *word = value.as_u64().unwrap_or(0);
+                }
+            }
+
+            x86_64::SpecialRegisters {
+                cs: segment("cs"),
+                ds: segment("ds"),
+                es: segment("es"),
+                fs: segment("fs"),
+                gs: segment("gs"),
+                ss: segment("ss"),
+                tr: segment("tr"),
+                ldt: segment("ldt"),
+                gdt: x86_64_dtable(&sregs["gdt"]),
+                idt: x86_64_dtable(&sregs["idt"]),
+                cr0: field(sregs, "cr0"),
+                cr2: field(sregs, "cr2"),
+                cr3: field(sregs, "cr3"),
+                cr4: field(sregs, "cr4"),
+                cr8: field(sregs, "cr8"),
+                efer: field(sregs, "efer"),
+                apic_base: field(sregs, "apic_base"),
+                interrupt_bitmap,
+            }
+        }
+    };
+
+    let mut other_registers = x86_64::OtherRegisters {
+        lstar: 0,
+        gs_kernel_base: 0,
+    };
+    let msrs = state.get("msrs").and_then(Value::as_array);
+    for msr in msrs.into_iter().flatten() {
+        match field(msr, "index") {
+            MSR_LSTAR => other_registers.lstar = field(msr, "data"),
+            MSR_KERNEL_GS_BASE => other_registers.gs_kernel_base = field(msr, "data"),
+            _ => (),
+        }
+    }
+
+    Ok(x86_64::Vcpu {
+        registers,
+        special_registers,
+        other_registers,
+    })
+}
+
+fn aarch64_vcpu(state: &Value) -> VmResult<aarch64::Vcpu> {
+    let mut vcpu: aarch64::Vcpu = bytemuck::Zeroable::zeroed();
+
+    // `struct kvm_regs` starts with `struct user_pt_regs` and `sp_el1`
+    let core_regs = untag(&state["core_regs"]);
+    match as_bytes(core_regs) {
+        Some(bytes) => {
+            let regs = bytes.get(..280).context("invalid core registers")?;
+            vcpu.registers = bytemuck::pod_read_unaligned(&regs[..272]);
+            vcpu.special_registers.sp_el1 = bytemuck::pod_read_unaligned(&regs[272..]);
+        }
+        None => {
+            let user_regs = &core_regs["regs"];
+            let regs = user_regs.get("regs").and_then(Value::as_array);
+            for (reg, value) in vcpu
+                .registers
+                .regs
+                .iter_mut()
+                .zip(regs.into_iter().flatten())
+            {
+                *reg = value.as_u64().unwrap_or(0);
+            }
+            vcpu.registers.sp = field(user_regs, "sp");
+            vcpu.registers.pc = field(user_regs, "pc");
+            vcpu.registers.pstate = field(user_regs, "pstate");
+            vcpu.special_registers.sp_el1 = field(core_regs, "sp_el1");
+        }
+    }
+
+    // The value of system registers is stored in the `addr` field
+    let sys_regs = state.get("sys_regs").and_then(Value::as_array);
+    for reg in sys_regs.into_iter().flatten() {
+        let (id, value) = match from_bytes::<[u64; 2]>(reg) {
+            Some([id, value]) => (id, value),
+            None => (field(reg, "id"), field(reg, "addr")),
+        };
+        if one_reg::size(id) == 8 {
+            one_reg::set_aarch64(&mut vcpu, id, value);
+        }
+    }
+
+    Ok(vcpu)
+}
+
+fn read_vcpus(cpu_manager: &Value) -> VmResult<Vcpus> {
+    let mut vcpus: Vec<(u64, Value)> = cpu_manager
+        .get("snapshots")
+        .and_then(Value::as_object)
+        .context("missing vCPUs snapshots")?
+        .iter()
+        .map(|(id, snapshot)| {
+            let id = id.parse().context("invalid vCPU id")?;
+            Ok((id, component_state(snapshot)?))
+        })
+        .collect::<VmResult<_>>()?;
+    vcpus.sort_by_key(|(id, _)| *id);
+
+    let is_x86_64 = vcpus.iter().any(|(_, state)| state.get("sregs").is_some());
+    Ok(if is_x86_64 {
+        let vcpus = vcpus.iter().map(|(_, state)| x86_64_vcpu(state));
+        Vcpus::X86_64(vcpus.collect::<VmResult<_>>()?)
+    } else {
+        let vcpus = vcpus.iter().map(|(_, state)| aarch64_vcpu(state));
+        Vcpus::Aarch64(vcpus.collect::<VmResult<_>>()?)
+    })
+}
+
+#[derive(Debug)]
+pub struct CloudHypervisor {
+    mem: MemRemap<vmc::mem::File>,
+    vcpus: Vcpus,
+}
+
+impl CloudHypervisor {
+    /// Opens a snapshot directory.
+    pub fn read<P: AsRef<Path>>(path: P) -> VmResult<Self> {
+        let path = path.as_ref();
+
+        let state = fs::read(path.join("state.json")).context("failed to read state.json")?;
+        let state: Value = serde_json::from_slice(&state).map_err(VmError::new)?;
+
+        let memory_manager =
+            find_snapshot(&state, "memory-manager").context("missing memory manager state")?;
+        let memory_manager = component_state(memory_manager)?;
+        let ranges = memory_manager["memory_ranges"]["data"]
+            .as_array()
+            .context("missing memory ranges")?;
+
+        let mut mappings = Vec::with_capacity(ranges.len());
+        let mut remap_at = Vec::with_capacity(ranges.len());
+        let mut offset = 0;
+        for range in ranges {
+            let start = field(range, "gpa");
+            let length = field(range, "length");
+            mappings.push(MemoryMap {
+                start: PhysicalAddress(start),
+                end: PhysicalAddress(start + length),
+            });
+            remap_at.push(PhysicalAddress(offset));
+            offset += length;
+        }
+
+        let file =
+            fs::File::open(path.join("memory-ranges")).context("failed to open memory-ranges")?;
+        let size = file.metadata()?.len();
+        if offset > size {
+            return Err(VmError::new("memory-ranges file is too small"));
+        }
+        let mem = MemRemap::new(vmc::mem::File::new(file, 0, size), mappings, remap_at);
+
+        let cpu_manager = find_snapshot(&state, "cpu-manager").context("missing CPU manager")?;
+        let vcpus = read_vcpus(cpu_manager)?;
+
+        Ok(Self { mem, vcpus })
+    }
+}
+
+impl vmc::Memory for CloudHypervisor {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+delegate_vcpus!(CloudHypervisor);
+
+impl vmc::Backend for CloudHypervisor {}
@@ -0,0 +1,45 @@
+use bytemuck::Zeroable;
+use vmc::{
+    VirtualAddress,
+    arch::aarch64::{Registers, Vcpu},
+};
+
+use super::{Notes, Vcpus, read_note};
+
+/// Offset of `pr_reg` in `struct elf_prstatus`
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+/// QEMU does not save system registers on aarch64, so try to recover the
+/// kernel page directory from the `VMCOREINFO` note written by Linux guests.
+fn find_ttbr1(notes: &Notes) -> Option<u64> {
+    let swapper_pg_dir = notes.vmcoreinfo("SYMBOL(swapper_pg_dir)")?;
+    let kimage_voffset = notes.vmcoreinfo("NUMBER(kimage_voffset)")?;
+    Some(swapper_pg_dir.wrapping_sub(kimage_voffset))
+}
+
+pub(super) fn read_vcpus(notes: &Notes) -> Vcpus {
+    let ttbr1 = find_ttbr1(notes);
+    match ttbr1 {
+        Some(ttbr1) => log::debug!("Found TTBR1 0x{ttbr1:x} in VMCOREINFO"),
+        None => log::warn!("No VMCOREINFO found, TTBR registers will be missing"),
+    }
+
+    let vcpus = notes
+        .prstatus
+        .iter()
+        .map(|desc| {
+            // `user_pt_regs` has the same layout as our registers
+            let registers: Registers = read_note(desc, PRSTATUS_REGS_OFFSET);
+
+            let mut vcpu = Vcpu::zeroed();
+            vcpu.registers = registers;
+            if VirtualAddress(registers.pc).is_kernel() {
+                vcpu.special_registers.sp_el1 = registers.sp;
+            }
+            vcpu.special_registers.ttbr1_el1 = ttbr1.unwrap_or(0);
+            vcpu
+        })
+        .collect();
+
+    Vcpus::Aarch64(vcpus)
+}
@@ -0,0 +1,241 @@
+//! ELF core files, such as the ones produced by QEMU's `dump-guest-memory`
+//! command, by `virsh dump --memory-only` or by VirtualBox's
+//! `VBoxManage debugvm dumpvmcore`.
+//!
+//! Guest memory is described by `PT_LOAD` segments, whose physical address
+//! gives the position of the segment in the guest. vCPUs registers are read
+//! from `NT_PRSTATUS` notes, and from `QEMU` or `VBCPU` notes when they are
+//! available.
+
+use object::{
+    LittleEndian as LE, elf,
+    read::elf::{FileHeader, ProgramHeader, SectionHeader},
+};
+use std::{fs, path::Path};
+use vmc::{PhysicalAddress, ResultExt, VmError, VmResult, mem::MemoryMap};
+
+use super::{
+    read_at,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+
+mod aarch64;
+mod x86_64;
+
+type FileHeader64 = elf::FileHeader64<LE>;
+type ProgramHeader64 = elf::ProgramHeader64<LE>;
+type SectionHeader64 = elf::SectionHeader64<LE>;
+
+/// Note types used by VirtualBox
+const NT_VBOXCORE: u32 = 0xb00;
+const NT_VBOXCPU: u32 = 0xb01;
+
+/// Notes of an ELF core file that are relevant to us.
+///
+/// Notes are kept in the order in which they appear in the file.
+#[derive(Debug, Default)]
+pub(crate) struct Notes {
+    pub prstatus: Vec<Vec<u8>>,
+    pub qemu: Vec<Vec<u8>>,
+    pub vbox_core: Option<Vec<u8>>,
+    pub vbox_cpu: Vec<Vec<u8>>,
+    pub vmcoreinfo: Option<Vec<u8>>,
+}
+
+impl Notes {
+    pub fn parse(&mut self, data: &[u8], align: u64) -> VmResult<()> {
+        let mut notes = object::read::elf::NoteIterator::<FileHeader64>::new(LE, align, data)
+            .map_err(VmError::new)?;
+
+        while let Some(note) = notes.next().map_err(VmError::new)? {
+            let desc = note.desc().to_vec();
+
+            match (note.name(), note.n_type(LE)) {
+                (b"CORE", elf::NT_PRSTATUS) => self.prstatus.push(desc),
+                (b"QEMU", 0) => self.qemu.push(desc),
+                (b"VBCORE", NT_VBOXCORE) => self.vbox_core = Some(desc),
+                (b"VBCPU", NT_VBOXCPU) => self.vbox_cpu.push(desc),
+                (b"VMCOREINFO", 0) => self.vmcoreinfo = Some(desc),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets a value from the `VMCOREINFO` note written by Linux guests.
+    pub fn vmcoreinfo(&self, key: &str) -> Option<u64> {
+        let info = self.vmcoreinfo.as_deref()?;
+        let info = core::str::from_utf8(info).ok()?;
+
+        info.lines().find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix('=')?;
+            match value.strip_prefix("0x") {
+                Some(value) => u64::from_str_radix(value, 16).ok(),
+                // Symbols are written in hexadecimal, numbers in decimal
+                None if key.starts_with("SYMBOL(") => u64::from_str_radix(value, 16).ok(),
+                None => value.parse::<i64>().ok().map(|n| n as u64),
+            }
+        })
+    }
+}
+
+/// The parsed headers of an ELF core file.
+#[derive(Debug)]
+pub(crate) struct ElfHeaders {
+    pub machine: u16,
+    pub mappings: Vec<MemoryMap>,
+    pub remap_at: Vec<PhysicalAddress>,
+    pub notes: Notes,
+}
+
+impl ElfHeaders {
+    pub fn read(file: &fs::File) -> VmResult<Self> {
+        let header = read_at(file, 0, size_of::<FileHeader64>())?;
+        let header = FileHeader64::parse(&*header).map_err(VmError::new)?;
+
+        if !header.is_class_64() || !header.is_little_endian() {
+            return Err(VmError::new("only little-endian ELF64 files are supported"));
+        }
+        if header.e_type(LE) != elf::ET_CORE {
+            return Err(VmError::new("not an ELF core file"));
+        }
+
+        // With a lot of segments, the real count is in the first section
+        let mut phnum = header.e_phnum(LE) as usize;
+        if phnum == elf::PN_XNUM as usize {
+            let section = read_at(file, header.e_shoff(LE), size_of::<SectionHeader64>())?;
+            let section: &SectionHeader64 = bytemuck_elf(&section)?;
+            phnum = section.sh_info(LE) as usize;
+        }
+
+        if header.e_phentsize(LE) as usize != size_of::<ProgramHeader64>() {
+            return Err(VmError::new("invalid program header size"));
+        }
+        let segments = read_at(
+            file,
+            header.e_phoff(LE),
+            phnum * size_of::<ProgramHeader64>(),
+        )?;
+        let (segments, _) = object::pod::slice_from_bytes::<ProgramHeader64>(&segments, phnum)
+            .map_err(|()| VmError::new("failed to read program headers"))?;
+
+        let mut mappings = Vec::new();
+        let mut remap_at = Vec::new();
+        let mut notes = Notes::default();
+
+        for segment in segments {
+            match segment.p_type(LE) {
+                elf::PT_LOAD => {
+                    let size = segment.p_filesz(LE);
+                    if size == 0 {
+                        continue;
+                    }
+
+                    let start = PhysicalAddress(segment.p_paddr(LE));
+                    mappings.push(MemoryMap {
+                        start,
+                        end: start + size,
+                    });
+                    remap_at.push(PhysicalAddress(segment.p_offset(LE)));
+                }
+                elf::PT_NOTE => {
+                    let data = read_at(file, segment.p_offset(LE), segment.p_filesz(LE) as usize)
+                        .context("failed to read notes")?;
+                    notes.parse(&data, segment.p_align(LE))?;
+                }
+                _ => (),
+            }
+        }
+
+        log::debug!(
+            "Found {} memory segments and {} CPU notes",
+            mappings.len(),
+            notes.prstatus.len()
+        );
+
+        Ok(Self {
+            machine: header.e_machine(LE),
+            mappings,
+            remap_at,
+            notes,
+        })
+    }
+}
+
+fn bytemuck_elf<T: object::pod::Pod>(data: &[u8]) -> VmResult<&T> {
+    object::pod::from_bytes(data)
+        .map(|(value, _)| value)
+        .map_err(|()| VmError::new("unexpected end of ELF file"))
+}
+
+/// Reads a C structure from the start of a note.
+///
+/// Missing trailing bytes are zeroed, as several notes have been extended over
+/// time.
+pub(crate) fn read_note<T: bytemuck::Pod>(desc: &[u8], offset: usize) -> T {
+    let mut value = T::zeroed();
+    let bytes = bytemuck::bytes_of_mut(&mut value);
+    let desc = desc.get(offset..).unwrap_or(&[]);
+    let len = core::cmp::min(desc.len(), bytes.len());
+    bytes[..len].copy_from_slice(&desc[..len]);
+    value
+}
+
+/// Reads vCPUs registers from the notes of a core file.
+pub(crate) fn vcpus_from_notes(machine: u16, notes: &Notes) -> VmResult<Vcpus> {
+    match machine {
+        elf::EM_X86_64 => Ok(x86_64::read_vcpus(notes)),
+        elf::EM_AARCH64 => Ok(aarch64::read_vcpus(notes)),
+        _ => Err(VmError::new("unsupported architecture")),
+    }
+}
+
+#[derive(Debug)]
+pub struct ElfCore<Mem> {
+    vcpus: Vcpus,
+    mem: vmc::mem::MemRemap<Mem>,
+}
+
+impl ElfCore<vmc::mem::File> {
+    pub fn read<P: AsRef<Path>>(path: P) -> VmResult<Self> {
+        let file = fs::File::open(path)?;
+        Self::from_file(file)
+    }
+
+    pub fn from_file(file: fs::File) -> VmResult<Self> {
+        let headers = ElfHeaders::read(&file)?;
+
+        let vcpus = vcpus_from_notes(headers.machine, &headers.notes)?;
+
+        let size = file.metadata()?.len();
+        let mem = vmc::mem::MemRemap::new(
+            vmc::mem::File::new(file, 0, size),
+            headers.mappings,
+            headers.remap_at,
+        );
+
+        Ok(Self { vcpus, mem })
+    }
+}
+
+impl<Mem: vmc::Memory> vmc::Memory for ElfCore<Mem> {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    #[inline]
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        self.mem.is_valid(addr, size)
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> vmc::MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+delegate_vcpus!(ElfCore<Mem>);
+
+impl<Mem: vmc::Memory> vmc::Backend for ElfCore<Mem> {}
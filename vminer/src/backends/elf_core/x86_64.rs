@@ -0,0 +1,438 @@
+use bytemuck::{Pod, Zeroable};
+use vmc::arch::x86_64::{Dtable, OtherRegisters, Registers, Segment, SpecialRegisters, Vcpu};
+
+use super::{Notes, Vcpus, read_note};
+
+/// Offset of `pr_reg` in `struct elf_prstatus`
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+/// `struct user_regs_struct`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct UserRegs {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rax: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    orig_rax: u64,
+    rip: u64,
+    cs: u64,
+    eflags: u64,
+    rsp: u64,
+    ss: u64,
+    fs_base: u64,
+    gs_base: u64,
+    ds: u64,
+    es: u64,
+    fs: u64,
+    gs: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct QemuSegment {
+    selector: u32,
+    limit: u32,
+    flags: u32,
+    pad: u32,
+    base: u64,
+}
+
+/// `QEMUCPUState`, see QEMU's `target/i386/arch_dump.c`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct QemuCpuState {
+    version: u32,
+    size: u32,
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rsp: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rflags: u64,
+    cs: QemuSegment,
+    ds: QemuSegment,
+    es: QemuSegment,
+    fs: QemuSegment,
+    gs: QemuSegment,
+    ss: QemuSegment,
+    ldt: QemuSegment,
+    tr: QemuSegment,
+    gdt: QemuSegment,
+    idt: QemuSegment,
+    cr: [u64; 5],
+    // Optional fields, check `size` before using them
+    kernel_gs_base: u64,
+}
+
+/// `DBGFCORE_MAGIC`, see VirtualBox's `include/VBox/vmm/dbgfcorefmt.h`
+const VBOX_CORE_MAGIC: u32 = 0xc01ac0de;
+
+/// `DBGFCOREDESCRIPTOR`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct VboxCoreDescriptor {
+    magic: u32,
+    format_version: u32,
+    size: u32,
+    vbox_version: u32,
+    vbox_revision: u32,
+    cpus: u32,
+}
+
+/// `DBGFCORESEL`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct VboxSegment {
+    base: u64,
+    limit: u32,
+    attr: u32,
+    selector: u16,
+    reserved0: u16,
+    reserved1: u32,
+}
+
+/// `DBGFXDTR`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct VboxDtable {
+    base: u64,
+    limit: u32,
+    reserved: u32,
+}
+
+/// The beginning of `DBGFCORECPU`, the extended state is not used.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct VboxCpu {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cs: VboxSegment,
+    ds: VboxSegment,
+    es: VboxSegment,
+    fs: VboxSegment,
+    gs: VboxSegment,
+    ss: VboxSegment,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+    dr: [u64; 8],
+    gdtr: VboxDtable,
+    idtr: VboxDtable,
+    ldtr: VboxSegment,
+    tr: VboxSegment,
+    sysenter: [u64; 3],
+    efer: u64,
+    star: u64,
+    pat: u64,
+    lstar: u64,
+    cstar: u64,
+    sfmask: u64,
+    kernel_gs_base: u64,
+    apic_base: u64,
+}
+
+impl QemuSegment {
+    /// QEMU keeps segment attributes in the same layout as the high 32 bits
+    /// of a segment descriptor.
+    fn to_segment(self) -> Segment {
+        let flags = self.flags;
+        let bit = |n: u32| ((flags >> n) & 1) as u8;
+
+        Segment {
+            base: self.base,
+            limit: self.limit,
+            selector: self.selector as u16,
+            type_: ((flags >> 8) & 0xf) as u8,
+            present: bit(15),
+            dpl: ((flags >> 13) & 0x3) as u8,
+            db: bit(22),
+            s: bit(12),
+            l: bit(21),
+            g: bit(23),
+            avl: bit(20),
+            unusable: 0,
+            padding: 0,
+        }
+    }
+
+    fn to_dtable(self) -> Dtable {
+        Dtable {
+            base: self.base,
+            limit: self.limit as u16,
+            padding: [0; 3],
+        }
+    }
+}
+
+impl VboxSegment {
+    /// VirtualBox keeps segment attributes in the format of VT-x access
+    /// rights.
+    fn to_segment(self) -> Segment {
+        let attr = self.attr;
+        let bit = |n: u32| ((attr >> n) & 1) as u8;
+
+        Segment {
+            base: self.base,
+            limit: self.limit,
+            selector: self.selector,
+            type_: (attr & 0xf) as u8,
+            present: bit(7),
+            dpl: ((attr >> 5) & 0x3) as u8,
+            db: bit(14),
+            s: bit(4),
+            l: bit(13),
+            g: bit(15),
+            avl: bit(12),
+            unusable: bit(16),
+            padding: 0,
+        }
+    }
+}
+
+impl VboxDtable {
+    fn to_dtable(self) -> Dtable {
+        Dtable {
+            base: self.base,
+            limit: self.limit as u16,
+            padding: [0; 3],
+        }
+    }
+}
+
+fn from_prstatus(regs: &UserRegs) -> Vcpu {
+    let mut vcpu = Vcpu::zeroed();
+
+    vcpu.registers = Registers {
+        rax: regs.rax,
+        rbx: regs.rbx,
+        rcx: regs.rcx,
+        rdx: regs.rdx,
+        rsi: regs.rsi,
+        rdi: regs.rdi,
+        rsp: regs.rsp,
+        rbp: regs.rbp,
+        r8: regs.r8,
+        r9: regs.r9,
+        r10: regs.r10,
+        r11: regs.r11,
+        r12: regs.r12,
+        r13: regs.r13,
+        r14: regs.r14,
+        r15: regs.r15,
+        rip: regs.rip,
+        rflags: regs.eflags,
+    };
+
+    let sregs = &mut vcpu.special_registers;
+    sregs.cs.selector = regs.cs as u16;
+    sregs.ds.selector = regs.ds as u16;
+    sregs.es.selector = regs.es as u16;
+    sregs.ss.selector = regs.ss as u16;
+    sregs.fs.selector = regs.fs as u16;
+    sregs.fs.base = regs.fs_base;
+    sregs.gs.selector = regs.gs as u16;
+    sregs.gs.base = regs.gs_base;
+
+    vcpu
+}
+
+fn from_qemu(state: &QemuCpuState) -> Vcpu {
+    let registers = Registers {
+        rax: state.rax,
+        rbx: state.rbx,
+        rcx: state.rcx,
+        rdx: state.rdx,
+        rsi: state.rsi,
+        rdi: state.rdi,
+        rsp: state.rsp,
+        rbp: state.rbp,
+        r8: state.r8,
+        r9: state.r9,
+        r10: state.r10,
+        r11: state.r11,
+        r12: state.r12,
+        r13: state.r13,
+        r14: state.r14,
+        r15: state.r15,
+        rip: state.rip,
+        rflags: state.rflags,
+    };
+
+    let special_registers = SpecialRegisters {
+        cs: state.cs.to_segment(),
+        ds: state.ds.to_segment(),
+        es: state.es.to_segment(),
+        fs: state.fs.to_segment(),
+        gs: state.gs.to_segment(),
+        ss: state.ss.to_segment(),
+        tr: state.tr.to_segment(),
+        ldt: state.ldt.to_segment(),
+        gdt: state.gdt.to_dtable(),
+        idt: state.idt.to_dtable(),
+        cr0: state.cr[0],
+        cr2: state.cr[2],
+        cr3: state.cr[3],
+        cr4: state.cr[4],
+        ..Zeroable::zeroed()
+    };
+
+    // `kernel_gs_base` was added later, older versions of QEMU don't have it
+    let gs_kernel_base = if state.size as usize >= size_of::<QemuCpuState>() {
+        state.kernel_gs_base
+    } else {
+        0
+    };
+
+    Vcpu {
+        registers,
+        special_registers,
+        other_registers: OtherRegisters {
+            lstar: 0,
+            gs_kernel_base,
+        },
+    }
+}
+
+fn from_vbox(cpu: &VboxCpu) -> Vcpu {
+    let registers = Registers {
+        rax: cpu.rax,
+        rbx: cpu.rbx,
+        rcx: cpu.rcx,
+        rdx: cpu.rdx,
+        rsi: cpu.rsi,
+        rdi: cpu.rdi,
+        rsp: cpu.rsp,
+        rbp: cpu.rbp,
+        r8: cpu.r8,
+        r9: cpu.r9,
+        r10: cpu.r10,
+        r11: cpu.r11,
+        r12: cpu.r12,
+        r13: cpu.r13,
+        r14: cpu.r14,
+        r15: cpu.r15,
+        rip: cpu.rip,
+        rflags: cpu.rflags,
+    };
+
+    let special_registers = SpecialRegisters {
+        cs: cpu.cs.to_segment(),
+        ds: cpu.ds.to_segment(),
+        es: cpu.es.to_segment(),
+        fs: cpu.fs.to_segment(),
+        gs: cpu.gs.to_segment(),
+        ss: cpu.ss.to_segment(),
+        tr: cpu.tr.to_segment(),
+        ldt: cpu.ldtr.to_segment(),
+        gdt: cpu.gdtr.to_dtable(),
+        idt: cpu.idtr.to_dtable(),
+        cr0: cpu.cr0,
+        cr2: cpu.cr2,
+        cr3: cpu.cr3,
+        cr4: cpu.cr4,
+        efer: cpu.efer,
+        apic_base: cpu.apic_base,
+        ..Zeroable::zeroed()
+    };
+
+    Vcpu {
+        registers,
+        special_registers,
+        other_registers: OtherRegisters {
+            lstar: cpu.lstar,
+            gs_kernel_base: cpu.kernel_gs_base,
+        },
+    }
+}
+
+/// Checks that VirtualBox notes can be used.
+fn has_vbox_notes(notes: &Notes) -> bool {
+    if notes.vbox_cpu.is_empty() {
+        return false;
+    }
+
+    let descriptor = match &notes.vbox_core {
+        Some(desc) => read_note::<VboxCoreDescriptor>(desc, 0),
+        None => VboxCoreDescriptor::zeroed(),
+    };
+    if descriptor.magic != VBOX_CORE_MAGIC {
+        log::warn!("Invalid VirtualBox core descriptor, ignoring VBCPU notes");
+        return false;
+    }
+
+    log::debug!(
+        "VirtualBox core dump, format version {:#x}",
+        descriptor.format_version
+    );
+    true
+}
+
+pub(super) fn read_vcpus(notes: &Notes) -> Vcpus {
+    // QEMU writes all `NT_PRSTATUS` notes first, then its own notes in the
+    // same order. The later are much more complete, so prefer them.
+    // VirtualBox only writes its own notes.
+    let vcpus = if has_vbox_notes(notes) {
+        notes
+            .vbox_cpu
+            .iter()
+            .map(|desc| from_vbox(&read_note(desc, 0)))
+            .collect()
+    } else if notes.qemu.is_empty() {
+        log::warn!("No QEMU CPU state found, system registers will be missing");
+
+        notes
+            .prstatus
+            .iter()
+            .map(|desc| from_prstatus(&read_note(desc, PRSTATUS_REGS_OFFSET)))
+            .collect()
+    } else {
+        notes
+            .qemu
+            .iter()
+            .map(|desc| from_qemu(&read_note(desc, 0)))
+            .collect()
+    };
+
+    Vcpus::X86_64(vcpus)
+}
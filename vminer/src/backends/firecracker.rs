@@ -0,0 +1,361 @@
+//! Firecracker snapshots.
+//!
+//! A snapshot is made of a guest memory file, which is a plain copy of guest
+//! RAM, and of a `vmstate` file with the serialized state of the microVM.
+//!
+//! The `vmstate` file starts with a magic number identifying the architecture
+//! and with the version of the snapshot format, followed by the `bincode`
+//! serialization of the state of the microVM. We decode it field by field, up
+//! to the state of the vCPUs, and refuse versions whose layout we don't know.
+//!
+//! In this encoding, integers are little-endian, lengths of sequences are
+//! `u64` and KVM structures are serialized as raw bytes with their length.
+
+use super::{
+    one_reg,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+use std::{fs, path::Path};
+use vmc::{
+    MemoryAccessResult, PhysicalAddress, VmError, VmResult,
+    arch::{aarch64, x86_64},
+    mem::{MemRemap, MemoryMap},
+};
+
+const MAGIC_X86_64: u64 = 0x0710_1984_8664_0000;
+const MAGIC_AARCH64: u64 = 0x0710_1984_aaaa_0000;
+
+/// Snapshots made before Firecracker 1.7 use another format, with the version
+/// of the format in the low bits of the magic number.
+const MAGIC_OLD_FORMAT_MASK: u64 = !0xffff;
+
+/// Major versions of the snapshot format that we know how to decode
+/// (Firecracker 1.7 and 1.8).
+const SUPPORTED_VERSIONS: core::ops::RangeInclusive<u64> = 1..=2;
+
+const MSR_LSTAR: u32 = 0xc000_0082;
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// Size of `struct kvm_msr_entry`
+const MSR_ENTRY_SIZE: usize = 16;
+
+/// Size of `struct kvm_xsave2`, without its flexible array member
+const XSAVE2_HEADER_SIZE: usize = 4100;
+
+/// Reads the `bincode` encoding of the state.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> VmResult<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(VmError::new("truncated vmstate file"));
+        }
+        let (data, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> VmResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> VmResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> VmResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bool(&mut self) -> VmResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(VmError::new("invalid boolean in vmstate file")),
+        }
+    }
+
+    /// Reads the length of a sequence.
+    fn len(&mut self) -> VmResult<usize> {
+        let len = self.u64()?;
+        if len > self.data.len() as u64 {
+            return Err(VmError::new("invalid length in vmstate file"));
+        }
+        Ok(len as usize)
+    }
+
+    fn bytes(&mut self) -> VmResult<&'a [u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> VmResult<&'a str> {
+        Ok(core::str::from_utf8(self.bytes()?)?)
+    }
+
+    fn option<T>(&mut self, read: impl FnOnce(&mut Self) -> VmResult<T>) -> VmResult<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => read(self).map(Some),
+            _ => Err(VmError::new("invalid option in vmstate file")),
+        }
+    }
+
+    fn vec<T>(&mut self, mut read: impl FnMut(&mut Self) -> VmResult<T>) -> VmResult<Vec<T>> {
+        let len = self.len()?;
+        (0..len).map(|_| read(self)).collect()
+    }
+
+    /// Reads a KVM structure, checking its size.
+    fn kvm_struct<T: bytemuck::Pod>(&mut self) -> VmResult<T> {
+        let bytes = self.bytes()?;
+        if bytes.len() != core::mem::size_of::<T>() {
+            return Err(VmError::new("unexpected size of KVM structure"));
+        }
+        Ok(bytemuck::pod_read_unaligned(bytes))
+    }
+
+    /// Reads a KVM structure with a flexible array member, whose entries are
+    /// KVM structures too, and returns the entries.
+    fn fam_struct(&mut self, entry_size: usize) -> VmResult<Vec<&'a [u8]>> {
+        self.bytes()?;
+        self.vec(|r| {
+            let entry = r.bytes()?;
+            if entry.len() != entry_size {
+                return Err(VmError::new("unexpected size of KVM structure"));
+            }
+            Ok(entry)
+        })
+    }
+}
+
+/// Architecture of a snapshot, given by the magic number of its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+/// Reads the header of the state, and checks that we know its version.
+fn read_header(r: &mut Reader) -> VmResult<Arch> {
+    let arch = match r.u64()? {
+        MAGIC_X86_64 => Arch::X86_64,
+        MAGIC_AARCH64 => Arch::Aarch64,
+        magic
+            if magic & MAGIC_OLD_FORMAT_MASK == MAGIC_X86_64
+                || magic & MAGIC_OLD_FORMAT_MASK == MAGIC_AARCH64 =>
+        {
+            return Err(VmError::new(
+                "snapshots made before Firecracker 1.7 are not supported",
+            ));
+        }
+        _ => return Err(VmError::new("not a Firecracker vmstate file")),
+    };
+
+    let version = r.str()?;
+    let major = version
+        .split('.')
+        .next()
+        .and_then(|major| major.parse().ok());
+    match major {
+        Some(major) if SUPPORTED_VERSIONS.contains(&major) => Ok(arch),
+        _ => Err(VmError::new(format!(
+            "unsupported Firecracker snapshot version {version}"
+        ))),
+    }
+}
+
+/// Skips `VmInfo`.
+fn skip_vm_info(r: &mut Reader) -> VmResult<()> {
+    let _mem_size_mib = r.u64()?;
+    let _smt = r.bool()?;
+    let _cpu_template = r.u32()?;
+    let _kernel_image_path = r.str()?;
+    let _initrd_path = r.option(Reader::str)?;
+    let _boot_args = r.option(Reader::str)?;
+    let _huge_pages = r.u32()?;
+    Ok(())
+}
+
+/// Reads `GuestMemoryState`, which gives the physical address, size and offset
+/// in the memory file of each region.
+fn read_memory_state(r: &mut Reader) -> VmResult<(Vec<MemoryMap>, Vec<PhysicalAddress>)> {
+    let regions = r.vec(|r| Ok((r.u64()?, r.u64()?, r.u64()?)))?;
+
+    let mut mappings = Vec::with_capacity(regions.len());
+    let mut remap_at = Vec::with_capacity(regions.len());
+    for (base_address, size, offset) in regions {
+        let end = base_address
+            .checked_add(size)
+            .ok_or_else(|| VmError::new("invalid memory region"))?;
+        mappings.push(MemoryMap {
+            start: PhysicalAddress(base_address),
+            end: PhysicalAddress(end),
+        });
+        remap_at.push(PhysicalAddress(offset));
+    }
+
+    Ok((mappings, remap_at))
+}
+
+/// Skips the `VmState` of x86_64: PIT, clock, PICs and IOAPIC.
+fn skip_x86_64_vm_state(r: &mut Reader) -> VmResult<()> {
+    for _ in 0..5 {
+        r.bytes()?;
+    }
+    Ok(())
+}
+
+/// Skips the `VmState` of aarch64, which is the state of the GIC.
+fn skip_aarch64_vm_state(r: &mut Reader) -> VmResult<()> {
+    /// Skips a list of registers, made of chunks of `width` bytes.
+    fn skip_regs(r: &mut Reader, width: usize) -> VmResult<()> {
+        r.vec(|r| r.vec(|r| r.take(width)))?;
+        Ok(())
+    }
+
+    // Distributor registers
+    skip_regs(r, 4)?;
+
+    // Redistributor and CPU interface registers of each vCPU
+    r.vec(|r| {
+        skip_regs(r, 4)?;
+        skip_regs(r, 8)?;
+        r.vec(|r| r.option(|r| r.vec(|r| r.take(8))))?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn read_x86_64_vcpu(r: &mut Reader) -> VmResult<x86_64::Vcpu> {
+    let _cpuid = r.fam_struct(40)?;
+
+    let mut other_registers: x86_64::OtherRegisters = bytemuck::Zeroable::zeroed();
+    for msrs in r.vec(|r| r.fam_struct(MSR_ENTRY_SIZE))? {
+        for entry in msrs {
+            // `struct kvm_msr_entry { index: u32, reserved: u32, data: u64 }`
+            let index = u32::from_le_bytes(entry[..4].try_into().unwrap());
+            let data = u64::from_le_bytes(entry[8..].try_into().unwrap());
+            match index {
+                MSR_LSTAR => other_registers.lstar = data,
+                MSR_KERNEL_GS_BASE => other_registers.gs_kernel_base = data,
+                _ => (),
+            }
+        }
+    }
+
+    let _debug_regs = r.bytes()?;
+    let _lapic = r.bytes()?;
+    let _mp_state = r.bytes()?;
+    let registers = r.kvm_struct()?;
+    let special_registers = r.kvm_struct()?;
+    let _vcpu_events = r.bytes()?;
+    let _xcrs = r.bytes()?;
+
+    // Either a `kvm_xsave`, or a `kvm_xsave2` followed by its extra words
+    if r.bytes()?.len() == XSAVE2_HEADER_SIZE {
+        r.vec(Reader::u32)?;
+    }
+
+    let _tsc_khz = r.option(Reader::u32)?;
+
+    Ok(x86_64::Vcpu {
+        registers,
+        special_registers,
+        other_registers,
+    })
+}
+
+fn read_aarch64_vcpu(r: &mut Reader) -> VmResult<aarch64::Vcpu> {
+    let _mp_state = r.bytes()?;
+
+    // Registers are serialized as the list of their `KVM_{GET,SET}_ONE_REG`
+    // ids, followed by their values
+    let ids = r.vec(Reader::u64)?;
+    let data = r.bytes()?;
+
+    let mut vcpu: aarch64::Vcpu = bytemuck::Zeroable::zeroed();
+    let mut offset = 0;
+    for id in ids {
+        let size = one_reg::size(id);
+        let value = data
+            .get(offset..offset + size)
+            .ok_or_else(|| VmError::new("invalid registers in vmstate file"))?;
+        if size == 8 {
+            one_reg::set_aarch64(&mut vcpu, id, u64::from_le_bytes(value.try_into().unwrap()));
+        }
+        offset += size;
+    }
+
+    let _mpidr = r.u64()?;
+    let _kvi = r.option(Reader::bytes)?;
+
+    Ok(vcpu)
+}
+
+/// Decodes the state of a microVM, up to the state of its vCPUs.
+fn read_state(state: &[u8]) -> VmResult<(Vcpus, Vec<MemoryMap>, Vec<PhysicalAddress>)> {
+    let r = &mut Reader { data: state };
+    let arch = read_header(r)?;
+
+    skip_vm_info(r)?;
+    let (mappings, remap_at) = read_memory_state(r)?;
+
+    let vcpus = match arch {
+        Arch::X86_64 => {
+            skip_x86_64_vm_state(r)?;
+            Vcpus::X86_64(r.vec(read_x86_64_vcpu)?)
+        }
+        Arch::Aarch64 => {
+            skip_aarch64_vm_state(r)?;
+            Vcpus::Aarch64(r.vec(read_aarch64_vcpu)?)
+        }
+    };
+
+    Ok((vcpus, mappings, remap_at))
+}
+
+#[derive(Debug)]
+pub struct Firecracker {
+    mem: MemRemap<vmc::mem::File>,
+    vcpus: Vcpus,
+}
+
+impl Firecracker {
+    /// Opens a snapshot from its `vmstate` file and its guest memory file.
+    pub fn read<P: AsRef<Path>, Q: AsRef<Path>>(vmstate: P, mem: Q) -> VmResult<Self> {
+        let state = fs::read(vmstate)?;
+        let (vcpus, mappings, remap_at) = read_state(&state)?;
+
+        let file = fs::File::open(mem)?;
+        let size = file.metadata()?.len();
+
+        for (mapping, offset) in mappings.iter().zip(&remap_at) {
+            let end = offset.0.checked_add(mapping.end.0 - mapping.start.0);
+            if end.is_none_or(|end| end > size) {
+                return Err(VmError::new("memory region goes past the end of the file"));
+            }
+        }
+
+        let mem = MemRemap::new(vmc::mem::File::new(file, 0, size), mappings, remap_at);
+        Ok(Self { mem, vcpus })
+    }
+}
+
+impl vmc::Memory for Firecracker {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+delegate_vcpus!(Firecracker);
+
+impl vmc::Backend for Firecracker {}
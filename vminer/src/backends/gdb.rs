@@ -0,0 +1,675 @@
+//! A client of the GDB remote serial protocol.
+//!
+//! This backend connects to a GDB stub, such as the one of QEMU (`-gdb` or
+//! `-s`), over TCP or a Unix socket. The guest is stopped while a debugger is
+//! attached, so registers of all vCPUs are read when connecting.
+//!
+//! Registers are found using the target description sent by the stub, which
+//! QEMU uses to expose system registers (eg `cr3`). Physical memory is read
+//! after switching to physical memory mode with `Qqemu.PhyMemMode`, and its
+//! layout is read from the output of `monitor info mtree -f`, unless it is
+//! given explicitly.
+
+use super::{
+    mtree,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+use vmc::{
+    MemoryAccessError, MemoryAccessResult, PhysicalAddress, ResultExt, VmError, VmResult,
+    arch::{aarch64, x86_64},
+    mem::MemoryMap,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum size of memory read with a single packet
+const MAX_READ_SIZE: usize = 0x800;
+
+/// Maximum size of target description read with a single packet
+const MAX_XFER_SIZE: usize = 0x800;
+
+/// Registers in `g` packets when the stub does not send a target description,
+/// as sent by QEMU for x86_64.
+const DEFAULT_X86_64_REGISTERS: &[(&str, usize)] = &[
+    ("rax", 64),
+    ("rbx", 64),
+    ("rcx", 64),
+    ("rdx", 64),
+    ("rsi", 64),
+    ("rdi", 64),
+    ("rbp", 64),
+    ("rsp", 64),
+    ("r8", 64),
+    ("r9", 64),
+    ("r10", 64),
+    ("r11", 64),
+    ("r12", 64),
+    ("r13", 64),
+    ("r14", 64),
+    ("r15", 64),
+    ("rip", 64),
+    ("eflags", 32),
+    ("cs", 32),
+    ("ss", 32),
+    ("ds", 32),
+    ("es", 32),
+    ("fs", 32),
+    ("gs", 32),
+];
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        // Unavailable value
+        b'x' => Some(0),
+        _ => None,
+    };
+
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks_exact(2)
+        .map(|pair| Some((digit(pair[0])? << 4) | digit(pair[1])?))
+        .collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_error(reply: &[u8]) -> bool {
+    reply.len() == 3 && reply[0] == b'E'
+}
+
+/// Removes escapes and run-length encoding from a packet.
+fn decode(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut data = data.iter().copied();
+
+    while let Some(b) = data.next() {
+        match b {
+            b'}' => {
+                if let Some(b) = data.next() {
+                    decoded.push(b ^ 0x20);
+                }
+            }
+            b'*' => {
+                if let (Some(&last), Some(count)) = (decoded.last(), data.next()) {
+                    let count = count.saturating_sub(29) as usize;
+                    decoded.extend(core::iter::repeat_n(last, count));
+                }
+            }
+            _ => decoded.push(b),
+        }
+    }
+
+    decoded
+}
+
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    stream: BufReader<Stream>,
+    no_ack: bool,
+    thread: Option<String>,
+}
+
+impl Connection {
+    fn send(&mut self, packet: &str) -> io::Result<()> {
+        let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${packet}#{checksum:02x}");
+
+        for _ in 0..3 {
+            self.stream.get_mut().write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack)?;
+            match ack[0] {
+                b'+' => return Ok(()),
+                b'-' => continue,
+                _ => return Err(invalid_data("unexpected acknowledgment")),
+            }
+        }
+
+        Err(invalid_data("packet rejected by the stub"))
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            // Skip anything until the start of a packet
+            let mut skipped = Vec::new();
+            self.stream.read_until(b'$', &mut skipped)?;
+            if skipped.last() != Some(&b'$') {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let mut data = Vec::new();
+            self.stream.read_until(b'#', &mut data)?;
+            if data.pop() != Some(b'#') {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let checksum = from_hex(&checksum).ok_or_else(|| invalid_data("invalid checksum"))?;
+            let valid = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == checksum[0];
+
+            if !self.no_ack {
+                let ack: &[u8] = if valid { b"+" } else { b"-" };
+                self.stream.get_mut().write_all(ack)?;
+            }
+
+            if valid {
+                return Ok(decode(&data));
+            } else if self.no_ack {
+                return Err(invalid_data("invalid checksum"));
+            }
+        }
+    }
+
+    fn request(&mut self, packet: &str) -> io::Result<Vec<u8>> {
+        self.send(packet)?;
+        self.recv()
+    }
+
+    fn select_thread(&mut self, thread: &str) -> VmResult<()> {
+        if self.thread.as_deref() == Some(thread) {
+            return Ok(());
+        }
+
+        let reply = self.request(&format!("Hg{thread}"))?;
+        if reply != b"OK" {
+            return Err(VmError::new(format!("failed to select thread {thread}")));
+        }
+        self.thread = Some(thread.to_owned());
+        Ok(())
+    }
+
+    /// Reads the value of a single register.
+    fn read_register(&mut self, regnum: u64) -> VmResult<Option<Vec<u8>>> {
+        let reply = self.request(&format!("p{regnum:x}"))?;
+        if reply.is_empty() || is_error(&reply) {
+            return Ok(None);
+        }
+        let value = from_hex(&reply).context("invalid register value")?;
+        Ok(Some(value))
+    }
+
+    /// Reads a whole object with `qXfer`.
+    fn read_xfer(&mut self, object: &str, annex: &str) -> VmResult<Option<Vec<u8>>> {
+        let mut content = Vec::new();
+
+        loop {
+            let offset = content.len();
+            let reply = self.request(&format!(
+                "qXfer:{object}:read:{annex}:{offset:x},{MAX_XFER_SIZE:x}"
+            ))?;
+
+            match reply.split_first() {
+                None => return Ok(None),
+                Some((b'm', data)) => content.extend_from_slice(data),
+                Some((b'l', data)) => {
+                    content.extend_from_slice(data);
+                    return Ok(Some(content));
+                }
+                _ => return Err(VmError::new(format!("failed to read {object} {annex}"))),
+            }
+        }
+    }
+
+    /// Runs a monitor command and returns its output.
+    fn monitor(&mut self, command: &str) -> VmResult<Option<String>> {
+        self.send(&format!("qRcmd,{}", to_hex(command.as_bytes())))?;
+        let mut output = Vec::new();
+
+        loop {
+            let reply = self.recv()?;
+            match &*reply {
+                b"" => return Ok(None),
+                b"OK" => break,
+                _ if is_error(&reply) => return Ok(None),
+                [b'O', hex @ ..] => output.extend(from_hex(hex).context("invalid output")?),
+                hex => {
+                    output.extend(from_hex(hex).context("invalid output")?);
+                    break;
+                }
+            }
+        }
+
+        Ok(Some(String::from_utf8_lossy(&output).into_owned()))
+    }
+
+    fn threads(&mut self) -> VmResult<Vec<String>> {
+        let mut threads = Vec::new();
+        let mut reply = self.request("qfThreadInfo")?;
+
+        loop {
+            match reply.split_first() {
+                Some((b'm', list)) => {
+                    let list = core::str::from_utf8(list)?;
+                    threads.extend(list.split(',').map(String::from));
+                }
+                Some((b'l', _)) => break,
+                // Single-threaded stubs may not support this
+                _ if threads.is_empty() => return Ok(vec![String::from("0")]),
+                _ => return Err(VmError::new("invalid thread list")),
+            }
+            reply = self.request("qsThreadInfo")?;
+        }
+
+        Ok(threads)
+    }
+}
+
+#[derive(Debug)]
+struct RegisterDesc {
+    name: String,
+    bitsize: usize,
+    regnum: u64,
+    /// Offset of the register in `g` packets
+    offset: usize,
+}
+
+#[derive(Debug, Default)]
+struct TargetDesc {
+    arch: Option<String>,
+    registers: Vec<RegisterDesc>,
+}
+
+/// Gets the value of an attribute in an XML tag.
+fn xml_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    loop {
+        let i = rest.find(name)?;
+        let before = rest[..i].chars().next_back();
+        rest = &rest[i + name.len()..];
+
+        if before.is_some_and(char::is_whitespace)
+            && let Some(value) = rest.trim_start().strip_prefix('=')
+        {
+            let value = value.trim_start();
+            let quote = value.chars().next()?;
+            let value = &value[1..];
+            return Some(&value[..value.find(quote)?]);
+        }
+    }
+}
+
+impl TargetDesc {
+    fn read(conn: &mut Connection, xfer: bool) -> VmResult<Self> {
+        let mut desc = Self::default();
+        let mut next_regnum = 0;
+
+        if !(xfer && desc.read_file(conn, "target.xml", &mut next_regnum, 0)?) {
+            log::warn!("No target description, assuming x86_64");
+            for &(name, bitsize) in DEFAULT_X86_64_REGISTERS {
+                desc.registers.push(RegisterDesc {
+                    name: name.into(),
+                    bitsize,
+                    regnum: next_regnum,
+                    offset: 0,
+                });
+                next_regnum += 1;
+            }
+        }
+
+        desc.registers.sort_by_key(|reg| reg.regnum);
+        let mut offset = 0;
+        for reg in &mut desc.registers {
+            reg.offset = offset;
+            offset += reg.bitsize / 8;
+        }
+
+        Ok(desc)
+    }
+
+    fn read_file(
+        &mut self,
+        conn: &mut Connection,
+        annex: &str,
+        next_regnum: &mut u64,
+        depth: u32,
+    ) -> VmResult<bool> {
+        if depth > 4 {
+            return Err(VmError::new("too many nested target descriptions"));
+        }
+        let Some(xml) = conn.read_xfer("features", annex)? else {
+            return Ok(false);
+        };
+        let xml = String::from_utf8_lossy(&xml);
+
+        for tag in xml.split('<').skip(1) {
+            let (tag, text) = tag.split_once('>').unwrap_or((tag, ""));
+
+            if tag == "architecture" {
+                self.arch = Some(text.trim().to_owned());
+            } else if tag.starts_with("xi:include") {
+                let href = xml_attribute(tag, "href").context("invalid xi:include")?;
+                self.read_file(conn, href, next_regnum, depth + 1)?;
+            } else if tag.starts_with("reg") && tag[3..].starts_with(char::is_whitespace) {
+                let name = xml_attribute(tag, "name").context("register without name")?;
+                let bitsize = xml_attribute(tag, "bitsize")
+                    .and_then(|size| size.parse().ok())
+                    .context("invalid register size")?;
+                if let Some(regnum) = xml_attribute(tag, "regnum") {
+                    *next_regnum = regnum.parse().context("invalid register number")?;
+                }
+
+                self.registers.push(RegisterDesc {
+                    name: name.into(),
+                    bitsize,
+                    regnum: *next_regnum,
+                    offset: 0,
+                });
+                *next_regnum += 1;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Registers of the current thread.
+struct ThreadRegisters<'a> {
+    conn: &'a mut Connection,
+    desc: &'a TargetDesc,
+    g: Vec<u8>,
+}
+
+impl<'a> ThreadRegisters<'a> {
+    fn read(conn: &'a mut Connection, desc: &'a TargetDesc, thread: &str) -> VmResult<Self> {
+        conn.select_thread(thread)?;
+        let reply = conn.request("g")?;
+        if is_error(&reply) {
+            return Err(VmError::new("failed to read registers"));
+        }
+        let g = from_hex(&reply).context("invalid registers")?;
+        Ok(Self { conn, desc, g })
+    }
+
+    /// Gets a register by name, or zero if it is not available.
+    fn get(&mut self, name: &str) -> VmResult<u64> {
+        let Some(reg) =
+            (self.desc.registers.iter()).find(|reg| reg.name.eq_ignore_ascii_case(name))
+        else {
+            log::debug!("Register {name} is not available");
+            return Ok(0);
+        };
+
+        let size = reg.bitsize / 8;
+        let value = match self.g.get(reg.offset..reg.offset + size) {
+            Some(value) => value.to_vec(),
+            // System registers are often not part of `g` packets
+            None => match self.conn.read_register(reg.regnum)? {
+                Some(value) => value,
+                None => {
+                    log::debug!("Failed to read register {name}");
+                    return Ok(0);
+                }
+            },
+        };
+
+        let mut bytes = [0; 8];
+        let len = value.len().min(8);
+        bytes[..len].copy_from_slice(&value[..len]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn x86_64(&mut self) -> VmResult<x86_64::Vcpu> {
+        let mut vcpu: x86_64::Vcpu = bytemuck::Zeroable::zeroed();
+
+        let regs = &mut vcpu.registers;
+        regs.rax = self.get("rax")?;
+        regs.rbx = self.get("rbx")?;
+        regs.rcx = self.get("rcx")?;
+        regs.rdx = self.get("rdx")?;
+        regs.rsi = self.get("rsi")?;
+        regs.rdi = self.get("rdi")?;
+        regs.rsp = self.get("rsp")?;
+        regs.rbp = self.get("rbp")?;
+        regs.r8 = self.get("r8")?;
+        regs.r9 = self.get("r9")?;
+        regs.r10 = self.get("r10")?;
+        regs.r11 = self.get("r11")?;
+        regs.r12 = self.get("r12")?;
+        regs.r13 = self.get("r13")?;
+        regs.r14 = self.get("r14")?;
+        regs.r15 = self.get("r15")?;
+        regs.rip = self.get("rip")?;
+        regs.rflags = self.get("eflags")?;
+
+        let sregs = &mut vcpu.special_registers;
+        sregs.cs.selector = self.get("cs")? as u16;
+        sregs.ss.selector = self.get("ss")? as u16;
+        sregs.ds.selector = self.get("ds")? as u16;
+        sregs.es.selector = self.get("es")? as u16;
+        sregs.fs.selector = self.get("fs")? as u16;
+        sregs.gs.selector = self.get("gs")? as u16;
+        sregs.fs.base = self.get("fs_base")?;
+        sregs.gs.base = self.get("gs_base")?;
+        sregs.cr0 = self.get("cr0")?;
+        sregs.cr2 = self.get("cr2")?;
+        sregs.cr3 = self.get("cr3")?;
+        sregs.cr4 = self.get("cr4")?;
+        sregs.cr8 = self.get("cr8")?;
+        sregs.efer = self.get("efer")?;
+
+        vcpu.other_registers.gs_kernel_base = self.get("k_gs_base")?;
+
+        Ok(vcpu)
+    }
+
+    fn aarch64(&mut self) -> VmResult<aarch64::Vcpu> {
+        let mut vcpu: aarch64::Vcpu = bytemuck::Zeroable::zeroed();
+
+        for i in 0..31 {
+            vcpu.registers.regs[i] = self.get(&format!("x{i}"))?;
+        }
+        vcpu.registers.sp = self.get("sp")?;
+        vcpu.registers.pc = self.get("pc")?;
+        vcpu.registers.pstate = self.get("cpsr")?;
+
+        let sregs = &mut vcpu.special_registers;
+        sregs.sp_el1 = self.get("sp_el1")?;
+        sregs.ttbr0_el1 = self.get("ttbr0_el1")?;
+        sregs.ttbr1_el1 = self.get("ttbr1_el1")?;
+        sregs.vbar_el1 = self.get("vbar_el1")?;
+
+        Ok(vcpu)
+    }
+}
+
+/// Finds the layout of physical memory from the output of `info mtree -f`.
+fn memory_mappings(output: &str) -> Vec<MemoryMap> {
+    let mut mappings: Vec<MemoryMap> = Vec::new();
+
+    for region in mtree::ram_regions(output) {
+        match mappings.last_mut() {
+            Some(last) if last.end == region.mapping.start => last.end = region.mapping.end,
+            _ => mappings.push(region.mapping),
+        }
+    }
+
+    mappings
+}
+
+#[derive(Debug)]
+pub struct Gdb {
+    conn: Mutex<Connection>,
+    mappings: Vec<MemoryMap>,
+    vcpus: Vcpus,
+    physical: bool,
+}
+
+impl Gdb {
+    /// Connects to a GDB stub listening on a TCP socket.
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> VmResult<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Self::create(Stream::Tcp(stream))
+    }
+
+    /// Connects to a GDB stub listening on a Unix socket.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> VmResult<Self> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Self::create(Stream::Unix(stream))
+    }
+
+    /// Sets the layout of physical memory, in case it cannot be found from the
+    /// stub.
+    pub fn with_memory_mappings(mut self, mappings: Vec<MemoryMap>) -> Self {
+        self.mappings = mappings;
+        self
+    }
+
+    fn create(stream: Stream) -> VmResult<Self> {
+        let mut conn = Connection {
+            stream: BufReader::new(stream),
+            no_ack: false,
+            thread: None,
+        };
+
+        let features = conn.request("qSupported:multiprocess+;xmlRegisters=i386")?;
+        let features = String::from_utf8_lossy(&features);
+        if features.split(';').any(|f| f == "QStartNoAckMode+")
+            && conn.request("QStartNoAckMode")? == b"OK"
+        {
+            conn.no_ack = true;
+        }
+
+        // Query the stop reason, as expected by stubs
+        conn.request("?")?;
+
+        let xfer = features.split(';').any(|f| f == "qXfer:features:read+");
+        let desc = TargetDesc::read(&mut conn, xfer)?;
+
+        let threads = conn.threads()?;
+        let vcpus = match desc.arch.as_deref() {
+            None | Some("i386:x86-64") => {
+                let vcpus = threads
+                    .iter()
+                    .map(|thread| ThreadRegisters::read(&mut conn, &desc, thread)?.x86_64());
+                Vcpus::X86_64(vcpus.collect::<VmResult<_>>()?)
+            }
+            Some("aarch64") => {
+                let vcpus = threads
+                    .iter()
+                    .map(|thread| ThreadRegisters::read(&mut conn, &desc, thread)?.aarch64());
+                Vcpus::Aarch64(vcpus.collect::<VmResult<_>>()?)
+            }
+            Some(arch) => return Err(VmError::new(format!("unsupported architecture: {arch}"))),
+        };
+
+        let physical = conn.request("Qqemu.PhyMemMode:1")? == b"OK";
+        if !physical {
+            log::warn!("The stub does not support physical memory mode");
+        }
+
+        let mappings = match conn.monitor("info mtree -f")? {
+            Some(output) => memory_mappings(&output),
+            None => Vec::new(),
+        };
+        if mappings.is_empty() {
+            log::warn!("Failed to find the layout of physical memory");
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            mappings,
+            vcpus,
+            physical,
+        })
+    }
+}
+
+impl vmc::Memory for Gdb {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        &self.mappings
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        if !self.physical {
+            return Err(MemoryAccessError::Unsupported);
+        }
+
+        let mut conn = self.conn.lock().unwrap();
+        let mut addr = addr;
+
+        for chunk in buf.chunks_mut(MAX_READ_SIZE) {
+            let reply = conn.request(&format!("m{:x},{:x}", addr.0, chunk.len()))?;
+            if reply.is_empty() || is_error(&reply) {
+                return Err(MemoryAccessError::OutOfBounds);
+            }
+
+            let data = from_hex(&reply).ok_or_else(|| invalid_data("invalid memory content"))?;
+            if data.len() != chunk.len() {
+                return Err(MemoryAccessError::OutOfBounds);
+            }
+            chunk.copy_from_slice(&data);
+            addr += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+}
+
+delegate_vcpus!(Gdb);
+
+impl vmc::Backend for Gdb {}
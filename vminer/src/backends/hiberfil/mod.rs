@@ -0,0 +1,562 @@
+//! Windows hibernation files (`hiberfil.sys`).
+//!
+//! Two formats are supported:
+//!
+//! - Until Windows 7, memory range tables list the pages saved after each of
+//!   them, which are compressed by groups of up to 16 pages in Xpress blocks.
+//! - Since Windows 8, pages are saved in two "restore sets" (boot and kernel),
+//!   made of compression sets that contain a list of page runs followed by the
+//!   data of these pages, compressed with Xpress or Xpress-Huffman.
+//!
+//! Compressed data is decompressed on demand, and the last used blocks are
+//! kept in a small cache.
+//!
+//! The processor state saved at hibernation (a `KPROCESSOR_STATE`) is looked
+//! for in the first pages of the file. It is always found in files written by
+//! older versions of Windows, but may be missing in newer ones, in which case
+//! the kernel page directory is found by scanning memory.
+
+use bytemuck::Zeroable;
+use std::{fs, path::Path, sync::Mutex};
+use vmc::{
+    MemoryAccessError, MemoryAccessResult, PhysicalAddress, VmError, VmResult,
+    arch::x86_64::{Dtable, OtherRegisters, Registers, Vcpu},
+    mem::MemoryMap,
+};
+
+use super::{
+    page_cache::PageCache,
+    read_at,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+
+mod xpress;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Number of decompressed blocks kept in memory
+const CACHE_SIZE: usize = 64;
+
+const SIGNATURES: &[&[u8; 4]] = &[b"hibr", b"HIBR", b"wake", b"WAKE", b"RSTR", b"HORM"];
+
+/// Magic of Xpress blocks in the legacy format
+const XPRESS_MAGIC: &[u8; 8] = b"\x81\x81xpress";
+const XPRESS_HEADER_SIZE: u64 = 0x20;
+
+/// Offsets of the fields we need in `PO_MEMORY_IMAGE`
+mod header {
+    pub const PAGE_SIZE: usize = 0x18;
+    /// Legacy format
+    pub const FIRST_TABLE_PAGE: usize = 0x68;
+    /// Offsets of `FirstBootRestorePage` and `FirstKernelRestorePage` in
+    /// Windows 10 and later, then in Windows 8 and 8.1
+    pub const RESTORE_PAGES: [(usize, usize); 2] = [(0x68, 0x70), (0x60, 0x68)];
+}
+
+/// Offsets of the fields we need in x64 `KPROCESSOR_STATE`
+mod state {
+    pub const CR0: usize = 0x00;
+    pub const CR2: usize = 0x08;
+    pub const CR3: usize = 0x10;
+    pub const CR4: usize = 0x18;
+    pub const GDTR: usize = 0x66;
+    pub const IDTR: usize = 0x76;
+    pub const TR: usize = 0x80;
+    pub const LDTR: usize = 0x82;
+    pub const CR8: usize = 0xb0;
+    pub const MSR_GS_BASE: usize = 0xb8;
+    pub const MSR_GS_SWAP: usize = 0xc0;
+    pub const MSR_LSTAR: usize = 0xd0;
+
+    /// Offsets of the `CONTEXT`, which depends on the size of
+    /// `KSPECIAL_REGISTERS`
+    pub const CONTEXT: [usize; 2] = [0xe0, 0xf0];
+    pub const CONTEXT_SIZE: usize = 0x100;
+}
+
+/// Offsets of the fields we need in x64 `CONTEXT`
+mod context {
+    pub const SEG_CS: usize = 0x38;
+    pub const EFLAGS: usize = 0x44;
+    pub const RAX: usize = 0x78;
+    pub const RIP: usize = 0xf8;
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Xpress,
+    XpressHuffman,
+}
+
+/// A block of compressed pages.
+#[derive(Debug)]
+struct Block {
+    offset: u64,
+    size: u32,
+    pages: u32,
+    compression: Compression,
+}
+
+/// Where a page is stored: the index of its block and its index in it.
+#[derive(Debug, Clone, Copy)]
+struct PageLocation {
+    block: u32,
+    index: u32,
+}
+
+#[derive(Debug, Default)]
+struct PageTable {
+    blocks: Vec<Block>,
+    pages: hashbrown::HashMap<u64, PageLocation>,
+}
+
+impl PageTable {
+    fn push_block(&mut self, block: Block) -> u32 {
+        self.blocks.push(block);
+        (self.blocks.len() - 1) as u32
+    }
+
+    fn insert(&mut self, pfn: u64, block: u32, index: u32) {
+        self.pages.insert(pfn, PageLocation { block, index });
+    }
+
+    /// Builds the memory map from the set of saved pages.
+    fn mappings(&self) -> Vec<MemoryMap> {
+        let mut pfns: Vec<u64> = self.pages.keys().copied().collect();
+        pfns.sort_unstable();
+
+        let mut mappings: Vec<MemoryMap> = Vec::new();
+        for pfn in pfns {
+            let start = PhysicalAddress(pfn * PAGE_SIZE);
+            match mappings.last_mut() {
+                Some(last) if last.end == start => last.end = start + PAGE_SIZE,
+                _ => mappings.push(MemoryMap {
+                    start,
+                    end: start + PAGE_SIZE,
+                }),
+            }
+        }
+        mappings
+    }
+}
+
+/// Reads the pages of the legacy format, starting at the first memory range
+/// table.
+fn read_legacy(file: &fs::File, file_size: u64, first_table: u64) -> VmResult<PageTable> {
+    let mut table = PageTable::default();
+    let mut next_table = first_table;
+
+    while next_table != 0 {
+        if next_table * PAGE_SIZE >= file_size {
+            return Err(VmError::new("memory range table is out of the file"));
+        }
+        let ranges = read_at(file, next_table * PAGE_SIZE, PAGE_SIZE as usize)?;
+
+        // `PO_MEMORY_RANGE_ARRAY`: a link to the next table, then ranges
+        let count = read_u32(&ranges, 8) as usize;
+        let count = count.min((PAGE_SIZE as usize - 16) / 16);
+        let pfns = (0..count).flat_map(|i| {
+            let start = read_u64(&ranges, 16 + 16 * i);
+            let end = read_u64(&ranges, 24 + 16 * i);
+            start..end
+        });
+
+        // Saved pages follow the table
+        let mut offset = (next_table + 1) * PAGE_SIZE;
+        let mut block = 0;
+        let mut remaining = 0;
+
+        for pfn in pfns {
+            if remaining == 0 {
+                let header = read_at(file, offset, XPRESS_HEADER_SIZE as usize)
+                    .map_err(|_| VmError::new("unexpected end of file"))?;
+                if !header.starts_with(XPRESS_MAGIC) {
+                    return Err(VmError::new("invalid Xpress block"));
+                }
+
+                let info = read_u32(&header, 8);
+                let pages = (info & 0x3ff) + 1;
+                let size = (info >> 10) + 1;
+                let compression = if size as u64 == pages as u64 * PAGE_SIZE {
+                    Compression::None
+                } else {
+                    Compression::Xpress
+                };
+
+                block = table.push_block(Block {
+                    offset: offset + XPRESS_HEADER_SIZE,
+                    size,
+                    pages,
+                    compression,
+                });
+                remaining = pages;
+                offset += XPRESS_HEADER_SIZE + (size as u64).next_multiple_of(8);
+            }
+
+            let index = table.blocks[block as usize].pages - remaining;
+            table.insert(pfn, block, index);
+            remaining -= 1;
+        }
+
+        next_table = read_u64(&ranges, 0);
+    }
+
+    Ok(table)
+}
+
+/// The header of a compression set of the newer format.
+struct CompressionSet {
+    /// Runs of pages, as first frame number and page count
+    runs: Vec<(u64, u32)>,
+    /// Size of the data that follows the header
+    size: u32,
+    compression: Compression,
+}
+
+/// Reads the header of a compression set, checking that it looks valid.
+fn read_compression_set(file: &fs::File, offset: u64, end: u64) -> Option<CompressionSet> {
+    let header = read_at(file, offset, 4).ok()?;
+    let header = read_u32(&header, 0);
+
+    let count = (header & 0xff) as usize;
+    let size = (header >> 8) & 0x3f_ffff;
+    let huffman = header & (1 << 31) != 0;
+    if count == 0 || size == 0 {
+        return None;
+    }
+
+    let descs = read_at(file, offset + 4, 8 * count).ok()?;
+    let runs: Vec<(u64, u32)> = descs
+        .chunks_exact(8)
+        .map(|desc| {
+            let desc = u64::from_le_bytes(desc.try_into().unwrap());
+            (desc >> 4, (desc & 0xf) as u32 + 1)
+        })
+        .collect();
+
+    let pages: u64 = runs.iter().map(|&(_, n)| n as u64).sum();
+    if size as u64 > pages * PAGE_SIZE || offset + 4 + 8 * count as u64 + size as u64 > end {
+        return None;
+    }
+
+    let compression = if size as u64 == pages * PAGE_SIZE {
+        Compression::None
+    } else if huffman {
+        Compression::XpressHuffman
+    } else {
+        Compression::Xpress
+    };
+
+    Some(CompressionSet {
+        runs,
+        size,
+        compression,
+    })
+}
+
+/// Reads the pages of a restore set, until an invalid compression set or
+/// `end` is found.
+fn read_restore_set(file: &fs::File, table: &mut PageTable, mut offset: u64, end: u64) {
+    while let Some(CompressionSet {
+        runs,
+        size,
+        compression,
+    }) = read_compression_set(file, offset, end)
+    {
+        let data = offset + 4 + 8 * runs.len() as u64;
+        let pages = runs.iter().map(|&(_, n)| n).sum();
+        let block = table.push_block(Block {
+            offset: data,
+            size,
+            pages,
+            compression,
+        });
+
+        let pfns = runs.iter().flat_map(|&(pfn, n)| pfn..pfn + n as u64);
+        for (index, pfn) in (0..).zip(pfns) {
+            table.insert(pfn, block, index);
+        }
+
+        offset = data + size as u64;
+    }
+}
+
+/// Reads the processor state at the given offset of a page, checking that it
+/// looks valid.
+fn read_processor_state(page: &[u8], context_offset: usize) -> Option<Vcpu> {
+    let ctx = &page[context_offset..context_offset + state::CONTEXT_SIZE];
+
+    let cr0 = read_u64(page, state::CR0);
+    let cr3 = read_u64(page, state::CR3);
+    let cs = read_u16(ctx, context::SEG_CS);
+    let rip = read_u64(ctx, context::RIP);
+    if cr0 & 0x8000_0001 != 0x8000_0001 || cr3 == 0 || !cr3.is_multiple_of(PAGE_SIZE) {
+        return None;
+    }
+    if cs & 3 != 0 || rip < 0xffff_8000_0000_0000 {
+        return None;
+    }
+
+    let mut vcpu = Vcpu::zeroed();
+
+    // `CONTEXT` stores general purpose registers in encoding order
+    let gprs = |n: usize| read_u64(ctx, context::RAX + 8 * n);
+    vcpu.registers = Registers {
+        rax: gprs(0),
+        rcx: gprs(1),
+        rdx: gprs(2),
+        rbx: gprs(3),
+        rsp: gprs(4),
+        rbp: gprs(5),
+        rsi: gprs(6),
+        rdi: gprs(7),
+        r8: gprs(8),
+        r9: gprs(9),
+        r10: gprs(10),
+        r11: gprs(11),
+        r12: gprs(12),
+        r13: gprs(13),
+        r14: gprs(14),
+        r15: gprs(15),
+        rip,
+        rflags: read_u32(ctx, context::EFLAGS) as u64,
+    };
+
+    let dtable = |offset: usize| Dtable {
+        base: read_u64(page, offset + 2),
+        limit: read_u16(page, offset),
+        padding: [0; 3],
+    };
+
+    let sregs = &mut vcpu.special_registers;
+    sregs.cs.selector = cs;
+    sregs.ds.selector = read_u16(ctx, context::SEG_CS + 2);
+    sregs.es.selector = read_u16(ctx, context::SEG_CS + 4);
+    sregs.fs.selector = read_u16(ctx, context::SEG_CS + 6);
+    sregs.gs.selector = read_u16(ctx, context::SEG_CS + 8);
+    sregs.gs.base = read_u64(page, state::MSR_GS_BASE);
+    sregs.ss.selector = read_u16(ctx, context::SEG_CS + 10);
+    sregs.tr.selector = read_u16(page, state::TR);
+    sregs.ldt.selector = read_u16(page, state::LDTR);
+    sregs.gdt = dtable(state::GDTR);
+    sregs.idt = dtable(state::IDTR);
+    sregs.cr0 = cr0;
+    sregs.cr2 = read_u64(page, state::CR2);
+    sregs.cr3 = cr3;
+    sregs.cr4 = read_u64(page, state::CR4);
+    sregs.cr8 = read_u64(page, state::CR8);
+
+    vcpu.other_registers = OtherRegisters {
+        lstar: read_u64(page, state::MSR_LSTAR),
+        gs_kernel_base: read_u64(page, state::MSR_GS_SWAP),
+    };
+
+    Some(vcpu)
+}
+
+/// Looks for the processor state in the pages before saved memory.
+fn find_processor_state(file: &fs::File, first_data_page: u64) -> Option<Vcpu> {
+    (1..first_data_page.min(16)).find_map(|n| {
+        let page = read_at(file, n * PAGE_SIZE, PAGE_SIZE as usize).ok()?;
+        state::CONTEXT
+            .iter()
+            .find_map(|&offset| read_processor_state(&page, offset))
+    })
+}
+
+#[derive(Debug)]
+pub struct Hiberfil {
+    file: vmc::mem::File,
+    table: PageTable,
+    mappings: Vec<MemoryMap>,
+    cache: Mutex<PageCache>,
+    vcpus: Vcpus,
+}
+
+impl Hiberfil {
+    pub fn read<P: AsRef<Path>>(path: P) -> VmResult<Self> {
+        let file = fs::File::open(path)?;
+        Self::from_file(file)
+    }
+
+    pub fn from_file(file: fs::File) -> VmResult<Self> {
+        let file_size = file.metadata()?.len();
+        let header = read_at(&file, 0, PAGE_SIZE as usize)?;
+
+        if header.iter().all(|&b| b == 0) {
+            return Err(VmError::new(
+                "empty hibernation file header, the system was probably resumed",
+            ));
+        }
+        if !SIGNATURES.iter().any(|sig| header.starts_with(*sig)) {
+            return Err(VmError::new("invalid hibernation file signature"));
+        }
+        if read_u32(&header, header::PAGE_SIZE) as u64 != PAGE_SIZE {
+            return Err(VmError::new("unsupported page size"));
+        }
+
+        // The legacy format is recognized by the Xpress block that follows
+        // the first memory range table.
+        let first_table = read_u64(&header, header::FIRST_TABLE_PAGE);
+        let is_legacy = first_table
+            .checked_add(1)
+            .and_then(|page| page.checked_mul(PAGE_SIZE))
+            .and_then(|offset| read_at(&file, offset, XPRESS_MAGIC.len()).ok())
+            .is_some_and(|magic| magic == XPRESS_MAGIC);
+
+        let (table, first_data_page) = if is_legacy {
+            log::debug!("First memory range table at page {first_table:#x}");
+            (read_legacy(&file, file_size, first_table)?, first_table)
+        } else {
+            // Find the restore sets of the newer format, checking that they
+            // start with a valid compression set
+            let is_valid = |page: u64| {
+                page.checked_mul(PAGE_SIZE)
+                    .is_some_and(|offset| read_compression_set(&file, offset, file_size).is_some())
+            };
+            let (boot, kernel) = header::RESTORE_PAGES
+                .iter()
+                .map(|&(boot, kernel)| (read_u64(&header, boot), read_u64(&header, kernel)))
+                .find(|&(boot, kernel)| {
+                    boot != 0 && boot < kernel && is_valid(boot) && is_valid(kernel)
+                })
+                .ok_or_else(|| VmError::new("unsupported hibernation file format"))?;
+
+            log::debug!("Restore sets at pages {boot:#x} and {kernel:#x}");
+            let mut table = PageTable::default();
+            read_restore_set(&file, &mut table, boot * PAGE_SIZE, kernel * PAGE_SIZE);
+            read_restore_set(&file, &mut table, kernel * PAGE_SIZE, file_size);
+            (table, boot)
+        };
+
+        let mappings = table.mappings();
+        log::debug!(
+            "Found {} pages in {} compressed blocks",
+            table.pages.len(),
+            table.blocks.len()
+        );
+        if mappings.is_empty() {
+            return Err(VmError::new("no saved memory found"));
+        }
+
+        let vcpus = match find_processor_state(&file, first_data_page) {
+            Some(vcpu) => vec![vcpu],
+            None => {
+                log::warn!("Processor state not found, registers will be missing");
+                Vec::new()
+            }
+        };
+
+        Ok(Self {
+            file: vmc::mem::File::new(file, 0, file_size),
+            table,
+            mappings,
+            cache: Mutex::new(PageCache::new(CACHE_SIZE)),
+            vcpus: Vcpus::X86_64(vcpus),
+        })
+    }
+
+    fn load_block(&self, n: u32) -> MemoryAccessResult<Box<[u8]>> {
+        let block = &self.table.blocks[n as usize];
+
+        let mut data = vec![0; block.size as usize];
+        vmc::Memory::read_physical(&self.file, PhysicalAddress(block.offset), &mut data)?;
+        let mut pages = vec![0; block.pages as usize * PAGE_SIZE as usize].into_boxed_slice();
+
+        let size = match block.compression {
+            Compression::None => {
+                pages.copy_from_slice(&data);
+                Some(pages.len())
+            }
+            Compression::Xpress => xpress::decompress(&data, &mut pages),
+            Compression::XpressHuffman => xpress::decompress_huffman(&data, &mut pages),
+        };
+
+        match size {
+            Some(size) if size == pages.len() => Ok(pages),
+            _ => {
+                log::warn!(
+                    "Failed to decompress block at 0x{:x} ({:?})",
+                    block.offset,
+                    block.compression
+                );
+                Err(MemoryAccessError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "failed to decompress block",
+                )))
+            }
+        }
+    }
+
+    fn with_page<T>(&self, pfn: u64, f: impl FnOnce(&[u8]) -> T) -> MemoryAccessResult<T> {
+        let location = *self
+            .table
+            .pages
+            .get(&pfn)
+            .ok_or(MemoryAccessError::OutOfBounds)?;
+        let start = location.index as usize * PAGE_SIZE as usize;
+        let page = start..start + PAGE_SIZE as usize;
+
+        let mut cache = self.cache.lock().unwrap();
+
+        if let Some(block) = cache.get(location.block as u64) {
+            return Ok(f(&block[page]));
+        }
+
+        let block = self.load_block(location.block)?;
+        let result = f(&block[page]);
+        cache.insert(location.block as u64, block);
+        Ok(result)
+    }
+}
+
+impl vmc::Memory for Hiberfil {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        &self.mappings
+    }
+
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        let start = addr.0 / PAGE_SIZE;
+        let end = (addr.0 + size as u64).div_ceil(PAGE_SIZE);
+        (start..end).all(|pfn| self.table.pages.contains_key(&pfn))
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        let mut addr = addr.0;
+        let mut buf = buf;
+
+        while !buf.is_empty() {
+            let pfn = addr / PAGE_SIZE;
+            let offset = (addr % PAGE_SIZE) as usize;
+            let len = core::cmp::min(buf.len(), PAGE_SIZE as usize - offset);
+            let (start, rest) = buf.split_at_mut(len);
+
+            self.with_page(pfn, |page| {
+                start.copy_from_slice(&page[offset..offset + len])
+            })?;
+
+            buf = rest;
+            addr += len as u64;
+        }
+
+        Ok(())
+    }
+}
+
+delegate_vcpus!(Hiberfil);
+
+impl vmc::Backend for Hiberfil {}
@@ -0,0 +1,369 @@
+//! Xpress decompressors, as described in `[MS-XCA]`.
+//!
+//! Both functions fill `out` entirely and return `None` on malformed input
+//! instead of reading or writing out of bounds.
+
+struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    /// Number of 16-bit words of padding read past the end of the input
+    padding: usize,
+}
+
+impl Reader<'_> {
+    #[inline]
+    fn byte(&mut self) -> Option<usize> {
+        let b = *self.input.get(self.pos)?;
+        self.pos += 1;
+        Some(b as usize)
+    }
+
+    #[inline]
+    fn le16(&mut self) -> Option<usize> {
+        let lo = self.byte()?;
+        let hi = self.byte()?;
+        Some(lo | (hi << 8))
+    }
+
+    #[inline]
+    fn le32(&mut self) -> Option<usize> {
+        let lo = self.le16()?;
+        let hi = self.le16()?;
+        Some(lo | (hi << 16))
+    }
+
+    /// Reads 16 bits of a Huffman bitstream, which may be padded with zeros
+    /// at its end.
+    #[inline]
+    fn bits16(&mut self) -> u32 {
+        let value = self.le16().unwrap_or_else(|| {
+            self.padding += 1;
+            0
+        });
+        self.pos = self.pos.min(self.input.len());
+        value as u32
+    }
+}
+
+/// Copies a match, which may overlap with the bytes it produces.
+#[inline]
+fn copy_match(out: &mut [u8], op: &mut usize, offset: usize, len: usize) -> Option<()> {
+    let start = op.checked_sub(offset)?;
+    let len = len.min(out.len() - *op);
+    for i in 0..len {
+        out[*op + i] = out[start + i];
+    }
+    *op += len;
+    Some(())
+}
+
+/// Decompresses plain LZ77 Xpress data (`COMPRESSION_FORMAT_XPRESS`).
+pub fn decompress(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut reader = Reader {
+        input,
+        pos: 0,
+        padding: 0,
+    };
+    let mut op = 0;
+
+    let mut flags = 0u32;
+    let mut flag_count = 0;
+    let mut last_half_byte = None;
+
+    while op < out.len() {
+        if flag_count == 0 {
+            flags = reader.le32()? as u32;
+            flag_count = 32;
+        }
+        flag_count -= 1;
+
+        if flags & (1 << flag_count) == 0 {
+            out[op] = reader.byte()? as u8;
+            op += 1;
+            continue;
+        }
+
+        let bytes = reader.le16()?;
+        let offset = (bytes >> 3) + 1;
+        let mut len = bytes & 7;
+
+        if len == 7 {
+            // Two 4-bit lengths are packed in the same byte
+            len = match last_half_byte.take() {
+                Some(pos) => input[pos] as usize >> 4,
+                None => {
+                    last_half_byte = Some(reader.pos);
+                    reader.byte()? & 0xf
+                }
+            };
+
+            if len == 15 {
+                len = reader.byte()?;
+                if len == 255 {
+                    len = match reader.le16()? {
+                        0 => reader.le32()?,
+                        n => n,
+                    };
+                    len = len.checked_sub(15 + 7)?;
+                }
+                len += 15;
+            }
+            len += 7;
+        }
+        len += 3;
+
+        copy_match(out, &mut op, offset, len)?;
+    }
+
+    Some(op)
+}
+
+const HUFFMAN_SYMBOLS: usize = 512;
+const HUFFMAN_MAX_BITS: u32 = 15;
+const HUFFMAN_CHUNK_SIZE: usize = 0x10000;
+
+/// Builds the decoding table of a canonical Huffman code, indexed by the next
+/// 15 bits of input.
+fn huffman_table(lengths: &[u8; HUFFMAN_SYMBOLS]) -> Option<Vec<u16>> {
+    let mut table = vec![0u16; 1 << HUFFMAN_MAX_BITS];
+    let mut pos = 0;
+
+    for bits in 1..=HUFFMAN_MAX_BITS {
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len as u32 == bits {
+                let count = 1 << (HUFFMAN_MAX_BITS - bits);
+                table.get_mut(pos..pos + count)?.fill(symbol as u16);
+                pos += count;
+            }
+        }
+    }
+
+    Some(table)
+}
+
+/// Decompresses LZ77+Huffman Xpress data (`COMPRESSION_FORMAT_XPRESS_HUFF`).
+pub fn decompress_huffman(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut reader = Reader {
+        input,
+        pos: 0,
+        padding: 0,
+    };
+    let mut op = 0;
+
+    while op < out.len() {
+        // Each chunk starts with the lengths of the codes of all symbols,
+        // packed as 4-bit values.
+        let packed = input.get(reader.pos..reader.pos + HUFFMAN_SYMBOLS / 2)?;
+        let mut lengths = [0; HUFFMAN_SYMBOLS];
+        for (i, &b) in packed.iter().enumerate() {
+            lengths[2 * i] = b & 0xf;
+            lengths[2 * i + 1] = b >> 4;
+        }
+        let table = huffman_table(&lengths)?;
+        reader.pos += HUFFMAN_SYMBOLS / 2;
+
+        let mut next_bits = (reader.bits16() << 16) | reader.bits16();
+        let mut extra_bits = 16i32;
+
+        // Consumes `n` bits from `next_bits`, refilling it as needed. Padding
+        // may be read ahead, but consuming it means that input is truncated.
+        let consume = |reader: &mut Reader, next_bits: &mut u32, extra_bits: &mut i32, n: u32| {
+            *next_bits = next_bits.checked_shl(n).unwrap_or(0);
+            *extra_bits -= n as i32;
+            if *extra_bits < 0 {
+                *next_bits |= reader.bits16() << -*extra_bits;
+                *extra_bits += 16;
+            }
+            (reader.padding as i32 * 16 <= 16 + *extra_bits).then_some(())
+        };
+
+        let chunk_end = (op + HUFFMAN_CHUNK_SIZE).min(out.len());
+        while op < chunk_end {
+            let symbol = table[(next_bits >> (32 - HUFFMAN_MAX_BITS)) as usize] as usize;
+            let len = lengths[symbol] as u32;
+            if len == 0 {
+                return None;
+            }
+            consume(&mut reader, &mut next_bits, &mut extra_bits, len)?;
+
+            if symbol < 256 {
+                out[op] = symbol as u8;
+                op += 1;
+                continue;
+            }
+
+            let symbol = symbol - 256;
+            let offset_bits = (symbol >> 4) as u32;
+            let mut len = symbol & 0xf;
+
+            if len == 15 {
+                len = reader.byte()?;
+                if len == 255 {
+                    len = match reader.le16()? {
+                        0 => reader.le32()?,
+                        n => n,
+                    };
+                    len = len.checked_sub(15)?;
+                }
+                len += 15;
+            }
+            len += 3;
+
+            let offset = match offset_bits {
+                0 => 1,
+                n => ((next_bits >> (32 - n)) | (1 << n)) as usize,
+            };
+            consume(&mut reader, &mut next_bits, &mut extra_bits, offset_bits)?;
+
+            copy_match(out, &mut op, offset, len)?;
+        }
+    }
+
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Examples of plain LZ77 compression from `[MS-XCA]` section 3.1
+    const ALPHABET: &[u8] = b"\x3f\0\0\0abcdefghijklmnopqrstuvwxyz";
+    const ABC_100: &[u8] = b"\xff\xff\xff\x1fabc\x17\x00\x0f\xff\x26\x01";
+
+    fn decompress_vec(input: &[u8], len: usize) -> Option<Vec<u8>> {
+        let mut out = vec![0; len];
+        let n = decompress(input, &mut out)?;
+        assert_eq!(n, len);
+        Some(out)
+    }
+
+    #[test]
+    fn known_vectors() {
+        assert_eq!(
+            decompress_vec(ALPHABET, 26).unwrap(),
+            b"abcdefghijklmnopqrstuvwxyz"
+        );
+        assert_eq!(decompress_vec(ABC_100, 300).unwrap(), b"abc".repeat(100));
+    }
+
+    #[test]
+    fn truncated_input() {
+        for input in [ALPHABET, ABC_100] {
+            for len in 0..input.len() {
+                assert_eq!(decompress_vec(&input[..len], 300), None, "{len}");
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_input() {
+        // Match before the start of the output
+        assert_eq!(decompress_vec(b"\0\0\0\x80\0\0", 4), None);
+        assert_eq!(decompress_vec(b"\0\0\0\x40a\x08\0", 4), None);
+
+        // Extended length smaller than what it extends
+        assert_eq!(decompress_vec(b"\0\0\0\x40a\x07\0\x0f\xff\x05\0", 32), None);
+    }
+
+    /// Writes a LZ77+Huffman chunk using the canonical code of `lengths`.
+    struct HuffmanWriter {
+        lengths: [u8; HUFFMAN_SYMBOLS],
+        bits: Vec<bool>,
+    }
+
+    impl HuffmanWriter {
+        fn new(lengths: &[(usize, u8)]) -> Self {
+            let mut this = Self {
+                lengths: [0; HUFFMAN_SYMBOLS],
+                bits: Vec::new(),
+            };
+            for &(symbol, len) in lengths {
+                this.lengths[symbol] = len;
+            }
+            this
+        }
+
+        fn bits(&mut self, value: u32, count: u32) -> &mut Self {
+            for i in (0..count).rev() {
+                self.bits.push(value & (1 << i) != 0);
+            }
+            self
+        }
+
+        fn symbol(&mut self, symbol: usize) -> &mut Self {
+            let len = self.lengths[symbol];
+            let code: u32 = (self.lengths.iter().enumerate())
+                .filter(|&(s, &l)| l != 0 && (l, s) < (len, symbol))
+                .map(|(_, &l)| 1 << (HUFFMAN_MAX_BITS - l as u32))
+                .sum();
+            self.bits(code >> (HUFFMAN_MAX_BITS - len as u32), len as u32)
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            let mut out: Vec<u8> = (self.lengths.chunks(2))
+                .map(|pair| pair[0] | pair[1] << 4)
+                .collect();
+            for word in self.bits.chunks(16) {
+                let word = (word.iter().enumerate())
+                    .fold(0u16, |acc, (i, &bit)| acc | (bit as u16) << (15 - i));
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+            out
+        }
+    }
+
+    fn decompress_huffman_vec(input: &[u8], len: usize) -> Option<Vec<u8>> {
+        let mut out = vec![0; len];
+        let n = decompress_huffman(input, &mut out)?;
+        assert_eq!(n, len);
+        Some(out)
+    }
+
+    /// "ab", then a match of length 4 at offset 2
+    fn abab() -> Vec<u8> {
+        HuffmanWriter::new(&[(b'a' as usize, 1), (b'b' as usize, 2), (256 + 0x11, 2)])
+            .symbol(b'a' as usize)
+            .symbol(b'b' as usize)
+            .symbol(256 + 0x11)
+            .bits(0, 1)
+            .finish()
+    }
+
+    #[test]
+    fn huffman_known_vectors() {
+        assert_eq!(decompress_huffman_vec(&abab(), 6).unwrap(), b"ababab");
+
+        // A match with an extended length of 10 + 15 + 3 bytes at offset 1
+        let mut input = HuffmanWriter::new(&[(b'a' as usize, 1), (256 + 0xf, 1)])
+            .symbol(b'a' as usize)
+            .symbol(256 + 0xf)
+            .finish();
+        input.extend_from_slice(&[0, 0, 10]);
+        assert_eq!(decompress_huffman_vec(&input, 29).unwrap(), [b'a'; 29]);
+    }
+
+    #[test]
+    fn huffman_truncated_input() {
+        let input = abab();
+        for len in 0..input.len() {
+            assert_eq!(decompress_huffman_vec(&input[..len], 6), None, "{len}");
+        }
+    }
+
+    #[test]
+    fn huffman_invalid_input() {
+        // No symbol at all
+        assert_eq!(decompress_huffman_vec(&[0; 260], 1), None);
+
+        // Too many codes of the same length
+        assert_eq!(decompress_huffman_vec(&[0x11; 260], 1), None);
+
+        // Match before the start of the output
+        let input = HuffmanWriter::new(&[(b'a' as usize, 1), (256 + 0x11, 1)])
+            .symbol(b'a' as usize)
+            .symbol(256 + 0x11)
+            .bits(0, 1)
+            .finish();
+        assert_eq!(decompress_huffman_vec(&input, 5), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! A minimal LZO1X decompressor, as used by `makedumpfile -l`.
+//!
+//! This follows the structure of `lzo1x_decompress_safe` from the Linux kernel.
+//! Returns `None` on malformed input instead of reading or writing out of
+//! bounds.
+
+struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    #[inline]
+    fn byte(&mut self) -> Option<usize> {
+        let b = *self.input.get(self.pos)?;
+        self.pos += 1;
+        Some(b as usize)
+    }
+
+    #[inline]
+    fn le16(&mut self) -> Option<usize> {
+        let lo = self.byte()?;
+        let hi = self.byte()?;
+        Some(lo | (hi << 8))
+    }
+
+    /// Reads a length encoded as a run of zeros followed by a non-zero byte
+    fn zero_run(&mut self, base: usize) -> Option<usize> {
+        let mut len = 0usize;
+        while *self.input.get(self.pos)? == 0 {
+            len = len.checked_add(255)?;
+            self.pos += 1;
+        }
+        len.checked_add(base + self.byte()?)
+    }
+}
+
+fn copy_literal(reader: &mut Reader, out: &mut [u8], op: &mut usize, len: usize) -> Option<()> {
+    let src = reader.input.get(reader.pos..reader.pos.checked_add(len)?)?;
+    out.get_mut(*op..*op + len)?.copy_from_slice(src);
+    reader.pos += len;
+    *op += len;
+    Some(())
+}
+
+fn copy_match(out: &mut [u8], op: &mut usize, distance: usize, len: usize) -> Option<()> {
+    let from = op.checked_sub(distance)?;
+    if *op + len > out.len() {
+        return None;
+    }
+
+    // Regions may overlap, so copy byte by byte
+    for i in 0..len {
+        out[*op + i] = out[from + i];
+    }
+    *op += len;
+
+    Some(())
+}
+
+/// Decompresses `input` into `out`, returning the decompressed size.
+pub fn decompress(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut reader = Reader { input, pos: 0 };
+    let mut op = 0;
+    let mut state;
+
+    // The first byte may encode a literal run
+    match *input.first()? as usize {
+        t @ 18.. => {
+            reader.pos += 1;
+            let t = t - 17;
+            copy_literal(&mut reader, out, &mut op, t)?;
+            state = if t < 4 { t } else { 4 };
+        }
+        _ => state = 0,
+    }
+
+    loop {
+        let t = reader.byte()?;
+
+        let (distance, len, next) = if t < 16 {
+            if state == 0 {
+                let len = if t == 0 { reader.zero_run(15)? } else { t };
+                copy_literal(&mut reader, out, &mut op, len + 3)?;
+                state = 4;
+                continue;
+            }
+
+            let distance = 1 + (t >> 2) + (reader.byte()? << 2);
+            if state != 4 {
+                (distance, 2, t & 3)
+            } else {
+                (distance + 0x800, 3, t & 3)
+            }
+        } else if t >= 64 {
+            let distance = 1 + ((t >> 2) & 7) + (reader.byte()? << 3);
+            (distance, (t >> 5) + 1, t & 3)
+        } else if t >= 32 {
+            let len = match t & 31 {
+                0 => reader.zero_run(31)?,
+                n => n,
+            };
+            let next = reader.le16()?;
+            (1 + (next >> 2), len + 2, next & 3)
+        } else {
+            let len = match t & 7 {
+                0 => reader.zero_run(7)?,
+                n => n,
+            };
+            let next = reader.le16()?;
+            let distance = ((t & 8) << 11) + (next >> 2);
+            if distance == 0 {
+                // End of stream marker
+                return (len + 2 == 3 && reader.pos == input.len()).then_some(op);
+            }
+            (distance + 0x4000, len + 2, next & 3)
+        };
+
+        copy_match(out, &mut op, distance, len)?;
+
+        state = next;
+        copy_literal(&mut reader, out, &mut op, next)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompress;
+
+    /// End of stream marker: a M4 match with a distance of 0
+    const EOS: [u8; 3] = [0x11, 0x00, 0x00];
+
+    fn stream(parts: &[&[u8]]) -> Vec<u8> {
+        let mut stream = parts.concat();
+        stream.extend_from_slice(&EOS);
+        stream
+    }
+
+    fn vectors() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let long_literal: Vec<u8> = (0..278).map(|i| i as u8).collect();
+
+        vec![
+            // A first byte above 17 is a run of literals
+            (stream(&[b"\x16hello"]), b"hello".to_vec()),
+            // One literal and a M3 match (length 8) that overlaps its output
+            (stream(&[b"\x12a\x26\x00\x00"]), vec![b'a'; 9]),
+            // A M2 match (distance 4, length 4) followed by 2 literals
+            (stream(&[b"\x15abcd\x6e\x00xy"]), b"abcdabcdxy".to_vec()),
+            // A literal run whose length is encoded with a run of zeros
+            (
+                stream(&[b"\x00\x00\x05", &long_literal]),
+                long_literal.clone(),
+            ),
+            // A M3 match whose length (4095) is encoded with a run of zeros
+            (
+                stream(&[b"\x12a\x20", &[0; 15], b"\xed\x00\x00"]),
+                vec![b'a'; 4096],
+            ),
+        ]
+    }
+
+    #[test]
+    fn known_vectors() {
+        for (input, expected) in vectors() {
+            let mut out = vec![0; expected.len()];
+            assert_eq!(decompress(&input, &mut out), Some(expected.len()));
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn truncated_input() {
+        for (input, expected) in vectors() {
+            let mut out = vec![0; expected.len()];
+            for len in 0..input.len() {
+                assert_eq!(decompress(&input[..len], &mut out), None);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_input() {
+        let mut out = [0; 16];
+
+        // The output buffer is too small
+        assert_eq!(
+            decompress(&stream(&[b"\x12a\x26\x00\x00"]), &mut out[..4]),
+            None
+        );
+        // The match starts before the beginning of the output
+        assert_eq!(decompress(&stream(&[b"\x12a\x26\x04\x00"]), &mut out), None);
+        // Data after the end of the stream
+        assert_eq!(
+            decompress(&stream(&[b"\x16hello"]).repeat(2), &mut out),
+            None
+        );
+        // The end of stream marker has a length of 3
+        assert_eq!(decompress(b"\x16hello\x12\x00\x00", &mut out), None);
+    }
+}
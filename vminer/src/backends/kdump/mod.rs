@@ -0,0 +1,282 @@
+//! Compressed kdump files, in the "diskdump" format produced by
+//! `makedumpfile` or by QEMU's `dump-guest-memory -z/-l/-s`.
+//!
+//! Pages are compressed one by one and only dumped pages are present in the
+//! file, as described by a bitmap. Pages are decompressed on demand, and the
+//! last used ones are kept in a small cache.
+
+use std::{fs, path::Path, sync::Mutex};
+use vmc::{
+    MemoryAccessError, MemoryAccessResult, PhysicalAddress, VmError, VmResult, mem::MemoryMap,
+};
+
+use super::{
+    bitmap::Bitmap,
+    elf_core,
+    page_cache::PageCache,
+    read_at,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+
+mod lzo;
+
+const SIGNATURE: &[u8; 8] = b"KDUMP   ";
+const FLAT_SIGNATURE: &[u8; 12] = b"makedumpfile";
+
+const DUMP_DH_COMPRESSED_ZLIB: u32 = 0x1;
+const DUMP_DH_COMPRESSED_LZO: u32 = 0x2;
+const DUMP_DH_COMPRESSED_SNAPPY: u32 = 0x4;
+const DUMP_DH_COMPRESSED_ZSTD: u32 = 0x20;
+
+/// Number of decompressed pages kept in memory
+const CACHE_SIZE: usize = 64;
+
+/// Offsets of the fields we need in `struct disk_dump_header`
+mod header {
+    pub const SIZE: usize = 464;
+    pub const HEADER_VERSION: usize = 8;
+    pub const UTSNAME_MACHINE: usize = 12 + 4 * 65;
+    pub const BLOCK_SIZE: usize = 428;
+    pub const SUB_HDR_SIZE: usize = 432;
+    pub const BITMAP_BLOCKS: usize = 436;
+    pub const MAX_MAPNR: usize = 440;
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// `struct kdump_sub_header`
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+struct KdumpSubHeader {
+    phys_base: u64,
+    dump_level: i32,
+    split: i32,
+    start_pfn: u64,
+    end_pfn: u64,
+    offset_vmcoreinfo: u64,
+    size_vmcoreinfo: u64,
+    offset_note: u64,
+    size_note: u64,
+    offset_eraseinfo: u64,
+    size_eraseinfo: u64,
+    start_pfn_64: u64,
+    end_pfn_64: u64,
+    max_mapnr_64: u64,
+}
+
+/// `struct page_desc`
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+struct PageDesc {
+    offset: u64,
+    size: u32,
+    flags: u32,
+    page_flags: u64,
+}
+
+#[derive(Debug)]
+pub struct Kdump {
+    file: vmc::mem::File,
+    page_size: u64,
+    page_descs_offset: u64,
+    dumped: Bitmap,
+    mappings: Vec<MemoryMap>,
+    cache: Mutex<PageCache>,
+    vcpus: Vcpus,
+}
+
+impl Kdump {
+    pub fn read<P: AsRef<Path>>(path: P) -> VmResult<Self> {
+        let file = fs::File::open(path)?;
+        Self::from_file(file)
+    }
+
+    pub fn from_file(file: fs::File) -> VmResult<Self> {
+        let header = read_at(&file, 0, header::SIZE)?;
+        if header.starts_with(FLAT_SIGNATURE) {
+            return Err(VmError::new(
+                "flattened kdump files are not supported, use `makedumpfile -R` first",
+            ));
+        }
+        if !header.starts_with(SIGNATURE) {
+            return Err(VmError::new("invalid file signature"));
+        }
+
+        let header_version = read_u32(&header, header::HEADER_VERSION);
+        let block_size = read_u32(&header, header::BLOCK_SIZE) as u64;
+        if !block_size.is_power_of_two() {
+            return Err(VmError::new("invalid block size"));
+        }
+
+        let sub_header = read_at(&file, block_size, size_of::<KdumpSubHeader>())?;
+        let sub_header: KdumpSubHeader = bytemuck::pod_read_unaligned(&sub_header);
+
+        let max_mapnr = if header_version >= 6 {
+            sub_header.max_mapnr_64
+        } else {
+            read_u32(&header, header::MAX_MAPNR) as u64
+        };
+
+        // The bitmap is split in two: the first half has valid pages, the
+        // second one has pages present in the dump.
+        let bitmap_offset = (1 + read_u32(&header, header::SUB_HDR_SIZE) as u64) * block_size;
+        let bitmap_size = read_u32(&header, header::BITMAP_BLOCKS) as u64 * block_size;
+        let bitmaps = read_at(&file, bitmap_offset, bitmap_size as usize)?;
+        let (valid, dumped) = bitmaps.split_at(bitmaps.len() / 2);
+        let map_bytes = max_mapnr.div_ceil(8) as usize;
+        let valid = Bitmap::new(&valid[..map_bytes.min(valid.len())]);
+        let dumped = Bitmap::new(&dumped[..map_bytes.min(dumped.len())]);
+
+        let mappings = valid
+            .ranges()
+            .map(|(start, end)| MemoryMap {
+                start: PhysicalAddress(start * block_size),
+                end: PhysicalAddress(end * block_size),
+            })
+            .collect();
+
+        let mut notes = elf_core::Notes::default();
+        if header_version >= 4 && sub_header.size_note != 0 {
+            let data = read_at(&file, sub_header.offset_note, sub_header.size_note as usize)?;
+            notes.parse(&data, 4)?;
+        }
+        if header_version >= 3 && notes.vmcoreinfo.is_none() {
+            let data = read_at(
+                &file,
+                sub_header.offset_vmcoreinfo,
+                sub_header.size_vmcoreinfo as usize,
+            )?;
+            notes.vmcoreinfo = Some(data);
+        }
+
+        let machine = &header[header::UTSNAME_MACHINE..header::UTSNAME_MACHINE + 65];
+        let machine = &machine[..memchr::memchr(0, machine).unwrap_or(machine.len())];
+        let vcpus = match machine {
+            b"x86_64" => elf_core::vcpus_from_notes(object::elf::EM_X86_64, &notes)?,
+            b"aarch64" => elf_core::vcpus_from_notes(object::elf::EM_AARCH64, &notes)?,
+            _ => return Err(VmError::new("unsupported architecture")),
+        };
+
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            file: vmc::mem::File::new(file, 0, size),
+            page_size: block_size,
+            page_descs_offset: bitmap_offset + bitmap_size,
+            dumped,
+            mappings,
+            cache: Mutex::new(PageCache::new(CACHE_SIZE)),
+            vcpus,
+        })
+    }
+
+    fn read_file(&self, offset: u64, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        vmc::Memory::read_physical(&self.file, PhysicalAddress(offset), buf)
+    }
+
+    fn load_page(&self, pfn: u64) -> MemoryAccessResult<Box<[u8]>> {
+        // Excluded pages are not in the file
+        let index = self
+            .dumped
+            .rank(pfn)
+            .ok_or(MemoryAccessError::OutOfBounds)?;
+
+        let mut desc: PageDesc = bytemuck::Zeroable::zeroed();
+        let offset = self.page_descs_offset + index * size_of::<PageDesc>() as u64;
+        self.read_file(offset, bytemuck::bytes_of_mut(&mut desc))?;
+
+        let mut data = vec![0; desc.size as usize];
+        self.read_file(desc.offset, &mut data)?;
+
+        let mut page = vec![0; self.page_size as usize].into_boxed_slice();
+
+        let size = if desc.flags & DUMP_DH_COMPRESSED_ZLIB != 0 {
+            miniz_oxide::inflate::decompress_slice_iter_to_slice(
+                &mut page,
+                core::iter::once(&*data),
+                true,
+                false,
+            )
+            .ok()
+        } else if desc.flags & DUMP_DH_COMPRESSED_LZO != 0 {
+            lzo::decompress(&data, &mut page)
+        } else if desc.flags & DUMP_DH_COMPRESSED_SNAPPY != 0 {
+            snap::raw::Decoder::new().decompress(&data, &mut page).ok()
+        } else if desc.flags & DUMP_DH_COMPRESSED_ZSTD != 0 {
+            ruzstd::decoding::FrameDecoder::new()
+                .decode_all(&data, &mut page)
+                .ok()
+        } else {
+            let len = data.len().min(page.len());
+            page[..len].copy_from_slice(&data[..len]);
+            Some(len)
+        };
+
+        match size {
+            Some(size) if size == page.len() => Ok(page),
+            _ => {
+                log::warn!(
+                    "Failed to decompress page 0x{pfn:x} (flags: {:#x})",
+                    desc.flags
+                );
+                Err(MemoryAccessError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "failed to decompress page",
+                )))
+            }
+        }
+    }
+
+    fn with_page<T>(&self, pfn: u64, f: impl FnOnce(&[u8]) -> T) -> MemoryAccessResult<T> {
+        let mut cache = self.cache.lock().unwrap();
+
+        if let Some(page) = cache.get(pfn) {
+            return Ok(f(page));
+        }
+
+        let page = self.load_page(pfn)?;
+        let result = f(&page);
+        cache.insert(pfn, page);
+        Ok(result)
+    }
+}
+
+impl vmc::Memory for Kdump {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        &self.mappings
+    }
+
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        let start = addr.0 / self.page_size;
+        let end = (addr.0 + size as u64).div_ceil(self.page_size);
+        (start..end).all(|pfn| self.dumped.is_set(pfn))
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        let mut addr = addr.0;
+        let mut buf = buf;
+
+        while !buf.is_empty() {
+            let pfn = addr / self.page_size;
+            let offset = (addr % self.page_size) as usize;
+            let len = core::cmp::min(buf.len(), self.page_size as usize - offset);
+            let (start, rest) = buf.split_at_mut(len);
+
+            self.with_page(pfn, |page| {
+                start.copy_from_slice(&page[offset..offset + len])
+            })?;
+
+            buf = rest;
+            addr += len as u64;
+        }
+
+        Ok(())
+    }
+}
+
+delegate_vcpus!(Kdump);
+
+impl vmc::Backend for Kdump {}
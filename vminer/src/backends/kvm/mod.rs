@@ -2,9 +2,16 @@
 
 use std::{
     fs,
-    io::{self, BufRead, Read},
-    os::unix::{net::UnixListener, prelude::*},
-    thread,
+    io::{self, BufRead, Read, Write},
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            net::{SocketAddr, UnixListener, UnixStream},
+            prelude::*,
+        },
+    },
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
 };
 use vmc::{ResultExt, VmError, VmResult};
 
@@ -17,9 +24,26 @@ mod ptrace;
 mod arch;
 
 const LIB_PATH: &[u8] = b"/usr/lib/libvminer_kvm_patch.so\0";
-const FUN_NAME: &[u8] = b"vminer_payload\0";
+const FUN_NAME: &[u8] = b"vminer_agent\0";
 const TOTAL_LEN: usize = LIB_PATH.len() + FUN_NAME.len();
 
+/// Maximum time to wait for the agent to connect or to answer
+const AGENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asks the agent for the registers of all vCPUs.
+///
+/// This must be kept in sync with `vminer_kvm_patch`.
+const REQUEST_REGISTERS: u8 = 1;
+
+/// Asks the agent for the memory slots of the VM.
+const REQUEST_MEMORY_SLOTS: u8 = 2;
+
+/// Asks the agent for the named registers of all vCPUs.
+const REQUEST_NAMED_REGISTERS: u8 = 3;
+
+/// Slot IDs are 16 bits within an address space
+const MAX_MEMORY_SLOTS: usize = 1 << 16;
+
 /// Finds the loading address of a library's text in a process' address space
 fn find_lib(pid: libc::pid_t, name: &str) -> VmResult<u64> {
     let path = format!("/proc/{pid}/maps");
@@ -102,9 +126,9 @@ impl<F: FnMut()> Drop for OnDrop<F> {
     }
 }
 
-/// Attach to a process, and make it execute our payload
+/// Attach to a process, and make it start our agent
 #[allow(clippy::fn_to_numeric_cast)]
-fn attach(pid: libc::pid_t, fds: &[i32]) -> VmResult<()> {
+fn attach(pid: libc::pid_t, fds: &[i32], socket_name: &[u8]) -> VmResult<()> {
     // Find remote function addresses so we can call them.
     // Use our own functions to get the offset within the lib, and read /proc
     // to bypass the ASLR.
@@ -153,7 +177,9 @@ fn attach(pid: libc::pid_t, fds: &[i32]) -> VmResult<()> {
     }
     log::trace!("dlopen handle at 0x{handle:x}");
 
-    let _do_dlclose = OnDrop(|| {
+    // The library is only unloaded on failure, as the agent keeps running its
+    // code afterwards
+    let do_dlclose = OnDrop(|| {
         // dlclose(handle);
         match tracee.funcall1(their_dlclose, handle) {
             Ok(0) => log::trace!("dlclose"),
@@ -173,15 +199,30 @@ fn attach(pid: libc::pid_t, fds: &[i32]) -> VmResult<()> {
     }
     log::trace!("payload at 0x{payload:x}");
 
-    // payload(fds)
-    tracee.poke_data(mmap_addr, bytemuck::cast_slice(fds))?;
-
-    let error = tracee.funcall2(payload, mmap_addr, fds.len() as u64)? as i32;
+    // payload(fds, n, socket_name, socket_name_len, vminer_pid)
+    let fds: &[u8] = bytemuck::cast_slice(fds);
+    if fds.len() + socket_name.len() > 0x1000 {
+        return Err(VmError::new("too many vCPUs"));
+    }
+    let socket_name_addr = mmap_addr + fds.len() as u64;
+    tracee.poke_data(mmap_addr, fds)?;
+    tracee.poke_data(socket_name_addr, socket_name)?;
+
+    let error = tracee.funcall6(
+        payload,
+        mmap_addr,
+        (fds.len() / 4) as u64,
+        socket_name_addr,
+        socket_name.len() as u64,
+        std::process::id() as u64,
+        0,
+    )? as i32;
     if error != 0 {
         let err = io::Error::from_raw_os_error(error);
         return Err(VmError::with_context("payload failed", err));
     }
 
+    std::mem::forget(do_dlclose);
     Ok(())
 }
 
@@ -215,54 +256,321 @@ fn get_vcpus_fds(pid: libc::pid_t) -> VmResult<Vec<i32>> {
     Ok(fds)
 }
 
-fn start_listener(
-    socket_path: &str,
-    fds: &[i32],
-) -> VmResult<thread::JoinHandle<VmResult<Vec<arch::Vcpu>>>> {
-    let fds_len = fds.len();
+/// Opens the memory of the KVM process, with write access if possible.
+fn open_memory(pid: libc::pid_t, start: u64, end: u64) -> VmResult<vmc::mem::File> {
+    let path = format!("/proc/{pid}/mem");
+    match vmc::mem::File::open_writable(&path, start, end) {
+        Ok(mem) => Ok(mem),
+        Err(err) => {
+            log::debug!("Failed to open VM memory for writing: {err}");
+            Ok(vmc::mem::File::open(&path, start, end)?)
+        }
+    }
+}
+
+/// Returns a new name for the socket of a session with the agent.
+///
+/// The socket is in the abstract namespace, so it does not clash with other
+/// files and is removed automatically.
+///
+/// Abstract sockets belong to a network namespace, so a VMM that runs in its
+/// own (eg with Firecracker's jailer or in a container) cannot connect to it.
+fn socket_name(pid: libc::pid_t) -> String {
+    static SESSIONS: AtomicU32 = AtomicU32::new(0);
+
+    let session = SESSIONS.fetch_add(1, Ordering::Relaxed);
+    format!("vminer/{}/{pid}/{session}", std::process::id())
+}
+
+/// Returns the PID of the process at the other end of the socket.
+fn peer_pid(socket: &UnixStream) -> io::Result<libc::pid_t> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    unsafe {
+        let res = libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        );
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(cred.pid)
+}
+
+/// Waits for the agent running in process `pid` to connect.
+///
+/// Anyone can connect to the socket, so connections from other processes are
+/// rejected.
+fn accept_agent(listener: &UnixListener, pid: libc::pid_t) -> VmResult<UnixStream> {
+    let deadline = Instant::now() + AGENT_TIMEOUT;
+    listener.set_nonblocking(true)?;
+
+    loop {
+        // Rejected connections must not delay the timeout either
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(VmError::new("timed out waiting for the agent"));
+        }
+
+        match listener.accept() {
+            Ok((socket, _)) => {
+                match peer_pid(&socket) {
+                    Ok(peer) if peer == pid => (),
+                    Ok(peer) => {
+                        log::warn!("Rejected connection from process {peer}");
+                        continue;
+                    }
+                    Err(err) => {
+                        log::warn!("Failed to check peer credentials: {err}");
+                        continue;
+                    }
+                }
+
+                socket.set_nonblocking(false)?;
+                socket.set_read_timeout(Some(AGENT_TIMEOUT))?;
+                socket.set_write_timeout(Some(AGENT_TIMEOUT))?;
+                return Ok(socket);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let mut pollfd = libc::pollfd {
+                    fd: listener.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                let timeout = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+                if unsafe { libc::poll(&mut pollfd, 1, timeout) } == -1 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err.into());
+                    }
+                }
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Injects the agent in the KVM process and connects to it.
+///
+/// Returns the connection and the ID of the agent's thread.
+fn start_agent(pid: libc::pid_t, fds: &[i32]) -> VmResult<(Agent, libc::pid_t)> {
+    let name = socket_name(pid);
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    let listener = UnixListener::bind_addr(&addr).context("failed to bind listener socket")?;
+
+    // The agent connects before the payload returns, so the connection is
+    // already waiting here
+    attach(pid, fds, name.as_bytes())?;
+    log::info!("Payload succeded");
+
+    let mut agent = accept_agent(&listener, pid).context("failed to connect to agent")?;
+
+    let mut tid = [0; 4];
+    agent.read_exact(&mut tid)?;
+    let agent = Agent {
+        stream: agent,
+        broken: false,
+    };
+    Ok((agent, libc::pid_t::from_ne_bytes(tid)))
+}
+
+/// A connection to the agent.
+///
+/// If an error happens while a response is read, the rest of it may still
+/// arrive later and would be mistaken for the next response. The connection
+/// is then considered broken and cannot be used anymore.
+struct Agent {
+    stream: UnixStream,
+    broken: bool,
+}
+
+impl Agent {
+    /// Sends a request to the agent, and reads the status of its response.
+    ///
+    /// If this succeeds, the data of the response must then be read with
+    /// [`Agent::read_response`].
+    fn send_request(&mut self, request: u8) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection to the agent was lost, reconnect to the VM",
+            ));
+        }
 
-    let _ = fs::remove_file(socket_path);
-    let listener = UnixListener::bind(socket_path).context("failed to bind listener socket")?;
-    // FIXME: is 777 mode really required ?
-    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o777))?;
+        // The connection is only usable again once the response is read
+        self.broken = true;
+        self.stream.write_all(&[request])?;
+
+        let mut status = [0; 4];
+        self.stream.read_exact(&mut status)?;
+        match i32::from_ne_bytes(status) {
+            0 => Ok(()),
+            errno => {
+                // Errors come without data
+                self.broken = false;
+                Err(io::Error::from_raw_os_error(errno))
+            }
+        }
+    }
 
-    Ok(thread::spawn(move || {
-        let mut registers = bytemuck::Zeroable::zeroed();
-        let mut special_registers = bytemuck::Zeroable::zeroed();
-        let mut other_registers = bytemuck::Zeroable::zeroed();
+    /// Reads the data of a response with `read`.
+    fn read_response<T>(
+        &mut self,
+        read: impl FnOnce(&mut UnixStream) -> VmResult<T>,
+    ) -> VmResult<T> {
+        let response = read(&mut self.stream)?;
+        self.broken = false;
+        Ok(response)
+    }
+}
 
-        let (mut socket, _) = listener.accept()?;
+/// Asks the agent for the current registers of all vCPUs.
+fn get_regs(agent: &mut Agent, vcpus_count: usize) -> VmResult<Vec<arch::Vcpu>> {
+    agent
+        .send_request(REQUEST_REGISTERS)
+        .context("agent failed to get registers")?;
 
-        (0..fds_len)
+    agent.read_response(|stream| {
+        (0..vcpus_count)
             .map(|_| {
-                socket.read_exact(bytemuck::bytes_of_mut(&mut registers))?;
-                socket.read_exact(bytemuck::bytes_of_mut(&mut special_registers))?;
-                socket.read_exact(bytemuck::bytes_of_mut(&mut other_registers))?;
-                Ok(arch::Vcpu {
-                    registers,
-                    special_registers,
-                    other_registers,
-                })
+                let mut vcpu: arch::Vcpu = bytemuck::Zeroable::zeroed();
+                stream.read_exact(bytemuck::bytes_of_mut(&mut vcpu.registers))?;
+                stream.read_exact(bytemuck::bytes_of_mut(&mut vcpu.special_registers))?;
+                stream.read_exact(bytemuck::bytes_of_mut(&mut vcpu.other_registers))?;
+                Ok(vcpu)
             })
             .collect()
-    }))
+    })
 }
 
-fn get_regs(pid: libc::pid_t) -> VmResult<Vec<arch::Vcpu>> {
-    let fds = get_vcpus_fds(pid)?;
-    let socket_path = "/tmp/get_fds";
-    let handle = start_listener(socket_path, &fds)?;
+/// Registers of a vCPU that are not part of the fixed structures, by name.
+type NamedRegisters = Vec<(String, Vec<u8>)>;
 
-    attach(pid, &fds)?;
-    log::info!("Payload succeded");
+/// Asks the agent for the named registers of all vCPUs.
+fn get_named_regs(agent: &mut Agent, vcpus_count: usize) -> VmResult<Vec<NamedRegisters>> {
+    fn read_u32(stream: &mut UnixStream) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf)?;
+        Ok(u32::from_ne_bytes(buf))
+    }
+
+    match agent.send_request(REQUEST_NAMED_REGISTERS) {
+        Ok(()) => (),
+        // The agent may come from an older version of vminer
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            log::debug!("Agent does not support named registers");
+            return Ok(vec![Vec::new(); vcpus_count]);
+        }
+        Err(err) => return Err(VmError::with_context("agent failed to get registers", err)),
+    }
 
-    let regs = handle.join().unwrap()?;
-    Ok(regs)
+    agent.read_response(|stream| {
+        (0..vcpus_count)
+            .map(|_| {
+                let count = read_u32(stream)?;
+                (0..count)
+                    .map(|_| {
+                        let mut len = [0];
+                        stream.read_exact(&mut len)?;
+                        let mut name = vec![0; len[0] as usize];
+                        stream.read_exact(&mut name)?;
+                        let name = String::from_utf8(name)
+                            .map_err(|_| VmError::new("invalid register name"))?;
+
+                        let mut value = vec![0; read_u32(stream)? as usize];
+                        stream.read_exact(&mut value)?;
+                        Ok((name, value))
+                    })
+                    .collect()
+            })
+            .collect()
+    })
+}
+
+/// A memory slot of KVM, as recorded by the agent.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct MemorySlot {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+}
+
+/// Asks the agent for the memory slots of the VM, sorted by guest address.
+///
+/// This is empty if the agent was not loaded when the VM started.
+fn get_memory_slots(agent: &mut Agent) -> VmResult<Vec<MemorySlot>> {
+    agent
+        .send_request(REQUEST_MEMORY_SLOTS)
+        .context("agent failed to get memory slots")?;
+
+    agent.read_response(|stream| {
+        let mut count = [0; 8];
+        stream.read_exact(&mut count)?;
+        let count = u64::from_ne_bytes(count) as usize;
+        if count > MAX_MEMORY_SLOTS {
+            return Err(VmError::new(format!("invalid memory slot count: {count}")));
+        }
+
+        let mut slots = vec![bytemuck::Zeroable::zeroed(); count];
+        stream.read_exact(bytemuck::cast_slice_mut(&mut slots))?;
+        Ok(slots)
+    })
+}
+
+/// Maps guest physical memory to the memory of the KVM process using memory
+/// slots.
+fn memory_from_slots(
+    pid: libc::pid_t,
+    slots: &[MemorySlot],
+) -> VmResult<vmc::mem::MemRemap<vmc::mem::File>> {
+    let end = slots
+        .iter()
+        .map(|slot| slot.userspace_addr + slot.memory_size)
+        .max()
+        .unwrap_or(0);
+    let mem = open_memory(pid, 0, end)?;
+
+    let (mappings, remap_at) = slots
+        .iter()
+        .map(|slot| {
+            let start = vmc::PhysicalAddress(slot.guest_phys_addr);
+            log::debug!(
+                "Found KVM memory slot at 0x{start:x} of size 0x{:x}",
+                slot.memory_size
+            );
+            let mapping = vmc::mem::MemoryMap {
+                start,
+                end: start + slot.memory_size,
+            };
+            (mapping, vmc::PhysicalAddress(slot.userspace_addr))
+        })
+        .unzip();
+
+    Ok(vmc::mem::MemRemap::new(mem, mappings, remap_at))
 }
 
 pub struct Kvm {
+    pid: libc::pid_t,
     mem: vmc::mem::MemRemap<vmc::mem::File>,
     vcpus: Vec<arch::Vcpu>,
+    named_registers: Vec<NamedRegisters>,
+    agent: Agent,
+    agent_tid: libc::pid_t,
+    stopped: Vec<ptrace::StoppedThread>,
+    /// The thread that stopped the others, which is the only one that can
+    /// restart them
+    tracer_tid: libc::pid_t,
 }
 
 impl Kvm {
@@ -309,25 +617,46 @@ impl Kvm {
 
         log::debug!("Found KVM memory of size 0x{map_size:x} at address 0x{map_guess:x}",);
 
-        Ok(vmc::mem::File::open(
-            format!("/proc/{pid}/mem"),
-            map_guess,
-            map_guess + map_size,
-        )?)
+        open_memory(pid, map_guess, map_guess + map_size)
     }
 
+    /// Connects to a VM running with KVM.
+    ///
+    /// KVM cannot be asked for its memory slots, so the agent records them
+    /// when the VMM sets them up. This only works if the agent library was
+    /// loaded with `LD_PRELOAD` when the VM started (see the README), and
+    /// then the layout of guest physical memory is taken from the slots.
+    ///
+    /// When the agent is only injected in a running VM, slots are unknown.
+    /// The largest mapping of the process is then assumed to be the whole
+    /// memory of the guest, starting at address 0, and a warning is logged.
     pub fn connect(pid: libc::pid_t) -> VmResult<Kvm> {
-        Self::create(pid, |size| {
-            (
-                vec![vmc::mem::MemoryMap {
-                    start: vmc::PhysicalAddress(0),
-                    end: vmc::PhysicalAddress(size),
-                }],
-                vec![vmc::PhysicalAddress(0)],
-            )
+        Self::create(pid, |agent| {
+            let slots = get_memory_slots(agent)?;
+            if !slots.is_empty() {
+                return memory_from_slots(pid, &slots);
+            }
+
+            log::warn!("KVM memory slots are unknown, guessing memory layout");
+            Self::remap_memory(pid, |size| {
+                (
+                    vec![vmc::mem::MemoryMap {
+                        start: vmc::PhysicalAddress(0),
+                        end: vmc::PhysicalAddress(size),
+                    }],
+                    vec![vmc::PhysicalAddress(0)],
+                )
+            })
         })
     }
 
+    /// Connects to a VM running with KVM, like [`Kvm::connect`], but assumes
+    /// the memory layout of QEMU if KVM memory slots are unknown.
+    ///
+    /// As with [`Kvm::connect`], slots are only known if the agent library
+    /// was loaded with `LD_PRELOAD` when the VM started. Otherwise, the
+    /// layout is guessed from the size of the largest mapping of the process,
+    /// which may be wrong for VMs with several memory regions.
     pub fn with_default_qemu_mappings(pid: libc::pid_t) -> VmResult<Kvm> {
         let default_qemu_mappings = |size| {
             if cfg!(target_arch = "x86_64") {
@@ -367,7 +696,15 @@ impl Kvm {
             }
         };
 
-        Self::create(pid, default_qemu_mappings)
+        Self::create(pid, |agent| {
+            let slots = get_memory_slots(agent)?;
+            if !slots.is_empty() {
+                return memory_from_slots(pid, &slots);
+            }
+
+            log::warn!("KVM memory slots are unknown, assuming QEMU memory layout");
+            Self::remap_memory(pid, default_qemu_mappings)
+        })
     }
 
     pub fn with_memory_mappings(
@@ -375,19 +712,233 @@ impl Kvm {
         mappings: Vec<vmc::mem::MemoryMap>,
         remap_at: Vec<vmc::PhysicalAddress>,
     ) -> VmResult<Kvm> {
-        Self::create(pid, |_| (mappings, remap_at))
+        Self::create(pid, |_| Self::remap_memory(pid, |_| (mappings, remap_at)))
     }
 
-    fn create<F>(pid: libc::pid_t, make_mappings: F) -> VmResult<Kvm>
+    fn remap_memory<F>(
+        pid: libc::pid_t,
+        make_mappings: F,
+    ) -> VmResult<vmc::mem::MemRemap<vmc::mem::File>>
     where
         F: FnOnce(u64) -> (Vec<vmc::mem::MemoryMap>, Vec<vmc::PhysicalAddress>),
     {
         let mem = Self::find_memory(pid)?;
-        let vcpus = get_regs(pid)?;
         let (mappings, remap_at) = make_mappings(mem.size());
-        let mem = vmc::mem::MemRemap::new(mem, mappings, remap_at);
+        Ok(vmc::mem::MemRemap::new(mem, mappings, remap_at))
+    }
 
-        Ok(Kvm { mem, vcpus })
+    fn create<F>(pid: libc::pid_t, find_memory: F) -> VmResult<Kvm>
+    where
+        F: FnOnce(&mut Agent) -> VmResult<vmc::mem::MemRemap<vmc::mem::File>>,
+    {
+        let fds = get_vcpus_fds(pid)?;
+        let (mut agent, agent_tid) = start_agent(pid, &fds)?;
+        let vcpus = get_regs(&mut agent, fds.len())?;
+        let named_registers = get_named_regs(&mut agent, fds.len())?;
+        let mem = find_memory(&mut agent)?;
+
+        Ok(Kvm {
+            pid,
+            mem,
+            vcpus,
+            named_registers,
+            agent,
+            agent_tid,
+            stopped: Vec::new(),
+            tracer_tid: 0,
+        })
+    }
+
+    /// Takes a new snapshot of the registers of all vCPUs.
+    ///
+    /// Registers are otherwise read once when connecting to the VM, so they
+    /// quickly get stale if it is running. This asks the agent that was
+    /// injected in KVM, so this is much cheaper than connecting again.
+    ///
+    /// If the VM is running, it is paused while registers are read so that
+    /// the registers of all vCPUs match the same point in time. On error, the
+    /// previous snapshot is kept.
+    ///
+    /// If the connection to the agent breaks, this fails until a new
+    /// connection to the VM is made.
+    pub fn refresh(&mut self) -> VmResult<()> {
+        if self.is_paused() {
+            return self.read_registers();
+        }
+
+        let result = self.pause();
+        let resumed = self.resume();
+        result.and(resumed)
+    }
+
+    fn read_registers(&mut self) -> VmResult<()> {
+        let vcpus = get_regs(&mut self.agent, self.vcpus.len())?;
+        let named_registers = get_named_regs(&mut self.agent, self.vcpus.len())?;
+        self.vcpus = vcpus;
+        self.named_registers = named_registers;
+        Ok(())
+    }
+
+    /// Returns the raw value of a register that is not part of the fixed
+    /// register structures, such as debug registers, model specific registers
+    /// or vector registers.
+    ///
+    /// Values that fit in 64 bits can also be read with
+    /// [`HasVcpus::register_by_name`](vmc::HasVcpus::register_by_name).
+    pub fn register_bytes(&self, vcpu: vmc::VcpuId, name: &str) -> vmc::VcpuResult<&[u8]> {
+        let registers = self
+            .named_registers
+            .get(vcpu.0)
+            .ok_or(vmc::VcpuError::InvalidId)?;
+
+        registers
+            .iter()
+            .find(|(reg_name, _)| reg_name == name)
+            .map(|(_, value)| &**value)
+            .ok_or(vmc::VcpuError::UnknownRegister)
+    }
+
+    /// Returns the names of the registers available with
+    /// [`Kvm::register_bytes`].
+    pub fn register_names(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<Vec<&str>> {
+        let registers = self
+            .named_registers
+            .get(vcpu.0)
+            .ok_or(vmc::VcpuError::InvalidId)?;
+        Ok(registers.iter().map(|(name, _)| &**name).collect())
+    }
+
+    /// Pauses the VM, and takes a new snapshot of its registers.
+    ///
+    /// All threads of the KVM process are stopped with ptrace, except the
+    /// agent, so memory and registers stay consistent until the VM is
+    /// resumed. Because of ptrace, [`Kvm::resume`] must be called from the
+    /// same thread and fails otherwise. [`Kvm::pause_guard`] makes this
+    /// easier.
+    ///
+    /// This does nothing if the VM is already paused.
+    pub fn pause(&mut self) -> VmResult<()> {
+        if self.is_paused() {
+            return Ok(());
+        }
+
+        self.tracer_tid = unsafe { libc::gettid() };
+        if let Err(err) = self.stop_threads() {
+            self.stopped.clear();
+            return Err(err);
+        }
+        log::debug!("Stopped {} KVM threads", self.stopped.len());
+
+        self.read_registers()
+    }
+
+    fn stop_threads(&mut self) -> VmResult<()> {
+        // Threads may be created while we stop the others
+        loop {
+            let mut found_new = false;
+
+            for entry in fs::read_dir(format!("/proc/{}/task", self.pid))? {
+                let Some(tid) = entry?.file_name().to_str().and_then(|tid| tid.parse().ok()) else {
+                    continue;
+                };
+
+                if tid == self.agent_tid || self.stopped.iter().any(|t| t.tid() == tid) {
+                    continue;
+                }
+
+                match ptrace::StoppedThread::stop(tid) {
+                    Ok(thread) => {
+                        self.stopped.push(thread);
+                        found_new = true;
+                    }
+                    // The thread exited in the meantime
+                    Err(err) if err.raw_os_error() == Some(libc::ESRCH) => (),
+                    Err(err) => {
+                        return Err(VmError::with_context(
+                            format!("failed to stop thread {tid}"),
+                            err,
+                        ));
+                    }
+                }
+            }
+
+            if !found_new {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Resumes a VM paused with [`Kvm::pause`].
+    ///
+    /// This must be called from the thread that paused the VM, otherwise the
+    /// VM stays paused and an error is returned.
+    pub fn resume(&mut self) -> VmResult<()> {
+        let tid = unsafe { libc::gettid() };
+        if self.is_paused() && tid != self.tracer_tid {
+            return Err(VmError::new(format!(
+                "the VM was paused by thread {}, it cannot be resumed from thread {tid}",
+                self.tracer_tid
+            )));
+        }
+
+        let mut result = Ok(());
+
+        for thread in self.stopped.drain(..) {
+            let tid = thread.tid();
+            if let Err(err) = thread.restart() {
+                log::warn!("Failed to restart thread {tid}: {err}");
+                result = Err(VmError::with_context("failed to resume VM", err));
+            }
+        }
+
+        result
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        !self.stopped.is_empty()
+    }
+
+    /// Pauses the VM until the returned guard is dropped.
+    pub fn pause_guard(&mut self) -> VmResult<PauseGuard<'_>> {
+        self.pause()?;
+        Ok(PauseGuard {
+            kvm: self,
+            _not_send: core::marker::PhantomData,
+        })
+    }
+}
+
+/// Keeps a VM paused, and resumes it when dropped.
+///
+/// The guard cannot be sent to another thread, as the VM has to be resumed
+/// from the thread that paused it. See [`Kvm::pause_guard`].
+pub struct PauseGuard<'a> {
+    kvm: &'a mut Kvm,
+    _not_send: core::marker::PhantomData<*const ()>,
+}
+
+impl std::ops::Deref for PauseGuard<'_> {
+    type Target = Kvm;
+
+    #[inline]
+    fn deref(&self) -> &Kvm {
+        self.kvm
+    }
+}
+
+impl std::ops::DerefMut for PauseGuard<'_> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Kvm {
+        self.kvm
+    }
+}
+
+impl Drop for PauseGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.kvm.resume() {
+            log::error!("{err}");
+        }
     }
 }
 
@@ -407,6 +958,17 @@ impl vmc::Memory for Kvm {
     }
 }
 
+impl vmc::MemoryMut for Kvm {
+    #[inline]
+    fn write_physical(
+        &mut self,
+        addr: vmc::PhysicalAddress,
+        buf: &[u8],
+    ) -> vmc::MemoryAccessResult<()> {
+        self.mem.write_physical(addr, buf)
+    }
+}
+
 impl vmc::HasVcpus for Kvm {
     type Arch = arch::Arch;
 
@@ -450,6 +1012,32 @@ impl vmc::HasVcpus for Kvm {
             .ok_or(vmc::VcpuError::InvalidId)?
             .other_registers)
     }
+
+    fn register_by_name(&self, vcpu: vmc::VcpuId, name: &str) -> vmc::VcpuResult<u64> {
+        use vmc::Architecture;
+
+        match self.arch().register_by_name(self, vcpu, name) {
+            Err(vmc::VcpuError::UnknownRegister) => (),
+            res => return res,
+        }
+
+        let value = self.register_bytes(vcpu, name)?;
+        let mut buf = [0; 8];
+        buf.get_mut(..value.len())
+            .ok_or(vmc::VcpuError::Unsupported)?
+            .copy_from_slice(value);
+        Ok(u64::from_ne_bytes(buf))
+    }
 }
 
-impl vmc::Backend for Kvm {}
+impl vmc::Backend for Kvm {
+    #[inline]
+    fn write_virtual_memory(
+        &mut self,
+        mmu_addr: vmc::PhysicalAddress,
+        addr: vmc::VirtualAddress,
+        buf: &[u8],
+    ) -> vmc::TranslationResult<()> {
+        vmc::backend::default_write_virtual_memory(self, mmu_addr, addr, buf)
+    }
+}
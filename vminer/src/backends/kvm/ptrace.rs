@@ -114,6 +114,80 @@ impl Drop for RawTracee {
     }
 }
 
+/// A thread stopped with `PTRACE_SEIZE` and `PTRACE_INTERRUPT`, which is
+/// restarted when dropped.
+///
+/// Unlike `PTRACE_ATTACH`, this does not stop the other threads of the
+/// process.
+pub struct StoppedThread {
+    tid: libc::pid_t,
+    /// A signal received while stopping, delivered again on restart
+    signal: libc::c_int,
+}
+
+impl StoppedThread {
+    pub fn stop(tid: libc::pid_t) -> io::Result<Self> {
+        unsafe {
+            check!(libc::ptrace(
+                libc::PTRACE_SEIZE,
+                tid,
+                ptr::null_mut::<libc::c_void>(),
+                0usize,
+            ))?;
+        }
+        let mut this = Self { tid, signal: 0 };
+
+        let mut status = 0;
+        unsafe {
+            check!(libc::ptrace(
+                libc::PTRACE_INTERRUPT,
+                tid,
+                ptr::null_mut::<libc::c_void>(),
+                0usize,
+            ))?;
+            check!(libc::waitpid(tid, &mut status, libc::__WALL))?;
+        }
+
+        if !libc::WIFSTOPPED(status) {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        // The thread may have received a signal before the interruption
+        if status >> 16 != libc::PTRACE_EVENT_STOP {
+            this.signal = libc::WSTOPSIG(status);
+        }
+
+        Ok(this)
+    }
+
+    #[inline]
+    pub fn tid(&self) -> libc::pid_t {
+        self.tid
+    }
+
+    pub fn restart(self) -> io::Result<()> {
+        let this = mem::ManuallyDrop::new(self);
+        this.detach()
+    }
+
+    fn detach(&self) -> io::Result<()> {
+        unsafe {
+            check!(libc::ptrace(
+                libc::PTRACE_DETACH,
+                self.tid,
+                ptr::null_mut::<libc::c_void>(),
+                self.signal as usize,
+            ))
+        }
+    }
+}
+
+impl Drop for StoppedThread {
+    fn drop(&mut self) {
+        let _ = self.detach();
+    }
+}
+
 /// Represents a ptraced process, ready to execute some functions for us.
 ///
 /// Cleans up everything behind dropped. Do not leak !
@@ -1,3 +1,13 @@
+//! vminer's own dump format.
+//!
+//! Version 1 files contain the memory mappings, the registers of each vCPU
+//! and then a raw copy of memory. Version 2 files are sparse and compressed,
+//! and store metadata about the guest OS (see the `v2` module).
+//!
+//! Both versions start with the same magic. In version 1 it is followed by
+//! the architecture (0 or 1), whereas version 2 puts its version number
+//! there instead.
+
 use bytemuck::Zeroable;
 use std::{
     fs,
@@ -5,10 +15,17 @@ use std::{
     path::Path,
 };
 use vmc::{
-    Architecture, Memory, PhysicalAddress, VmResult,
-    arch::{self, HasVcpus, aarch64, x86_64},
+    Memory, PhysicalAddress, VirtualAddress, VmResult,
+    arch::{aarch64, x86_64},
 };
 
+use super::vcpus::{Vcpus, delegate_vcpus};
+
+mod sparse;
+mod v2;
+
+pub use sparse::SparseCapture;
+
 #[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C)]
 pub struct Header {
@@ -20,53 +37,238 @@ pub struct Header {
 
 const MAGIC: u32 = u32::from_le_bytes(*b"\xaabox");
 
-#[derive(Debug)]
-enum Vcpus {
-    X86_64(Vec<arch::x86_64::Vcpu>),
-    Aarch64(Vec<arch::aarch64::Vcpu>),
+/// The OS running in the guest when a dump was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsKind {
+    Linux,
+    Windows,
 }
 
-impl Vcpus {
-    fn read_x86_64<R: Read>(mut reader: R, n_vcpus: usize) -> io::Result<Self> {
-        let mut vcpus = Vec::with_capacity(n_vcpus);
+/// Information about the guest OS stored in a dump, so that it does not
+/// have to be searched for again when the dump is read.
+///
+/// This is only saved by version 2 dumps.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub os: Option<OsKind>,
+    pub kpgd: Option<PhysicalAddress>,
+    /// Kernel base on Windows, address of the banner on Linux
+    pub kaslr: Option<VirtualAddress>,
+    /// PDB id of the kernel on Windows, banner on Linux
+    pub version: Option<String>,
+    /// Capture time, in seconds since the Unix epoch
+    pub timestamp: Option<u64>,
+}
 
-        for _ in 0..n_vcpus {
-            let mut vcpu = x86_64::Vcpu::zeroed();
+impl Metadata {
+    /// Creates empty metadata with the current time as capture time.
+    pub fn now() -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|time| time.as_secs());
+
+        Self {
+            timestamp,
+            ..Self::default()
+        }
+    }
 
-            reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.registers))?;
-            reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.special_registers))?;
-            reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.other_registers))?;
+    /// Detects the OS running in a guest and records what was found.
+    ///
+    /// If no OS is detected, only the capture time is set.
+    #[cfg(any(feature = "linux", feature = "windows"))]
+    pub fn detect<B: vmc::Backend>(backend: &B) -> Self {
+        use crate::os::Buildable;
+
+        let mut metadata = Self::now();
+
+        #[cfg(feature = "linux")]
+        if let Some(builder) = crate::os::Linux::quick_check(backend) {
+            metadata.os = Some(OsKind::Linux);
+            metadata.kpgd = builder.kpgd;
+            metadata.kaslr = builder.kaslr;
+
+            if let (Some(kpgd), Some(banner_addr)) = (builder.kpgd, builder.kaslr) {
+                let mut banner = [0; 0x200];
+                let banner = vmc::read_virtual_memory(banner_addr, &mut banner, |addr, buf| {
+                    backend.read_virtual_memory(kpgd, addr, buf)
+                })
+                .ok()
+                .and_then(|()| {
+                    let len = memchr::memchr(0, &banner)?;
+                    Some(String::from_utf8_lossy(&banner[..len]).into_owned())
+                });
+                metadata.version = banner;
+            }
+
+            return metadata;
+        }
 
-            vcpus.push(vcpu);
+        #[cfg(feature = "windows")]
+        if let Some(builder) = crate::os::Windows::quick_check(backend) {
+            metadata.os = Some(OsKind::Windows);
+            metadata.kpgd = builder.kpgd;
+            metadata.kaslr = builder.kaslr;
+            metadata.version = builder.version;
         }
 
-        Ok(Self::X86_64(vcpus))
+        metadata
     }
 
-    fn read_aarch64<R: Read>(mut reader: R, n_vcpus: usize) -> io::Result<Self> {
-        let mut vcpus = Vec::with_capacity(n_vcpus);
+    /// Returns an `OsBuilder` with the kernel page directory, base address
+    /// and version that were recorded.
+    pub fn os_builder(&self) -> crate::os::OsBuilder {
+        let mut builder = crate::os::OsBuilder::new();
+        if let Some(kpgd) = self.kpgd {
+            builder = builder.with_kpgd(kpgd);
+        }
+        if let Some(kaslr) = self.kaslr {
+            builder = builder.with_kaslr(kaslr);
+        }
+        if let Some(version) = &self.version {
+            builder = builder.with_version(version.clone());
+        }
+        builder
+    }
+}
 
-        for _ in 0..n_vcpus {
-            let mut vcpu = aarch64::Vcpu::zeroed();
+fn arch_header(vcpus: &Vcpus) -> (u32, u32) {
+    match vcpus {
+        Vcpus::X86_64(vcpus) => (0, vcpus.len() as u32),
+        Vcpus::Aarch64(vcpus) => (1, vcpus.len() as u32),
+    }
+}
 
-            reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.registers))?;
-            reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.special_registers))?;
-            reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.other_registers))?;
+fn write_vcpus<W: Write>(mut out: W, vcpus: &Vcpus) -> io::Result<()> {
+    match vcpus {
+        Vcpus::X86_64(vcpus) => {
+            for vcpu in vcpus {
+                out.write_all(bytemuck::bytes_of(&vcpu.registers))?;
+                out.write_all(bytemuck::bytes_of(&vcpu.special_registers))?;
+                out.write_all(bytemuck::bytes_of(&vcpu.other_registers))?;
+            }
+        }
+        Vcpus::Aarch64(vcpus) => {
+            for vcpu in vcpus {
+                out.write_all(bytemuck::bytes_of(&vcpu.registers))?;
+                out.write_all(bytemuck::bytes_of(&vcpu.special_registers))?;
+                out.write_all(bytemuck::bytes_of(&vcpu.other_registers))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_vcpus<R: Read>(reader: R, arch: u32, n_vcpus: u32) -> VmResult<Vcpus> {
+    match arch {
+        0 => Ok(read_vcpus_x86_64(reader, n_vcpus as _)?),
+        1 => Ok(read_vcpus_aarch64(reader, n_vcpus as _)?),
+        _ => Err(vmc::VmError::new("unsupported architecture")),
+    }
+}
+
+fn remap_addresses(mappings: &[vmc::mem::MemoryMap]) -> (Vec<PhysicalAddress>, u64) {
+    let mut remap_addr = PhysicalAddress(0);
+    let remap_at = mappings
+        .iter()
+        .map(|mapping| {
+            let offset = remap_addr;
+            remap_addr += mapping.end - mapping.start;
+            offset
+        })
+        .collect();
+    (remap_at, remap_addr.0)
+}
+
+fn read_vcpus_x86_64<R: Read>(mut reader: R, n_vcpus: usize) -> io::Result<Vcpus> {
+    let mut vcpus = Vec::with_capacity(n_vcpus);
+
+    for _ in 0..n_vcpus {
+        let mut vcpu = x86_64::Vcpu::zeroed();
+
+        reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.registers))?;
+        reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.special_registers))?;
+        reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.other_registers))?;
+
+        vcpus.push(vcpu);
+    }
+
+    Ok(Vcpus::X86_64(vcpus))
+}
+
+fn read_vcpus_aarch64<R: Read>(mut reader: R, n_vcpus: usize) -> io::Result<Vcpus> {
+    let mut vcpus = Vec::with_capacity(n_vcpus);
+
+    for _ in 0..n_vcpus {
+        let mut vcpu = aarch64::Vcpu::zeroed();
+
+        reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.registers))?;
+        reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.special_registers))?;
+        reader.read_exact(bytemuck::bytes_of_mut(&mut vcpu.other_registers))?;
+
+        vcpus.push(vcpu);
+    }
 
-            vcpus.push(vcpu);
+    Ok(Vcpus::Aarch64(vcpus))
+}
+
+#[derive(Debug)]
+enum Storage<Mem> {
+    Raw(vmc::mem::MemRemap<Mem>),
+    Paged(v2::PagedMemory<Mem>),
+}
+
+impl<Mem: vmc::Memory> vmc::Memory for Storage<Mem> {
+    #[inline]
+    fn memory_mappings(&self) -> &[vmc::mem::MemoryMap] {
+        match self {
+            Self::Raw(mem) => mem.memory_mappings(),
+            Self::Paged(mem) => mem.memory_mappings(),
         }
+    }
 
-        Ok(Self::Aarch64(vcpus))
+    #[inline]
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        match self {
+            Self::Raw(mem) => mem.is_valid(addr, size),
+            Self::Paged(mem) => mem.is_valid(addr, size),
+        }
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> vmc::MemoryAccessResult<()> {
+        match self {
+            Self::Raw(mem) => mem.read_physical(addr, buf),
+            Self::Paged(mem) => mem.read_physical(addr, buf),
+        }
+    }
+
+    #[inline]
+    fn search(
+        &self,
+        addr: PhysicalAddress,
+        page_size: u64,
+        finder: &memchr::memmem::Finder,
+        buf: &mut [u8],
+    ) -> vmc::MemoryAccessResult<Option<u64>> {
+        match self {
+            Self::Raw(mem) => mem.search(addr, page_size, finder, buf),
+            Self::Paged(mem) => mem.search(addr, page_size, finder, buf),
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct DumbDump<Mem> {
     vcpus: Vcpus,
-    mem: vmc::mem::MemRemap<Mem>,
+    mem: Storage<Mem>,
+    metadata: Metadata,
 }
 
 impl DumbDump<vmc::mem::File> {
+    /// Reads a dump in either version of the format.
     pub fn read<P: AsRef<Path>>(path: P) -> VmResult<Self> {
         let mut file = io::BufReader::new(fs::File::open(path)?);
 
@@ -77,110 +279,337 @@ impl DumbDump<vmc::mem::File> {
             return Err(vmc::VmError::new("invalid file magic"));
         }
 
+        match header.arch {
+            0 | 1 => Self::read_v1(file, header),
+            v2::VERSION => Self::read_v2(file),
+            _ => Err(vmc::VmError::new("unsupported dump version")),
+        }
+    }
+
+    fn read_v1(mut file: io::BufReader<fs::File>, header: Header) -> VmResult<Self> {
         let mut mappings = vec![vmc::mem::MemoryMap::zeroed(); header.n_mappings as _];
         file.read_exact(bytemuck::cast_slice_mut(&mut mappings))?;
 
-        let mut remap_addr = PhysicalAddress(0);
-        let remap_at = mappings
-            .iter()
-            .map(|mapping| {
-                let offset = remap_addr;
-                remap_addr += mapping.end - mapping.start;
-                offset
-            })
-            .collect();
-
-        let vcpus = match header.arch {
-            0 => Vcpus::read_x86_64(&mut file, header.n_vcpus as _)?,
-            1 => Vcpus::read_aarch64(&mut file, header.n_vcpus as _)?,
-            _ => return Err(vmc::VmError::new("unsupported architecture")),
-        };
+        let (remap_at, mem_size) = remap_addresses(&mappings);
+        let vcpus = read_vcpus(&mut file, header.arch, header.n_vcpus)?;
 
         let start = file.stream_position()?;
         let mut file = file.into_inner();
         let end = file.seek(io::SeekFrom::End(0))?;
 
-        let mem_size: i64 = mappings.iter().map(|map| map.end - map.start).sum();
-        if end - start != mem_size as u64 {
-            println!(
-                "memsize 0x{mem_size:x} ; 0x{end:x} - 0x{start:x} = 0x{:x}",
-                end - start
-            );
+        if end - start != mem_size {
             return Err(vmc::VmError::new("invalid file size"));
         }
 
         let mem =
             vmc::mem::MemRemap::new(vmc::mem::File::new(file, start, end), mappings, remap_at);
 
-        Ok(DumbDump { vcpus, mem })
+        Ok(DumbDump {
+            vcpus,
+            mem: Storage::Raw(mem),
+            metadata: Metadata::default(),
+        })
+    }
+
+    fn read_v2(mut file: io::BufReader<fs::File>) -> VmResult<Self> {
+        let mut header = v2::Header::zeroed();
+        file.rewind()?;
+        file.read_exact(bytemuck::bytes_of_mut(&mut header))?;
+
+        // Sizes come from the file, so check them before allocating
+        let end = file.get_ref().metadata()?.len();
+        let mappings_size = header.n_mappings as u64 * size_of::<vmc::mem::MemoryMap>() as u64;
+        if mappings_size.saturating_add(header.metadata_size) > end {
+            return Err(vmc::VmError::new("invalid file size"));
+        }
+
+        let mut mappings = vec![vmc::mem::MemoryMap::zeroed(); header.n_mappings as _];
+        file.read_exact(bytemuck::cast_slice_mut(&mut mappings))?;
+
+        let vcpus = read_vcpus(&mut file, header.arch, header.n_vcpus)?;
+
+        let mut metadata = vec![0; header.metadata_size as usize];
+        file.read_exact(&mut metadata)?;
+        let metadata = v2::decode_metadata(&metadata)?;
+
+        let file = file.into_inner();
+        let mem = v2::PagedMemory::new(vmc::mem::File::new(file, 0, end), &header, mappings)?;
+
+        Ok(DumbDump {
+            vcpus,
+            mem: Storage::Paged(mem),
+            metadata,
+        })
+    }
+}
+
+impl<Mem> DumbDump<Mem> {
+    /// Metadata saved with the dump, or found when it was taken.
+    #[inline]
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    #[inline]
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
+
+    #[inline]
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Returns an `OsBuilder` with the kernel page directory, base address
+    /// and version saved in the dump, so that they don't have to be searched
+    /// for.
+    #[inline]
+    pub fn os_builder(&self) -> crate::os::OsBuilder {
+        self.metadata.os_builder()
     }
 }
 
 impl<Mem: vmc::Memory> DumbDump<Mem> {
+    /// Writes the dump in the version 2 format.
+    #[inline]
     pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
-        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        self.write_as(path, Format::V2)
+    }
+
+    /// Writes the dump in the version 1 format, which can be read by older
+    /// versions of vminer.
+    #[inline]
+    pub fn write_v1<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write_as(path, Format::V1)
+    }
+
+    pub fn write_as<P: AsRef<Path>>(&self, path: P, format: Format) -> io::Result<()> {
+        write_dump(
+            path.as_ref(),
+            format,
+            &self.vcpus,
+            &self.mem,
+            self.mem.memory_mappings(),
+            &self.metadata,
+            &mut |_, _| (),
+        )
+    }
+}
 
-        let mappings = self.mem.memory_mappings();
+/// Version of the format used to write a dump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Raw copy of memory, readable by older versions of vminer
+    V1,
+    /// Sparse and compressed memory, with metadata
+    #[default]
+    V2,
+}
 
-        let mut header = Header {
-            magic: MAGIC,
-            n_mappings: mappings.len() as u32,
-            arch: 0,
-            n_vcpus: 0,
-        };
+/// Maximum size of memory read at once when writing a dump
+const CHUNK_SIZE: usize = 1 << 20;
 
-        match &self.vcpus {
-            Vcpus::X86_64(vcpus) => {
-                header.arch = 0;
-                header.n_vcpus = vcpus.len() as u32;
+enum Output {
+    V1(io::BufWriter<fs::File>),
+    V2(v2::Writer<fs::File>),
+}
+
+impl Output {
+    fn create(
+        path: &Path,
+        format: Format,
+        vcpus: &Vcpus,
+        mappings: &[vmc::mem::MemoryMap],
+        metadata: &Metadata,
+    ) -> io::Result<Self> {
+        match format {
+            Format::V1 => {
+                let mut out = io::BufWriter::new(fs::File::create(path)?);
+                let (arch, n_vcpus) = arch_header(vcpus);
+
+                let header = Header {
+                    magic: MAGIC,
+                    n_mappings: mappings.len() as u32,
+                    arch,
+                    n_vcpus,
+                };
+
+                out.write_all(bytemuck::bytes_of(&header))?;
+                out.write_all(bytemuck::cast_slice(mappings))?;
+                write_vcpus(&mut out, vcpus)?;
+                Ok(Self::V1(out))
             }
-            Vcpus::Aarch64(vcpus) => {
-                header.arch = 1;
-                header.n_vcpus = vcpus.len() as u32;
+            Format::V2 => {
+                let file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?;
+                let writer = v2::Writer::new(file, vcpus, mappings, metadata)?;
+                Ok(Self::V2(writer))
             }
         }
+    }
 
-        out.write_all(bytemuck::bytes_of(&header))?;
-        out.write_all(bytemuck::cast_slice(mappings))?;
+    /// Writes the next chunk of a mapping. Chunks must be a multiple of the
+    /// page size, except at the end of a mapping.
+    fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        match self {
+            Self::V1(out) => out.write_all(chunk),
+            Self::V2(writer) => chunk
+                .chunks(v2::PAGE_SIZE as usize)
+                .try_for_each(|page| writer.write_page(page)),
+        }
+    }
 
-        match &self.vcpus {
-            Vcpus::X86_64(vcpus) => {
-                for vcpu in vcpus {
-                    out.write_all(bytemuck::bytes_of(&vcpu.registers))?;
-                    out.write_all(bytemuck::bytes_of(&vcpu.special_registers))?;
-                    out.write_all(bytemuck::bytes_of(&vcpu.other_registers))?;
-                }
-            }
-            Vcpus::Aarch64(vcpus) => {
-                for vcpu in vcpus {
-                    out.write_all(bytemuck::bytes_of(&vcpu.registers))?;
-                    out.write_all(bytemuck::bytes_of(&vcpu.special_registers))?;
-                    out.write_all(bytemuck::bytes_of(&vcpu.other_registers))?;
-                }
-            }
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::V1(mut out) => out.flush(),
+            Self::V2(writer) => writer.finish().map(drop),
+        }
+    }
+}
+
+/// Writes a dump, copying memory by chunks of bounded size.
+///
+/// `progress` is called after each chunk with the number of bytes of memory
+/// written so far and the total.
+fn write_dump<M: vmc::Memory + ?Sized>(
+    path: &Path,
+    format: Format,
+    vcpus: &Vcpus,
+    mem: &M,
+    mappings: &[vmc::mem::MemoryMap],
+    metadata: &Metadata,
+    progress: &mut dyn FnMut(u64, u64),
+) -> io::Result<()> {
+    let total = mappings
+        .iter()
+        .map(|mapping| (mapping.end - mapping.start) as u64)
+        .sum();
+
+    let mut output = Output::create(path, format, vcpus, mappings, metadata)?;
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut done = 0;
+    for mapping in mappings {
+        let mut addr = mapping.start;
+        while addr < mapping.end {
+            let len = core::cmp::min(CHUNK_SIZE, (mapping.end - addr) as usize);
+            let chunk = &mut buf[..len];
+            mem.read_physical(addr, chunk).map_err(io::Error::other)?;
+            output.write_chunk(chunk)?;
+
+            addr += len as u64;
+            done += len as u64;
+            progress(done, total);
+        }
+    }
+
+    output.finish()
+}
+
+/// Writes a dump of a guest directly to a file, without loading its whole
+/// memory first.
+///
+/// ```no_run
+/// # fn f(vm: &impl vminer::core::Backend) -> vminer::core::VmResult<()> {
+/// use vminer::backends::kvm_dump::DumpWriter;
+///
+/// DumpWriter::new(vm)
+///     .with_progress(|done, total| eprint!("\r{}%", done * 100 / total))
+///     .write("vm.dump")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct DumpWriter<'a, B> {
+    backend: &'a B,
+    format: Format,
+    metadata: Metadata,
+    mappings: Option<Vec<vmc::mem::MemoryMap>>,
+    progress: Option<Box<dyn FnMut(u64, u64) + 'a>>,
+}
+
+impl<'a, B: vmc::Backend> DumpWriter<'a, B> {
+    /// Creates a writer for the version 2 format, with the current time as
+    /// capture time.
+    #[inline]
+    pub fn new(backend: &'a B) -> Self {
+        Self {
+            backend,
+            format: Format::default(),
+            metadata: Metadata::now(),
+            mappings: None,
+            progress: None,
         }
+    }
 
-        self.mem.dump(&mut out)?;
+    #[inline]
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
 
+    /// Sets the metadata to write. It is ignored by the version 1 format.
+    #[inline]
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Only writes these ranges of memory instead of all of it.
+    ///
+    /// See [`SparseCapture`] to select memory referenced by page tables.
+    #[inline]
+    pub fn with_mappings(mut self, mappings: Vec<vmc::mem::MemoryMap>) -> Self {
+        self.mappings = Some(mappings);
+        self
+    }
+
+    /// Sets a function called regularly with the number of bytes of memory
+    /// written so far and the total.
+    #[inline]
+    pub fn with_progress(mut self, progress: impl FnMut(u64, u64) + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn write<P: AsRef<Path>>(mut self, path: P) -> VmResult<()> {
+        let vcpus = Vcpus::from_backend(self.backend)?;
+        let mappings = match &self.mappings {
+            Some(mappings) => mappings,
+            None => self.backend.memory_mappings(),
+        };
+        let progress = match &mut self.progress {
+            Some(progress) => &mut **progress,
+            None => &mut |_, _| (),
+        };
+
+        write_dump(
+            path.as_ref(),
+            self.format,
+            &vcpus,
+            self.backend,
+            mappings,
+            &self.metadata,
+            progress,
+        )?;
         Ok(())
     }
 }
 
 impl DumbDump<vmc::mem::RawMemory<Vec<u8>>> {
+    /// Copies the memory and registers of a guest.
+    ///
+    /// All memory is loaded in RAM, so [`DumpWriter`] should be preferred to
+    /// write a dump of a large guest.
     pub fn dump_vm<B: vmc::Backend>(backend: &B) -> VmResult<Self> {
         let mappings = backend.memory_mappings().to_owned();
+        let (remap_at, mem_size) = remap_addresses(&mappings);
 
-        let mut remap_addr = PhysicalAddress(0);
-        let remap_at = mappings
-            .iter()
-            .map(|mapping| {
-                let offset = remap_addr;
-                remap_addr += mapping.end - mapping.start;
-                offset
-            })
-            .collect();
-
-        let mut mem = vec![0; remap_addr.0 as usize];
+        let mut mem = vec![0; mem_size as usize];
         let mut offset = 0;
         for mapping in &mappings {
             let next_offset = offset + (mapping.end - mapping.start) as usize;
@@ -188,37 +617,16 @@ impl DumbDump<vmc::mem::RawMemory<Vec<u8>>> {
             offset = next_offset;
         }
 
-        let vcpus = match backend.arch().into_runtime() {
-            arch::RuntimeArchitecture::X86_64(_) => {
-                let backend = vmc::arch::AssumeX86_64(backend);
-                let mut vcpus = Vec::with_capacity(backend.vcpus_count());
-                for vcpu in backend.iter_vcpus() {
-                    vcpus.push(vmc::arch::x86_64::Vcpu {
-                        registers: backend.registers(vcpu)?,
-                        special_registers: backend.special_registers(vcpu)?,
-                        other_registers: backend.other_registers(vcpu)?,
-                    })
-                }
-                Vcpus::X86_64(vcpus)
-            }
-            arch::RuntimeArchitecture::Aarch64(_) => {
-                let backend = vmc::arch::AssumeAarch64(backend);
-                let mut vcpus = Vec::with_capacity(backend.vcpus_count());
-                for vcpu in backend.iter_vcpus() {
-                    vcpus.push(vmc::arch::aarch64::Vcpu {
-                        registers: backend.registers(vcpu)?,
-                        special_registers: backend.special_registers(vcpu)?,
-                        other_registers: backend.other_registers(vcpu)?,
-                    })
-                }
-                Vcpus::Aarch64(vcpus)
-            }
-        };
+        let vcpus = Vcpus::from_backend(backend)?;
 
         let mem = vmc::mem::RawMemory::new(mem);
         let mem = vmc::mem::MemRemap::new(mem, mappings, remap_at);
 
-        let dump = DumbDump { vcpus, mem };
+        let dump = DumbDump {
+            vcpus,
+            mem: Storage::Raw(mem),
+            metadata: Metadata::now(),
+        };
         Ok(dump)
     }
 }
@@ -251,82 +659,6 @@ impl<Mem: vmc::Memory> vmc::Memory for DumbDump<Mem> {
     }
 }
 
-impl<Mem> vmc::HasVcpus for DumbDump<Mem> {
-    type Arch = vmc::arch::RuntimeArchitecture;
-
-    fn arch(&self) -> Self::Arch {
-        match &self.vcpus {
-            Vcpus::X86_64(_) => vmc::arch::RuntimeArchitecture::X86_64(vmc::arch::X86_64),
-            Vcpus::Aarch64(_) => vmc::arch::RuntimeArchitecture::Aarch64(vmc::arch::Aarch64),
-        }
-    }
-
-    fn vcpus_count(&self) -> usize {
-        match &self.vcpus {
-            Vcpus::X86_64(vcpus) => vcpus.len(),
-            Vcpus::Aarch64(vcpus) => vcpus.len(),
-        }
-    }
-
-    fn registers(
-        &self,
-        vcpu: vmc::VcpuId,
-    ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::Registers> {
-        Ok(match &self.vcpus {
-            Vcpus::X86_64(vcpus) => vmc::arch::runtime::Registers::X86_64(
-                vcpus
-                    .get(vcpu.0)
-                    .ok_or(vmc::VcpuError::InvalidId)?
-                    .registers,
-            ),
-            Vcpus::Aarch64(vcpus) => vmc::arch::runtime::Registers::Aarch64(
-                vcpus
-                    .get(vcpu.0)
-                    .ok_or(vmc::VcpuError::InvalidId)?
-                    .registers,
-            ),
-        })
-    }
-
-    fn special_registers(
-        &self,
-        vcpu: vmc::VcpuId,
-    ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::SpecialRegisters> {
-        Ok(match &self.vcpus {
-            Vcpus::X86_64(vcpus) => vmc::arch::runtime::SpecialRegisters::X86_64(
-                vcpus
-                    .get(vcpu.0)
-                    .ok_or(vmc::VcpuError::InvalidId)?
-                    .special_registers,
-            ),
-            Vcpus::Aarch64(vcpus) => vmc::arch::runtime::SpecialRegisters::Aarch64(
-                vcpus
-                    .get(vcpu.0)
-                    .ok_or(vmc::VcpuError::InvalidId)?
-                    .special_registers,
-            ),
-        })
-    }
-
-    fn other_registers(
-        &self,
-        vcpu: vmc::VcpuId,
-    ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::OtherRegisters> {
-        Ok(match &self.vcpus {
-            Vcpus::X86_64(vcpus) => vmc::arch::runtime::OtherRegisters::X86_64(
-                vcpus
-                    .get(vcpu.0)
-                    .ok_or(vmc::VcpuError::InvalidId)?
-                    .other_registers,
-            ),
-            Vcpus::Aarch64(vcpus) => vmc::arch::runtime::OtherRegisters::Aarch64(
-                vcpus
-                    .get(vcpu.0)
-                    .ok_or(vmc::VcpuError::InvalidId)?
-                    .other_registers,
-            ),
-        })
-    }
-}
+delegate_vcpus!(DumbDump<Mem>);
 
 impl<Mem: vmc::Memory> vmc::Backend for DumbDump<Mem> {}
@@ -0,0 +1,268 @@
+//! Selection of the memory referenced by page tables, to write dumps that
+//! only contain what is mapped by the kernel and by processes.
+
+use core::ops::ControlFlow;
+use vmc::{PhysicalAddress, VirtualAddress, VmResult, arch::PageWalkEntry, mem::MemoryMap};
+
+#[cfg(feature = "linux")]
+use std::sync::Mutex;
+
+/// Collects the physical memory mapped by the page directories of the kernel
+/// and of all processes, including the translation tables themselves.
+///
+/// The result can be given to [`DumpWriter::with_mappings`] to write a sparse
+/// dump.
+///
+/// Note that some kernels map all physical memory in their address space
+/// (for example the "direct map" of Linux), in which case the whole memory is
+/// selected unless this range is excluded. Data structures that are only
+/// mapped there are then missing from the dump, unless they are collected
+/// with [`SparseCapture::collect_linux`].
+///
+/// [`DumpWriter::with_mappings`]: super::DumpWriter::with_mappings
+#[derive(Debug, Clone, Default)]
+pub struct SparseCapture {
+    excluded: Vec<(VirtualAddress, VirtualAddress)>,
+}
+
+impl SparseCapture {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignores pages mapped between `start` and `end` in all page
+    /// directories.
+    #[inline]
+    pub fn exclude(mut self, start: VirtualAddress, end: VirtualAddress) -> Self {
+        self.excluded.push((start, end));
+        self
+    }
+
+    fn is_excluded(&self, addr: VirtualAddress, size: u64) -> bool {
+        self.excluded
+            .iter()
+            .any(|&(start, end)| start <= addr && addr + size <= end)
+    }
+
+    /// Returns the sorted ranges of physical memory referenced by the page
+    /// directories.
+    pub fn collect<B: vmc::Backend, O: vmc::Os + ?Sized>(
+        &self,
+        backend: &B,
+        os: &O,
+    ) -> VmResult<Vec<MemoryMap>> {
+        let mut tables = hashbrown::HashSet::new();
+        let mut frames = Vec::new();
+
+        let mut add_frame = |start: PhysicalAddress, size: u64| match frames.last_mut() {
+            Some(MemoryMap { end, .. }) if *end == start => *end = start + size,
+            _ => frames.push(MemoryMap {
+                start,
+                end: start + size,
+            }),
+        };
+
+        let mut walk = |pgd: PhysicalAddress, kernel: bool| {
+            backend.walk_page_tables(pgd, kernel, &mut |entry| match entry {
+                PageWalkEntry::Table {
+                    physical_addr,
+                    virtual_addr,
+                    size,
+                } => {
+                    // Tables of the kernel are shared by all processes
+                    if self.is_excluded(virtual_addr, size) || !tables.insert(physical_addr) {
+                        return false;
+                    }
+                    add_frame(physical_addr, 0x1000);
+                    true
+                }
+                PageWalkEntry::Page {
+                    physical_addr,
+                    virtual_addr,
+                    size,
+                } => {
+                    if !self.is_excluded(virtual_addr, size) {
+                        add_frame(physical_addr, size);
+                    }
+                    true
+                }
+            })
+        };
+
+        walk(os.kernel_pgd(), true)?;
+
+        os.for_each_process(&mut |proc| {
+            match os.process_pgd(proc) {
+                Ok(pgd) => walk(pgd, false)?,
+                Err(err) => log::debug!("Skipping process at {:#x}: {err}", proc.0),
+            }
+            Ok(ControlFlow::Continue(()))
+        })?;
+
+        Ok(vmc::mem::clamp_to_mappings(
+            frames,
+            backend.memory_mappings(),
+        ))
+    }
+}
+
+#[cfg(feature = "linux")]
+impl SparseCapture {
+    /// Collects the memory of a Linux guest, without its direct map.
+    ///
+    /// The kernel structures are allocated in the direct map, so the pages
+    /// read while walking processes, threads and VMAs are kept too. This
+    /// makes the dump much smaller than the guest's memory, at the cost of
+    /// the page cache, of the memory of the kernel allocator that vminer
+    /// does not walk and of anything that changes while the guest runs.
+    pub fn collect_linux<B: vmc::Backend>(
+        &self,
+        backend: &B,
+        builder: crate::os::OsBuilder,
+    ) -> VmResult<Vec<MemoryMap>> {
+        let recorder = Recorder {
+            backend,
+            pages: Mutex::new(hashbrown::HashSet::new()),
+        };
+        let linux: crate::os::Linux<_> = builder.build(recorder)?;
+
+        let (start, end) = linux.direct_map()?;
+        log::debug!("Excluding the direct map at {start:#x}-{end:#x}");
+        let mut frames = self.clone().exclude(start, end).collect(backend, &linux)?;
+
+        touch_os_structures(&linux)?;
+
+        let pages = core::mem::take(&mut *linux.backend().pages.lock().unwrap());
+        frames.extend(pages.into_iter().map(|page| MemoryMap {
+            start: PhysicalAddress(page),
+            end: PhysicalAddress(page + 0x1000),
+        }));
+        Ok(vmc::mem::clamp_to_mappings(
+            frames,
+            backend.memory_mappings(),
+        ))
+    }
+}
+
+/// Reads the structures that vminer uses to inspect processes.
+///
+/// Errors are ignored, as the goal is only to read memory.
+#[cfg(feature = "linux")]
+fn touch_os_structures<O: vmc::Os + ?Sized>(os: &O) -> VmResult<()> {
+    for vcpu in os.iter_vcpus() {
+        let _ = os.current_thread(vcpu);
+    }
+
+    os.for_each_process(&mut |proc| {
+        let _ = os.process_id(proc);
+        let _ = os.process_name(proc);
+        let _ = os.process_path(proc);
+        let _ = os.process_parent_id(proc);
+        let _ = os.process_for_each_child(proc, &mut |_| Ok(ControlFlow::Continue(())));
+
+        let _ = os.process_for_each_thread(proc, &mut |thread| {
+            let _ = os.thread_id(thread);
+            let _ = os.thread_name(thread);
+            Ok(ControlFlow::Continue(()))
+        });
+
+        let _ = os.process_for_each_vma(proc, &mut |vma| {
+            let _ = os.vma_start(vma);
+            let _ = os.vma_end(vma);
+            let _ = os.vma_flags(vma);
+            let _ = os.vma_path(vma);
+            Ok(ControlFlow::Continue(()))
+        });
+
+        let _ = os.process_for_each_module(proc, &mut |module| {
+            let _ = os.module_name(module, proc);
+            let _ = os.module_path(module, proc);
+            let _ = os.module_span(module, proc);
+            Ok(ControlFlow::Continue(()))
+        });
+
+        Ok(ControlFlow::Continue(()))
+    })
+}
+
+/// A backend that records which physical pages are read.
+#[cfg(feature = "linux")]
+struct Recorder<'a, B> {
+    backend: &'a B,
+    pages: Mutex<hashbrown::HashSet<u64>>,
+}
+
+#[cfg(feature = "linux")]
+impl<B: vmc::Backend> vmc::Memory for Recorder<'_, B> {
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.backend.memory_mappings()
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> vmc::MemoryAccessResult<()> {
+        self.backend.read_physical(addr, buf)?;
+
+        if let Some(last) = (addr.0 + buf.len() as u64).checked_sub(1) {
+            let mut pages = self.pages.lock().unwrap();
+            pages.extend((addr.0 & !0xfff..=last).step_by(0x1000));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "linux")]
+impl<B: vmc::Backend> vmc::HasVcpus for Recorder<'_, B> {
+    type Arch = B::Arch;
+
+    fn arch(&self) -> Self::Arch {
+        self.backend.arch()
+    }
+
+    fn vcpus_count(&self) -> usize {
+        self.backend.vcpus_count()
+    }
+
+    fn registers(
+        &self,
+        vcpu: vmc::VcpuId,
+    ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::Registers> {
+        self.backend.registers(vcpu)
+    }
+
+    fn special_registers(
+        &self,
+        vcpu: vmc::VcpuId,
+    ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::SpecialRegisters> {
+        self.backend.special_registers(vcpu)
+    }
+
+    fn other_registers(
+        &self,
+        vcpu: vmc::VcpuId,
+    ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::OtherRegisters> {
+        self.backend.other_registers(vcpu)
+    }
+
+    fn instruction_pointer(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<VirtualAddress> {
+        self.backend.instruction_pointer(vcpu)
+    }
+
+    fn stack_pointer(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<VirtualAddress> {
+        self.backend.stack_pointer(vcpu)
+    }
+
+    fn base_pointer(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<Option<VirtualAddress>> {
+        self.backend.base_pointer(vcpu)
+    }
+
+    fn pgd(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<PhysicalAddress> {
+        self.backend.pgd(vcpu)
+    }
+
+    fn kernel_per_cpu(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<Option<VirtualAddress>> {
+        self.backend.kernel_per_cpu(vcpu)
+    }
+}
+
+#[cfg(feature = "linux")]
+impl<B: vmc::Backend> vmc::Backend for Recorder<'_, B> {}
@@ -0,0 +1,440 @@
+//! Version 2 of the dump format.
+//!
+//! Memory is split in pages, which are stored individually and compressed
+//! with zstd when this makes them smaller. Pages filled with zeros are not
+//! stored at all, and identical pages are only stored once. The file is laid
+//! out as follows:
+//!
+//! - the header;
+//! - the memory mappings;
+//! - the registers of each vCPU, as in version 1;
+//! - the metadata, as a list of `(tag, length, value)` entries;
+//! - the content of the pages;
+//! - the page index, with an entry for each page of each mapping, in order.
+//!
+//! The last page of a mapping may be shorter than the others if the mapping
+//! size is not a multiple of the page size.
+
+use bytemuck::Zeroable;
+use core::hash::BuildHasher;
+use std::{
+    io::{self, Read, Seek, Write},
+    sync::Mutex,
+};
+use vmc::{MemoryAccessError, MemoryAccessResult, PhysicalAddress, VirtualAddress, mem::MemoryMap};
+
+use super::{Metadata, OsKind};
+use crate::backends::{page_cache::PageCache, vcpus::Vcpus};
+
+pub(super) const VERSION: u32 = 2;
+
+pub(super) const PAGE_SIZE: u64 = 0x1000;
+
+/// Number of decompressed pages kept in memory
+const CACHE_SIZE: usize = 64;
+
+const PAGE_ZERO: u32 = 0;
+const PAGE_RAW: u32 = 1;
+const PAGE_ZSTD: u32 = 2;
+
+const TAG_OS: u32 = 1;
+const TAG_KPGD: u32 = 2;
+const TAG_KASLR: u32 = 3;
+const TAG_VERSION: u32 = 4;
+const TAG_TIMESTAMP: u32 = 5;
+
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub(super) struct Header {
+    pub magic: u32,
+    pub version: u32,
+    pub arch: u32,
+    pub n_mappings: u32,
+    pub n_vcpus: u32,
+    pub page_size: u32,
+    pub metadata_size: u64,
+    pub index_offset: u64,
+}
+
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+struct PageEntry {
+    offset: u64,
+    size: u32,
+    kind: u32,
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn mapping_pages(mapping: &MemoryMap, page_size: u64) -> u64 {
+    (mapping.end - mapping.start)
+        .cast_unsigned()
+        .div_ceil(page_size)
+}
+
+pub(super) fn encode_metadata(metadata: &Metadata) -> Vec<u8> {
+    fn push(out: &mut Vec<u8>, tag: u32, value: &[u8]) {
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        out.extend_from_slice(value);
+    }
+
+    let mut out = Vec::new();
+
+    if let Some(os) = metadata.os {
+        let os: u32 = match os {
+            OsKind::Linux => 1,
+            OsKind::Windows => 2,
+        };
+        push(&mut out, TAG_OS, &os.to_le_bytes());
+    }
+    if let Some(kpgd) = metadata.kpgd {
+        push(&mut out, TAG_KPGD, &kpgd.0.to_le_bytes());
+    }
+    if let Some(kaslr) = metadata.kaslr {
+        push(&mut out, TAG_KASLR, &kaslr.0.to_le_bytes());
+    }
+    if let Some(version) = &metadata.version {
+        push(&mut out, TAG_VERSION, version.as_bytes());
+    }
+    if let Some(timestamp) = metadata.timestamp {
+        push(&mut out, TAG_TIMESTAMP, &timestamp.to_le_bytes());
+    }
+
+    out
+}
+
+/// Decodes metadata entries. Unknown tags are skipped, so that new ones can
+/// be added without breaking older readers.
+pub(super) fn decode_metadata(mut data: &[u8]) -> io::Result<Metadata> {
+    let mut metadata = Metadata::default();
+
+    let u64_value = |value: &[u8]| {
+        let value = value
+            .try_into()
+            .map_err(|_| invalid_data("invalid metadata"))?;
+        Ok::<_, io::Error>(u64::from_le_bytes(value))
+    };
+
+    while !data.is_empty() {
+        let (tag, len) = match data {
+            [t0, t1, t2, t3, l0, l1, l2, l3, ..] => (
+                u32::from_le_bytes([*t0, *t1, *t2, *t3]),
+                u32::from_le_bytes([*l0, *l1, *l2, *l3]) as usize,
+            ),
+            _ => return Err(invalid_data("truncated metadata")),
+        };
+        let value = data
+            .get(8..8 + len)
+            .ok_or_else(|| invalid_data("truncated metadata"))?;
+        data = &data[8 + len..];
+
+        match tag {
+            TAG_OS => match value {
+                [1, 0, 0, 0] => metadata.os = Some(OsKind::Linux),
+                [2, 0, 0, 0] => metadata.os = Some(OsKind::Windows),
+                _ => log::warn!("Unknown OS kind in dump metadata"),
+            },
+            TAG_KPGD => metadata.kpgd = Some(PhysicalAddress(u64_value(value)?)),
+            TAG_KASLR => metadata.kaslr = Some(VirtualAddress(u64_value(value)?)),
+            TAG_VERSION => metadata.version = Some(String::from_utf8_lossy(value).into_owned()),
+            TAG_TIMESTAMP => metadata.timestamp = Some(u64_value(value)?),
+            _ => log::debug!("Skipping unknown dump metadata tag {tag}"),
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// Writes a version 2 dump page by page.
+///
+/// Pages must be given in the order of the mappings, and the header is only
+/// completed by [`Writer::finish`].
+pub(super) struct Writer<W: Write> {
+    out: io::BufWriter<W>,
+    offset: u64,
+    index: Vec<PageEntry>,
+    n_pages: u64,
+
+    /// Indices of stored pages, by hash of their content
+    stored: hashbrown::HashMap<u64, Vec<usize>>,
+    hasher: hashbrown::DefaultHashBuilder,
+}
+
+impl<W: Read + Write + Seek> Writer<W> {
+    pub fn new(
+        out: W,
+        vcpus: &Vcpus,
+        mappings: &[MemoryMap],
+        metadata: &Metadata,
+    ) -> io::Result<Self> {
+        let mut out = io::BufWriter::new(out);
+        let metadata = encode_metadata(metadata);
+
+        let (arch, n_vcpus) = super::arch_header(vcpus);
+        let header = Header {
+            magic: super::MAGIC,
+            version: VERSION,
+            arch,
+            n_mappings: mappings.len() as u32,
+            n_vcpus,
+            page_size: PAGE_SIZE as u32,
+            metadata_size: metadata.len() as u64,
+            index_offset: 0,
+        };
+
+        out.write_all(bytemuck::bytes_of(&header))?;
+        out.write_all(bytemuck::cast_slice(mappings))?;
+        super::write_vcpus(&mut out, vcpus)?;
+        out.write_all(&metadata)?;
+
+        let offset = out.stream_position()?;
+        let n_pages = mappings.iter().map(|m| mapping_pages(m, PAGE_SIZE)).sum();
+
+        Ok(Self {
+            out,
+            offset,
+            index: Vec::with_capacity(n_pages as usize),
+            n_pages,
+            stored: hashbrown::HashMap::new(),
+            hasher: hashbrown::DefaultHashBuilder::default(),
+        })
+    }
+
+    /// Checks if the data at `entry` is the same as `data`.
+    fn is_stored_at(&mut self, entry: PageEntry, data: &[u8]) -> io::Result<bool> {
+        if entry.size as usize != data.len() {
+            return Ok(false);
+        }
+
+        let mut stored = vec![0; data.len()];
+        self.out.flush()?;
+        let file = self.out.get_mut();
+        file.seek(io::SeekFrom::Start(entry.offset))?;
+        file.read_exact(&mut stored)?;
+        file.seek(io::SeekFrom::Start(self.offset))?;
+
+        Ok(stored == data)
+    }
+
+    /// Adds the next page to the dump.
+    pub fn write_page(&mut self, page: &[u8]) -> io::Result<()> {
+        if page.len() > PAGE_SIZE as usize || self.index.len() as u64 >= self.n_pages {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "page does not fit in the dump",
+            ));
+        }
+
+        if page.iter().all(|&b| b == 0) {
+            self.index.push(PageEntry {
+                offset: 0,
+                size: page.len() as u32,
+                kind: PAGE_ZERO,
+            });
+            return Ok(());
+        }
+
+        let compressed =
+            ruzstd::encoding::compress_to_vec(page, ruzstd::encoding::CompressionLevel::Fastest);
+        let (kind, data) = if compressed.len() < page.len() {
+            (PAGE_ZSTD, &*compressed)
+        } else {
+            (PAGE_RAW, page)
+        };
+
+        let hash = self.hasher.hash_one((kind, data));
+        let candidates = self.stored.get(&hash).cloned().unwrap_or_default();
+        for i in candidates {
+            let entry = self.index[i];
+            if entry.kind == kind && self.is_stored_at(entry, data)? {
+                self.index.push(entry);
+                return Ok(());
+            }
+        }
+
+        self.stored.entry(hash).or_default().push(self.index.len());
+        self.index.push(PageEntry {
+            offset: self.offset,
+            size: data.len() as u32,
+            kind,
+        });
+        self.out.write_all(data)?;
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Writes the page index and completes the header.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.index.len() as u64 != self.n_pages {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "missing pages in the dump",
+            ));
+        }
+
+        self.out.write_all(bytemuck::cast_slice(&self.index))?;
+
+        let index_offset = self.offset;
+        self.out.seek(io::SeekFrom::Start(
+            core::mem::offset_of!(Header, index_offset) as u64,
+        ))?;
+        self.out.write_all(&index_offset.to_le_bytes())?;
+
+        self.out.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+/// Memory of a version 2 dump, read from its page index.
+#[derive(Debug)]
+pub(super) struct PagedMemory<Mem> {
+    file: Mem,
+    mappings: Vec<MemoryMap>,
+    first_pages: Vec<u64>,
+    /// Indices of mappings, sorted by address
+    sorted: Vec<usize>,
+    index: Vec<PageEntry>,
+    page_size: u64,
+    cache: Mutex<PageCache>,
+}
+
+impl<Mem: vmc::Memory> PagedMemory<Mem> {
+    pub fn new(file: Mem, header: &Header, mappings: Vec<MemoryMap>) -> MemoryAccessResult<Self> {
+        let page_size = header.page_size as u64;
+        if page_size == 0 {
+            return Err(MemoryAccessError::Io(invalid_data("invalid page size")));
+        }
+
+        let mut n_pages = 0u64;
+        let mut first_pages = Vec::with_capacity(mappings.len());
+        for mapping in &mappings {
+            first_pages.push(n_pages);
+            n_pages = (mapping.end.0.checked_sub(mapping.start.0))
+                .and_then(|size| n_pages.checked_add(size.div_ceil(page_size)))
+                .ok_or_else(|| MemoryAccessError::Io(invalid_data("invalid mapping")))?;
+        }
+
+        // Check the index size against the file before allocating it
+        let index_size = n_pages.checked_mul(size_of::<PageEntry>() as u64);
+        let index_fits = index_size.is_some_and(|size| {
+            header.index_offset.checked_add(size).is_some()
+                && file.is_valid(PhysicalAddress(header.index_offset), size as usize)
+        });
+        if !index_fits {
+            return Err(MemoryAccessError::Io(invalid_data(
+                "page index goes past the end of the file",
+            )));
+        }
+
+        let mut sorted: Vec<usize> = (0..mappings.len()).collect();
+        sorted.sort_unstable_by_key(|&i| mappings[i].start);
+
+        let mut index = vec![PageEntry::zeroed(); n_pages as usize];
+        file.read_physical(
+            PhysicalAddress(header.index_offset),
+            bytemuck::cast_slice_mut(&mut index),
+        )?;
+
+        Ok(Self {
+            file,
+            mappings,
+            first_pages,
+            sorted,
+            index,
+            page_size,
+            cache: Mutex::new(PageCache::new(CACHE_SIZE)),
+        })
+    }
+
+    /// Finds the mapping that contains a range of memory.
+    fn find_mapping(&self, addr: PhysicalAddress, size: usize) -> Option<usize> {
+        let i = self
+            .sorted
+            .partition_point(|&i| self.mappings[i].end <= addr);
+        let &i = self.sorted.get(i)?;
+        let mapping = &self.mappings[i];
+        (mapping.start <= addr && addr + size as u64 <= mapping.end).then_some(i)
+    }
+
+    fn load_page(&self, entry: &PageEntry) -> MemoryAccessResult<Box<[u8]>> {
+        let mut data = vec![0; entry.size as usize];
+        self.file
+            .read_physical(PhysicalAddress(entry.offset), &mut data)?;
+
+        let mut page = vec![0; self.page_size as usize].into_boxed_slice();
+        match ruzstd::decoding::FrameDecoder::new().decode_all(&data, &mut page) {
+            Ok(_) => Ok(page),
+            Err(_) => {
+                log::warn!("Failed to decompress page at 0x{:x}", entry.offset);
+                Err(MemoryAccessError::Io(invalid_data(
+                    "failed to decompress page",
+                )))
+            }
+        }
+    }
+
+    fn read_page(&self, entry: &PageEntry, offset: u64, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        match entry.kind {
+            PAGE_ZERO => buf.fill(0),
+            PAGE_RAW => self
+                .file
+                .read_physical(PhysicalAddress(entry.offset + offset), buf)?,
+            PAGE_ZSTD => {
+                let offset = offset as usize;
+                let mut cache = self.cache.lock().unwrap();
+
+                match cache.get(entry.offset) {
+                    Some(page) => buf.copy_from_slice(&page[offset..offset + buf.len()]),
+                    None => {
+                        let page = self.load_page(entry)?;
+                        buf.copy_from_slice(&page[offset..offset + buf.len()]);
+                        cache.insert(entry.offset, page);
+                    }
+                }
+            }
+            _ => return Err(MemoryAccessError::Io(invalid_data("invalid page kind"))),
+        }
+
+        Ok(())
+    }
+}
+
+impl<Mem: vmc::Memory> vmc::Memory for PagedMemory<Mem> {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        &self.mappings
+    }
+
+    #[inline]
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        self.find_mapping(addr, size).is_some()
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        let i = self
+            .find_mapping(addr, buf.len())
+            .ok_or(MemoryAccessError::OutOfBounds)?;
+        let mapping = &self.mappings[i];
+        let first_page = self.first_pages[i];
+
+        let mut offset = (addr - mapping.start).cast_unsigned();
+        let mut buf = buf;
+
+        while !buf.is_empty() {
+            let page = first_page + offset / self.page_size;
+            let page_offset = offset % self.page_size;
+            let len = core::cmp::min(buf.len(), (self.page_size - page_offset) as usize);
+            let (start, rest) = buf.split_at_mut(len);
+
+            self.read_page(&self.index[page as usize], page_offset, start)?;
+
+            buf = rest;
+            offset += len as u64;
+        }
+
+        Ok(())
+    }
+}
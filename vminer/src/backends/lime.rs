@@ -0,0 +1,130 @@
+//! LiME (Linux Memory Extractor) images.
+//!
+//! These files contain ranges of physical memory, each of them preceded by a
+//! small header. They don't contain any register, so there is no vCPU and the
+//! kernel page directory has to be found from memory content (see
+//! [`vmc::Backend::find_kernel_pgd_from_content`]).
+
+use super::read_at;
+use std::{fs, path::Path};
+use vmc::{
+    MemoryAccessResult, PhysicalAddress, ResultExt, VcpuError, VcpuResult, VmError, VmResult,
+    arch::{RuntimeArchitecture, runtime},
+    mem::{MemRemap, MemoryMap},
+};
+
+const LIME_MAGIC: u32 = 0x4c69_4d45;
+const HEADER_SIZE: u64 = 32;
+
+/// `lime_mem_range_header`
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+struct RangeHeader {
+    magic: u32,
+    version: u32,
+    s_addr: u64,
+    e_addr: u64,
+    reserved: [u8; 8],
+}
+
+#[derive(Debug)]
+pub struct Lime<Mem> {
+    mem: MemRemap<Mem>,
+    arch: RuntimeArchitecture,
+}
+
+impl Lime<vmc::mem::File> {
+    /// Opens a LiME image.
+    ///
+    /// As the file does not tell which architecture the memory comes from, it
+    /// has to be given here.
+    pub fn read<P: AsRef<Path>>(path: P, arch: RuntimeArchitecture) -> VmResult<Self> {
+        let file = fs::File::open(path)?;
+        Self::from_file(file, arch)
+    }
+
+    pub fn from_file(file: fs::File, arch: RuntimeArchitecture) -> VmResult<Self> {
+        let size = file.metadata()?.len();
+
+        let mut mappings = Vec::new();
+        let mut remap_at = Vec::new();
+        let mut offset = 0;
+
+        while offset < size {
+            let header = read_at(&file, offset, HEADER_SIZE as usize)?;
+            let header: RangeHeader = bytemuck::pod_read_unaligned(&header);
+
+            if header.magic != LIME_MAGIC {
+                return Err(VmError::new("invalid range header magic"));
+            }
+            if header.version != 1 {
+                return Err(VmError::new("unsupported LiME version"));
+            }
+
+            // The end address is inclusive
+            let end = header.e_addr.checked_add(1).context("invalid range")?;
+            let range_size = end.checked_sub(header.s_addr).context("invalid range")?;
+            offset += HEADER_SIZE;
+
+            mappings.push(MemoryMap {
+                start: PhysicalAddress(header.s_addr),
+                end: PhysicalAddress(end),
+            });
+            remap_at.push(PhysicalAddress(offset));
+
+            offset = offset
+                .checked_add(range_size)
+                .context("range goes past the end of the file")?;
+        }
+
+        if offset > size {
+            return Err(VmError::new("truncated file"));
+        }
+
+        let mem = MemRemap::new(vmc::mem::File::new(file, 0, size), mappings, remap_at);
+        Ok(Self { mem, arch })
+    }
+}
+
+impl<Mem: vmc::Memory> vmc::Memory for Lime<Mem> {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+impl<Mem> vmc::HasVcpus for Lime<Mem> {
+    type Arch = RuntimeArchitecture;
+
+    #[inline]
+    fn arch(&self) -> Self::Arch {
+        self.arch
+    }
+
+    #[inline]
+    fn vcpus_count(&self) -> usize {
+        0
+    }
+
+    #[inline]
+    fn registers(&self, _vcpu: vmc::VcpuId) -> VcpuResult<runtime::Registers> {
+        Err(VcpuError::InvalidId)
+    }
+
+    #[inline]
+    fn special_registers(&self, _vcpu: vmc::VcpuId) -> VcpuResult<runtime::SpecialRegisters> {
+        Err(VcpuError::InvalidId)
+    }
+
+    #[inline]
+    fn other_registers(&self, _vcpu: vmc::VcpuId) -> VcpuResult<runtime::OtherRegisters> {
+        Err(VcpuError::InvalidId)
+    }
+}
+
+impl<Mem: vmc::Memory> vmc::Backend for Lime<Mem> {}
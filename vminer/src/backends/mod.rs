@@ -7,3 +7,71 @@ pub mod kvm;
 
 #[cfg(feature = "dump")]
 pub mod kvm_dump;
+
+#[cfg(feature = "elf_core")]
+pub mod elf_core;
+
+#[cfg(feature = "kdump")]
+pub mod kdump;
+
+#[cfg(feature = "windows_dump")]
+pub mod windows_dump;
+
+#[cfg(feature = "lime")]
+pub mod lime;
+
+#[cfg(feature = "raw")]
+pub mod raw;
+
+#[cfg(feature = "vmware")]
+pub mod vmware;
+
+#[cfg(feature = "firecracker")]
+pub mod firecracker;
+
+#[cfg(feature = "cloud_hypervisor")]
+pub mod cloud_hypervisor;
+
+#[cfg(feature = "gdb")]
+pub mod gdb;
+
+#[cfg(all(unix, feature = "qemu"))]
+pub mod qemu;
+
+#[cfg(feature = "xen")]
+pub mod xen;
+
+#[cfg(feature = "hiberfil")]
+pub mod hiberfil;
+
+#[cfg(any(feature = "kdump", feature = "windows_dump"))]
+mod bitmap;
+#[cfg(any(feature = "gdb", feature = "qemu"))]
+mod mtree;
+#[cfg(any(feature = "firecracker", feature = "cloud_hypervisor"))]
+mod one_reg;
+#[cfg(feature = "dump")]
+mod page_cache;
+#[cfg(any(feature = "dump", feature = "gdb", feature = "qemu"))]
+mod vcpus;
+
+/// Reads `len` bytes at `offset` in a file.
+#[cfg(any(
+    feature = "elf_core",
+    feature = "windows_dump",
+    feature = "lime",
+    feature = "vmware",
+    feature = "hiberfil"
+))]
+fn read_at(
+    mut file: &std::fs::File,
+    offset: u64,
+    len: usize,
+) -> std::io::Result<alloc::vec::Vec<u8>> {
+    use std::io::{Read, Seek};
+
+    let mut buf = alloc::vec![0; len];
+    file.seek(std::io::SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
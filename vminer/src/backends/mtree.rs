@@ -0,0 +1,82 @@
+//! Parsing of the output of QEMU `info mtree -f` monitor command.
+
+use vmc::{PhysicalAddress, mem::MemoryMap};
+
+/// A range of RAM in the guest physical address space.
+#[derive(Debug)]
+pub(crate) struct RamRegion {
+    pub mapping: MemoryMap,
+    /// Name of the memory region backing this range
+    pub name: String,
+    /// Offset of this range in the backing memory region
+    pub offset: u64,
+}
+
+/// Parses RAM ranges of the system address space.
+///
+/// Adjacent ranges are merged if they are also adjacent in their backing
+/// memory region.
+pub(crate) fn ram_regions(output: &str) -> Vec<RamRegion> {
+    let mut regions: Vec<RamRegion> = Vec::new();
+    let mut in_system = false;
+
+    for line in output.lines() {
+        let line = line.trim();
+
+        if line.starts_with("FlatView") {
+            if !regions.is_empty() {
+                break;
+            }
+            in_system = false;
+        } else if line.starts_with("AS \"memory\"") {
+            in_system = true;
+        } else if in_system {
+            // eg "0000000000100000-000000007fffffff (prio 0, ram): pc.ram @0000000000100000 KVM"
+            let Some((range, rest)) = line.split_once(' ') else {
+                continue;
+            };
+            let Some((kind, rest)) = rest.split_once("): ") else {
+                continue;
+            };
+            if !kind.ends_with(", ram") {
+                continue;
+            }
+            let Some((start, end)) = range.split_once('-') else {
+                continue;
+            };
+            let (Ok(start), Ok(end)) =
+                (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16))
+            else {
+                continue;
+            };
+
+            let mut words = rest.split_whitespace();
+            let name = words.next().unwrap_or_default();
+            let offset = words
+                .next()
+                .and_then(|word| word.strip_prefix('@'))
+                .and_then(|offset| u64::from_str_radix(offset, 16).ok())
+                .unwrap_or(0);
+
+            let start = PhysicalAddress(start);
+            let end = PhysicalAddress(end + 1);
+
+            match regions.last_mut() {
+                Some(last)
+                    if last.mapping.end == start
+                        && last.name == name
+                        && last.offset + (last.mapping.end.0 - last.mapping.start.0) == offset =>
+                {
+                    last.mapping.end = end
+                }
+                _ => regions.push(RamRegion {
+                    mapping: MemoryMap { start, end },
+                    name: name.into(),
+                    offset,
+                }),
+            }
+        }
+    }
+
+    regions
+}
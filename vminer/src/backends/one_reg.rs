@@ -0,0 +1,56 @@
+//! Ids of registers for `KVM_{GET,SET}_ONE_REG`, as used in saved states.
+
+use vmc::arch::aarch64;
+
+pub(crate) const KVM_REG_ARM64_CORE: u64 = 0x6030_0000_0010_0000;
+const KVM_REG_ARM64_SYSREG: u64 = 0x6030_0000_0013_0000;
+
+const KVM_REG_SIZE_MASK: u64 = 0x00f0_0000_0000_0000;
+const KVM_REG_SIZE_SHIFT: u64 = 52;
+
+/// Size of the value of a register, in bytes.
+pub(crate) fn size(id: u64) -> usize {
+    1 << ((id & KVM_REG_SIZE_MASK) >> KVM_REG_SIZE_SHIFT)
+}
+
+const fn arm64_core_reg(index: u64) -> u64 {
+    // Core registers ids are their offset in `struct kvm_regs`, in 32-bit words
+    KVM_REG_ARM64_CORE | (index * 2)
+}
+
+const fn arm64_sysreg(op0: u64, op1: u64, crn: u64, crm: u64, op2: u64) -> u64 {
+    KVM_REG_ARM64_SYSREG | (op0 << 14) | (op1 << 11) | (crn << 7) | (crm << 3) | op2
+}
+
+const ARM64_SP: u64 = arm64_core_reg(31);
+const ARM64_PC: u64 = arm64_core_reg(32);
+const ARM64_PSTATE: u64 = arm64_core_reg(33);
+const ARM64_SP_EL1: u64 = arm64_core_reg(34);
+const ARM64_TTBR0_EL1: u64 = arm64_sysreg(3, 0, 2, 0, 0);
+const ARM64_TTBR1_EL1: u64 = arm64_sysreg(3, 0, 2, 0, 1);
+const ARM64_VBAR_EL1: u64 = arm64_sysreg(3, 0, 12, 0, 0);
+
+/// Sets a register from its `KVM_{GET,SET}_ONE_REG` id.
+///
+/// Returns `false` if we don't store this register.
+pub(crate) fn set_aarch64(vcpu: &mut aarch64::Vcpu, id: u64, value: u64) -> bool {
+    let regs = &mut vcpu.registers;
+    let sregs = &mut vcpu.special_registers;
+
+    let reg = match id {
+        ARM64_SP => &mut regs.sp,
+        ARM64_PC => &mut regs.pc,
+        ARM64_PSTATE => &mut regs.pstate,
+        ARM64_SP_EL1 => &mut sregs.sp_el1,
+        ARM64_TTBR0_EL1 => &mut sregs.ttbr0_el1,
+        ARM64_TTBR1_EL1 => &mut sregs.ttbr1_el1,
+        ARM64_VBAR_EL1 => &mut sregs.vbar_el1,
+        _ => match id.checked_sub(KVM_REG_ARM64_CORE) {
+            Some(index) if index % 2 == 0 && index < 62 => &mut regs.regs[index as usize / 2],
+            _ => return false,
+        },
+    };
+
+    *reg = value;
+    true
+}
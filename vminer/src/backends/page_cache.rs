@@ -0,0 +1,45 @@
+//! A small cache of decompressed pages.
+
+use alloc::collections::VecDeque;
+
+/// A least recently used cache of decompressed data, indexed by page (or
+/// block) number.
+#[derive(Debug)]
+pub(crate) struct PageCache {
+    pages: hashbrown::HashMap<u64, Box<[u8]>>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pages: hashbrown::HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn get(&mut self, pfn: u64) -> Option<&[u8]> {
+        let page = self.pages.get(&pfn)?;
+
+        // Move the page to the back of the queue
+        if let Some(i) = self.order.iter().position(|&p| p == pfn) {
+            self.order.remove(i);
+        }
+        self.order.push_back(pfn);
+
+        Some(page)
+    }
+
+    pub fn insert(&mut self, pfn: u64, page: Box<[u8]>) {
+        if self.pages.len() >= self.capacity
+            && let Some(old) = self.order.pop_front()
+        {
+            self.pages.remove(&old);
+        }
+
+        self.pages.insert(pfn, page);
+        self.order.push_back(pfn);
+    }
+}
@@ -0,0 +1,362 @@
+//! QEMU guests with memory shared through a file, introspected over QMP.
+//!
+//! This backend requires QEMU to be started with guest RAM in a shared file
+//! (eg `-object memory-backend-file,id=mem,mem-path=/dev/shm/vm,share=on`)
+//! and a QMP socket (eg `-qmp unix:/run/vm.qmp,server=on,wait=off`). Unlike
+//! the `kvm` backend, it does not need to attach to the QEMU process.
+//!
+//! Shared memory backends are found with `query-memdev` and `qom-get`, the
+//! guest physical memory layout comes from `info mtree -f`, and registers of
+//! all vCPUs from `info registers -a`, read once when connecting. QEMU does not
+//! print all registers there, so `IA32_KERNEL_GS_BASE`, `IA32_LSTAR` and
+//! aarch64 system registers are not available.
+
+use super::{
+    mtree,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+use serde_json::{Value, json};
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+use vmc::{
+    MemoryAccessError, MemoryAccessResult, PhysicalAddress, ResultExt, VmError, VmResult,
+    arch::{aarch64, x86_64},
+    mem::MemoryMap,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A QMP client.
+struct Qmp {
+    stream: BufReader<UnixStream>,
+}
+
+impl Qmp {
+    fn connect(path: &Path) -> VmResult<Self> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("failed to connect to {}", path.display()))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut qmp = Self {
+            stream: BufReader::new(stream),
+        };
+        let greeting = qmp.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(VmError::new("invalid QMP greeting"));
+        }
+        qmp.execute("qmp_capabilities", Value::Null)?;
+
+        Ok(qmp)
+    }
+
+    fn read_message(&mut self) -> VmResult<Value> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(VmError::new("QMP connection closed"));
+        }
+        serde_json::from_str(&line).map_err(VmError::new)
+    }
+
+    fn execute(&mut self, command: &str, arguments: Value) -> VmResult<Value> {
+        let mut request = json!({ "execute": command });
+        if !arguments.is_null() {
+            request["arguments"] = arguments;
+        }
+        writeln!(self.stream.get_mut(), "{request}")?;
+
+        loop {
+            let mut message = self.read_message()?;
+            if let Some(result) = message.get_mut("return") {
+                return Ok(result.take());
+            }
+            if let Some(error) = message.get("error") {
+                let desc = error["desc"].as_str().unwrap_or("unknown error");
+                return Err(VmError::new(format!(
+                    "QMP command {command} failed: {desc}"
+                )));
+            }
+            // Anything else is an asynchronous event
+        }
+    }
+
+    /// Runs a human monitor command and returns its output.
+    fn hmp(&mut self, command: &str) -> VmResult<String> {
+        let output = self.execute("human-monitor-command", json!({ "command-line": command }))?;
+        match output {
+            Value::String(output) => Ok(output),
+            _ => Err(VmError::new("invalid monitor output")),
+        }
+    }
+}
+
+/// Parses a value of `info registers`.
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value, 16).ok()
+}
+
+/// Parses a segment of `info registers`: selector, base, limit and flags,
+/// which are the high half of the descriptor.
+fn parse_x86_64_segment(fields: &str) -> Option<x86_64::Segment> {
+    let mut fields = fields.split_whitespace().map(parse_hex);
+    let selector = fields.next()??;
+    let base = fields.next()??;
+    let limit = fields.next()??;
+    let flags = fields.next()??;
+
+    let bit = |n: u64| ((flags >> n) & 1) as u8;
+    Some(x86_64::Segment {
+        base,
+        limit: limit as u32,
+        selector: selector as u16,
+        type_: ((flags >> 8) & 0xf) as u8,
+        present: bit(15),
+        dpl: ((flags >> 13) & 3) as u8,
+        db: bit(22),
+        s: bit(12),
+        l: bit(21),
+        g: bit(23),
+        avl: bit(20),
+        unusable: bit(15) ^ 1,
+        padding: 0,
+    })
+}
+
+fn parse_x86_64_line(vcpu: &mut x86_64::Vcpu, line: &str) {
+    // Some names are padded, eg "R8 =0000000000000000"
+    let line = line.replace(" =", "=");
+    let Some((first, rest)) = line.split_once('=') else {
+        return;
+    };
+
+    let sregs = &mut vcpu.special_registers;
+    let segment = match first.trim() {
+        "ES" => Some(&mut sregs.es),
+        "CS" => Some(&mut sregs.cs),
+        "SS" => Some(&mut sregs.ss),
+        "DS" => Some(&mut sregs.ds),
+        "FS" => Some(&mut sregs.fs),
+        "GS" => Some(&mut sregs.gs),
+        "LDT" => Some(&mut sregs.ldt),
+        "TR" => Some(&mut sregs.tr),
+        "GDT" | "IDT" => {
+            let table = if first.trim() == "GDT" {
+                &mut sregs.gdt
+            } else {
+                &mut sregs.idt
+            };
+            let mut fields = rest.split_whitespace().filter_map(parse_hex);
+            table.base = fields.next().unwrap_or(0);
+            table.limit = fields.next().unwrap_or(0) as u16;
+            return;
+        }
+        _ => None,
+    };
+    if let Some(segment) = segment {
+        if let Some(value) = parse_x86_64_segment(rest) {
+            *segment = value;
+        }
+        return;
+    }
+
+    for token in line.split_whitespace() {
+        let Some((name, value)) = token.split_once('=') else {
+            continue;
+        };
+        let Some(value) = parse_hex(value) else {
+            continue;
+        };
+
+        let regs = &mut vcpu.registers;
+        let sregs = &mut vcpu.special_registers;
+        let reg = match name {
+            "RAX" => &mut regs.rax,
+            "RBX" => &mut regs.rbx,
+            "RCX" => &mut regs.rcx,
+            "RDX" => &mut regs.rdx,
+            "RSI" => &mut regs.rsi,
+            "RDI" => &mut regs.rdi,
+            "RBP" => &mut regs.rbp,
+            "RSP" => &mut regs.rsp,
+            "R8" => &mut regs.r8,
+            "R9" => &mut regs.r9,
+            "R10" => &mut regs.r10,
+            "R11" => &mut regs.r11,
+            "R12" => &mut regs.r12,
+            "R13" => &mut regs.r13,
+            "R14" => &mut regs.r14,
+            "R15" => &mut regs.r15,
+            "RIP" => &mut regs.rip,
+            "RFL" => &mut regs.rflags,
+            "CR0" => &mut sregs.cr0,
+            "CR2" => &mut sregs.cr2,
+            "CR3" => &mut sregs.cr3,
+            "CR4" => &mut sregs.cr4,
+            "EFER" => &mut sregs.efer,
+            _ => continue,
+        };
+        *reg = value;
+    }
+}
+
+fn parse_aarch64_line(vcpu: &mut aarch64::Vcpu, line: &str) {
+    for token in line.split_whitespace() {
+        let Some((name, value)) = token.split_once('=') else {
+            continue;
+        };
+        let Some(value) = parse_hex(value) else {
+            continue;
+        };
+
+        let regs = &mut vcpu.registers;
+        let reg = match name {
+            "PC" => &mut regs.pc,
+            "SP" => &mut regs.sp,
+            "PSTATE" => &mut regs.pstate,
+            _ => match name.strip_prefix('X').and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n < 31 => &mut regs.regs[n],
+                _ => continue,
+            },
+        };
+        *reg = value;
+    }
+}
+
+/// Parses the output of `info registers -a`.
+///
+/// Registers of each vCPU are preceded by a `CPU#<n>` line.
+fn parse_registers<V: bytemuck::Zeroable>(output: &str, parse_line: fn(&mut V, &str)) -> Vec<V> {
+    let mut vcpus = Vec::new();
+
+    for line in output.lines() {
+        if line.starts_with("CPU#") {
+            vcpus.push(V::zeroed());
+        }
+        if let Some(vcpu) = vcpus.last_mut() {
+            parse_line(vcpu, line);
+        }
+    }
+
+    vcpus
+}
+
+#[derive(Debug)]
+struct Region {
+    file: usize,
+    offset: u64,
+}
+
+#[derive(Debug)]
+pub struct Qemu {
+    files: Vec<vmc::mem::File>,
+    mappings: Vec<MemoryMap>,
+    regions: Vec<Region>,
+    vcpus: Vcpus,
+}
+
+impl Qemu {
+    /// Connects to the QMP socket of a QEMU instance.
+    pub fn connect<P: AsRef<Path>>(qmp_socket: P) -> VmResult<Self> {
+        let mut qmp = Qmp::connect(qmp_socket.as_ref())?;
+
+        // Find memory backends shared through a file
+        let mut files = Vec::new();
+        let mut ids = Vec::new();
+        let memdevs = qmp.execute("query-memdev", Value::Null)?;
+        for memdev in memdevs.as_array().into_iter().flatten() {
+            let Some(id) = memdev["id"].as_str() else {
+                continue;
+            };
+            if memdev["share"].as_bool() != Some(true) {
+                log::debug!("Memory backend {id} is not shared");
+                continue;
+            }
+
+            let path = format!("/objects/{id}");
+            let mem_path = qmp.execute("qom-get", json!({ "path": path, "property": "mem-path" }));
+            let Ok(Value::String(mem_path)) = mem_path else {
+                log::debug!("Memory backend {id} is not backed by a file");
+                continue;
+            };
+
+            let size = memdev["size"]
+                .as_u64()
+                .context("invalid memory backend size")?;
+            let file = vmc::mem::File::open(&mem_path, 0, size)
+                .with_context(|| format!("failed to open {mem_path}"))?;
+            files.push(file);
+            ids.push(id.to_owned());
+        }
+        if files.is_empty() {
+            return Err(VmError::new(
+                "no memory backend is shared with a file, use \"memory-backend-file,share=on\"",
+            ));
+        }
+
+        let mut mappings = Vec::new();
+        let mut regions = Vec::new();
+        for region in mtree::ram_regions(&qmp.hmp("info mtree -f")?) {
+            let Some(file) = ids.iter().position(|id| *id == region.name) else {
+                continue;
+            };
+
+            let size = region.mapping.end.0 - region.mapping.start.0;
+            if region.offset + size > files[file].size() {
+                log::warn!("Memory region {} is out of its backend", region.name);
+                continue;
+            }
+
+            mappings.push(region.mapping);
+            regions.push(Region {
+                file,
+                offset: region.offset,
+            });
+        }
+        if mappings.is_empty() {
+            return Err(VmError::new("shared memory is not mapped in the guest"));
+        }
+
+        let target = qmp.execute("query-target", Value::Null)?;
+        let registers = qmp.hmp("info registers -a")?;
+        let vcpus = match target["arch"].as_str() {
+            Some("x86_64") => Vcpus::X86_64(parse_registers(&registers, parse_x86_64_line)),
+            Some("aarch64") => Vcpus::Aarch64(parse_registers(&registers, parse_aarch64_line)),
+            arch => {
+                let arch = arch.unwrap_or("unknown");
+                return Err(VmError::new(format!("unsupported architecture: {arch}")));
+            }
+        };
+
+        Ok(Self {
+            files,
+            mappings,
+            regions,
+            vcpus,
+        })
+    }
+}
+
+impl vmc::Memory for Qemu {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        &self.mappings
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        let end = addr + buf.len() as u64;
+        let (mapping, region) = (self.mappings.iter().zip(&self.regions))
+            .find(|(mapping, _)| mapping.start <= addr && end <= mapping.end)
+            .ok_or(MemoryAccessError::OutOfBounds)?;
+
+        let offset = region.offset + (addr.0 - mapping.start.0);
+        self.files[region.file].read_physical(PhysicalAddress(offset), buf)
+    }
+}
+
+delegate_vcpus!(Qemu);
+
+impl vmc::Backend for Qemu {}
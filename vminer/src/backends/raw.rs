@@ -0,0 +1,285 @@
+//! Raw physical memory images, described by a small metadata file.
+//!
+//! The metadata ("sidecar") file can be written in JSON or TOML, and is
+//! selected based on its extension. It gives the architecture, the layout of
+//! physical memory in the image and registers of each vCPU. Registers that are
+//! not given are set to zero. Integers can also be written as strings (eg
+//! `"0x1000"`), which is required for large values in TOML and handy in JSON.
+//!
+//! ```toml
+//! arch = "x86_64"
+//!
+//! # Physical memory ranges. `file_offset` defaults to the end of the previous
+//! # range. If there is no mapping, the whole file is mapped at address 0.
+//! [[mappings]]
+//! start = 0x0
+//! end = 0x80000000
+//!
+//! [[mappings]]
+//! start = 0x100000000
+//! end = 0x180000000
+//! file_offset = 0x80000000
+//!
+//! [[vcpus]]
+//! rip = "0xffffffff81000000"
+//! cr3 = 0x10c8a000
+//! gs_kernel_base = "0xffff88807dc00000"
+//! ```
+//!
+//! Without any vCPU, the kernel page directory can still be found from memory
+//! content (see [`vmc::Backend::find_kernel_pgd_from_content`]).
+
+use super::vcpus::{Vcpus, delegate_vcpus};
+use alloc::collections::BTreeMap;
+use std::{fs, path::Path};
+use vmc::{
+    MemoryAccessResult, PhysicalAddress, ResultExt, VmError, VmResult,
+    arch::{aarch64, x86_64},
+    mem::{MemRemap, MemoryMap},
+};
+
+/// An integer, possibly written as a string
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+enum Integer {
+    Int(u64),
+    Str(String),
+}
+
+impl Integer {
+    fn get(&self) -> VmResult<u64> {
+        match self {
+            Integer::Int(n) => Ok(*n),
+            Integer::Str(s) => {
+                let n = match s.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => s.parse(),
+                };
+                n.map_err(|_| VmError::new(format!("invalid integer: \"{s}\"")))
+            }
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mapping {
+    start: Integer,
+    end: Integer,
+    #[serde(default)]
+    file_offset: Option<Integer>,
+}
+
+/// The content of a sidecar file.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Metadata {
+    arch: String,
+    #[serde(default)]
+    mappings: Vec<Mapping>,
+    #[serde(default)]
+    vcpus: Vec<BTreeMap<String, Integer>>,
+}
+
+impl Metadata {
+    /// Reads a sidecar file, in JSON or TOML depending on its extension.
+    pub fn read<P: AsRef<Path>>(path: P) -> VmResult<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&content),
+            Some("toml") => Self::from_toml(&content),
+            _ => Err(VmError::new(
+                "unknown sidecar format, expected .json or .toml",
+            )),
+        }
+    }
+
+    pub fn from_json(content: &str) -> VmResult<Self> {
+        serde_json::from_str(content).map_err(VmError::new)
+    }
+
+    pub fn from_toml(content: &str) -> VmResult<Self> {
+        toml::from_str(content).map_err(VmError::new)
+    }
+
+    fn memory_layout(&self, size: u64) -> VmResult<(Vec<MemoryMap>, Vec<PhysicalAddress>)> {
+        if self.mappings.is_empty() {
+            let mapping = MemoryMap {
+                start: PhysicalAddress(0),
+                end: PhysicalAddress(size),
+            };
+            return Ok((vec![mapping], vec![PhysicalAddress(0)]));
+        }
+
+        let mut mappings = Vec::with_capacity(self.mappings.len());
+        let mut remap_at = Vec::with_capacity(self.mappings.len());
+        let mut offset = 0;
+
+        for mapping in &self.mappings {
+            let start = mapping.start.get()?;
+            let end = mapping.end.get()?;
+            if let Some(file_offset) = &mapping.file_offset {
+                offset = file_offset.get()?;
+            }
+            let mapping_end = end
+                .checked_sub(start)
+                .and_then(|len| offset.checked_add(len))
+                .context("invalid mapping")?;
+            if mapping_end > size {
+                return Err(VmError::new("mapping goes past the end of the file"));
+            }
+
+            mappings.push(MemoryMap {
+                start: PhysicalAddress(start),
+                end: PhysicalAddress(end),
+            });
+            remap_at.push(PhysicalAddress(offset));
+            offset = mapping_end;
+        }
+
+        Ok((mappings, remap_at))
+    }
+
+    fn vcpus(&self) -> VmResult<Vcpus> {
+        match &*self.arch {
+            "x86_64" => {
+                let vcpus = self.vcpus.iter().map(|regs| {
+                    let mut vcpu = bytemuck::Zeroable::zeroed();
+                    set_registers(regs, |name, value| set_x86_64(&mut vcpu, name, value))?;
+                    Ok(vcpu)
+                });
+                Ok(Vcpus::X86_64(vcpus.collect::<VmResult<_>>()?))
+            }
+            "aarch64" => {
+                let vcpus = self.vcpus.iter().map(|regs| {
+                    let mut vcpu = bytemuck::Zeroable::zeroed();
+                    set_registers(regs, |name, value| set_aarch64(&mut vcpu, name, value))?;
+                    Ok(vcpu)
+                });
+                Ok(Vcpus::Aarch64(vcpus.collect::<VmResult<_>>()?))
+            }
+            arch => Err(VmError::new(format!("unsupported architecture: {arch}"))),
+        }
+    }
+}
+
+fn set_registers(
+    regs: &BTreeMap<String, Integer>,
+    mut set: impl FnMut(&str, u64) -> Option<()>,
+) -> VmResult<()> {
+    for (name, value) in regs {
+        set(name, value.get()?).ok_or_else(|| VmError::new(format!("unknown register: {name}")))?;
+    }
+    Ok(())
+}
+
+fn set_x86_64(vcpu: &mut x86_64::Vcpu, name: &str, value: u64) -> Option<()> {
+    let regs = &mut vcpu.registers;
+    let sregs = &mut vcpu.special_registers;
+    let oregs = &mut vcpu.other_registers;
+
+    let reg = match name {
+        "rax" => &mut regs.rax,
+        "rbx" => &mut regs.rbx,
+        "rcx" => &mut regs.rcx,
+        "rdx" => &mut regs.rdx,
+        "rsi" => &mut regs.rsi,
+        "rdi" => &mut regs.rdi,
+        "rsp" => &mut regs.rsp,
+        "rbp" => &mut regs.rbp,
+        "r8" => &mut regs.r8,
+        "r9" => &mut regs.r9,
+        "r10" => &mut regs.r10,
+        "r11" => &mut regs.r11,
+        "r12" => &mut regs.r12,
+        "r13" => &mut regs.r13,
+        "r14" => &mut regs.r14,
+        "r15" => &mut regs.r15,
+        "rip" => &mut regs.rip,
+        "rflags" => &mut regs.rflags,
+        "cr0" => &mut sregs.cr0,
+        "cr2" => &mut sregs.cr2,
+        "cr3" => &mut sregs.cr3,
+        "cr4" => &mut sregs.cr4,
+        "cr8" => &mut sregs.cr8,
+        "efer" => &mut sregs.efer,
+        "apic_base" => &mut sregs.apic_base,
+        "fs_base" => &mut sregs.fs.base,
+        "gs_base" => &mut sregs.gs.base,
+        "lstar" => &mut oregs.lstar,
+        "gs_kernel_base" => &mut oregs.gs_kernel_base,
+        _ => return None,
+    };
+
+    *reg = value;
+    Some(())
+}
+
+fn set_aarch64(vcpu: &mut aarch64::Vcpu, name: &str, value: u64) -> Option<()> {
+    let regs = &mut vcpu.registers;
+    let sregs = &mut vcpu.special_registers;
+
+    let reg = match name {
+        "sp" => &mut regs.sp,
+        "pc" => &mut regs.pc,
+        "pstate" => &mut regs.pstate,
+        "sp_el1" => &mut sregs.sp_el1,
+        "ttbr0_el1" => &mut sregs.ttbr0_el1,
+        "ttbr1_el1" => &mut sregs.ttbr1_el1,
+        "vbar_el1" => &mut sregs.vbar_el1,
+        _ => {
+            let n: usize = name.strip_prefix('x')?.parse().ok()?;
+            regs.regs.get_mut(n)?
+        }
+    };
+
+    *reg = value;
+    Some(())
+}
+
+#[derive(Debug)]
+pub struct RawImage<Mem> {
+    mem: MemRemap<Mem>,
+    vcpus: Vcpus,
+}
+
+impl RawImage<vmc::mem::File> {
+    /// Opens a raw memory image and its sidecar file.
+    pub fn read<P: AsRef<Path>, Q: AsRef<Path>>(image: P, sidecar: Q) -> VmResult<Self> {
+        let metadata = Metadata::read(sidecar)?;
+        let file = fs::File::open(image)?;
+        let size = file.metadata()?.len();
+        Self::new(vmc::mem::File::new(file, 0, size), size, &metadata)
+    }
+}
+
+impl<Mem: vmc::Memory> RawImage<Mem> {
+    /// Creates a backend from raw memory of `size` bytes.
+    pub fn new(mem: Mem, size: u64, metadata: &Metadata) -> VmResult<Self> {
+        let (mappings, remap_at) = metadata.memory_layout(size)?;
+        let vcpus = metadata.vcpus()?;
+
+        Ok(Self {
+            mem: MemRemap::new(mem, mappings, remap_at),
+            vcpus,
+        })
+    }
+}
+
+impl<Mem: vmc::Memory> vmc::Memory for RawImage<Mem> {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+delegate_vcpus!(RawImage<Mem>);
+
+impl<Mem: vmc::Memory> vmc::Backend for RawImage<Mem> {}
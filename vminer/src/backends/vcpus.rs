@@ -0,0 +1,164 @@
+//! Storage for vCPUs registers of backends that read them once, typically
+//! from a file.
+
+use vmc::arch::{self, RuntimeArchitecture, aarch64, runtime, x86_64};
+
+#[derive(Debug)]
+pub(crate) enum Vcpus {
+    X86_64(Vec<x86_64::Vcpu>),
+    Aarch64(Vec<aarch64::Vcpu>),
+}
+
+impl Vcpus {
+    /// Reads registers of all vCPUs of a backend.
+    #[cfg(feature = "dump")]
+    pub fn from_backend<B: vmc::HasVcpus + ?Sized>(backend: &B) -> vmc::VcpuResult<Self> {
+        use vmc::{Architecture, HasVcpus};
+
+        Ok(match backend.arch().into_runtime() {
+            RuntimeArchitecture::X86_64(_) => {
+                let backend = arch::AssumeX86_64(backend);
+                let mut vcpus = Vec::with_capacity(backend.vcpus_count());
+                for vcpu in backend.iter_vcpus() {
+                    vcpus.push(x86_64::Vcpu {
+                        registers: backend.registers(vcpu)?,
+                        special_registers: backend.special_registers(vcpu)?,
+                        other_registers: backend.other_registers(vcpu)?,
+                    })
+                }
+                Vcpus::X86_64(vcpus)
+            }
+            RuntimeArchitecture::Aarch64(_) => {
+                let backend = arch::AssumeAarch64(backend);
+                let mut vcpus = Vec::with_capacity(backend.vcpus_count());
+                for vcpu in backend.iter_vcpus() {
+                    vcpus.push(aarch64::Vcpu {
+                        registers: backend.registers(vcpu)?,
+                        special_registers: backend.special_registers(vcpu)?,
+                        other_registers: backend.other_registers(vcpu)?,
+                    })
+                }
+                Vcpus::Aarch64(vcpus)
+            }
+        })
+    }
+}
+
+impl vmc::HasVcpus for Vcpus {
+    type Arch = RuntimeArchitecture;
+
+    fn arch(&self) -> Self::Arch {
+        match self {
+            Vcpus::X86_64(_) => RuntimeArchitecture::X86_64(arch::X86_64),
+            Vcpus::Aarch64(_) => RuntimeArchitecture::Aarch64(arch::Aarch64),
+        }
+    }
+
+    fn vcpus_count(&self) -> usize {
+        match self {
+            Vcpus::X86_64(vcpus) => vcpus.len(),
+            Vcpus::Aarch64(vcpus) => vcpus.len(),
+        }
+    }
+
+    fn registers(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<runtime::Registers> {
+        Ok(match self {
+            Vcpus::X86_64(vcpus) => runtime::Registers::X86_64(
+                vcpus
+                    .get(vcpu.0)
+                    .ok_or(vmc::VcpuError::InvalidId)?
+                    .registers,
+            ),
+            Vcpus::Aarch64(vcpus) => runtime::Registers::Aarch64(
+                vcpus
+                    .get(vcpu.0)
+                    .ok_or(vmc::VcpuError::InvalidId)?
+                    .registers,
+            ),
+        })
+    }
+
+    fn special_registers(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<runtime::SpecialRegisters> {
+        Ok(match self {
+            Vcpus::X86_64(vcpus) => runtime::SpecialRegisters::X86_64(
+                vcpus
+                    .get(vcpu.0)
+                    .ok_or(vmc::VcpuError::InvalidId)?
+                    .special_registers,
+            ),
+            Vcpus::Aarch64(vcpus) => runtime::SpecialRegisters::Aarch64(
+                vcpus
+                    .get(vcpu.0)
+                    .ok_or(vmc::VcpuError::InvalidId)?
+                    .special_registers,
+            ),
+        })
+    }
+
+    fn other_registers(&self, vcpu: vmc::VcpuId) -> vmc::VcpuResult<runtime::OtherRegisters> {
+        Ok(match self {
+            Vcpus::X86_64(vcpus) => runtime::OtherRegisters::X86_64(
+                vcpus
+                    .get(vcpu.0)
+                    .ok_or(vmc::VcpuError::InvalidId)?
+                    .other_registers,
+            ),
+            Vcpus::Aarch64(vcpus) => runtime::OtherRegisters::Aarch64(
+                vcpus
+                    .get(vcpu.0)
+                    .ok_or(vmc::VcpuError::InvalidId)?
+                    .other_registers,
+            ),
+        })
+    }
+}
+
+/// Implements `vmc::HasVcpus` for a backend by delegating to its `vcpus` field.
+macro_rules! delegate_vcpus {
+    ($ty:ident $(< $($param:ident),* >)?) => {
+        impl $(< $($param),* >)? vmc::HasVcpus for $ty $(< $($param),* >)? {
+            type Arch = vmc::arch::RuntimeArchitecture;
+
+            #[inline]
+            fn arch(&self) -> Self::Arch {
+                self.vcpus.arch()
+            }
+
+            #[inline]
+            fn vcpus_count(&self) -> usize {
+                self.vcpus.vcpus_count()
+            }
+
+            #[inline]
+            fn registers(
+                &self,
+                vcpu: vmc::VcpuId,
+            ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::Registers> {
+                self.vcpus.registers(vcpu)
+            }
+
+            #[inline]
+            fn special_registers(
+                &self,
+                vcpu: vmc::VcpuId,
+            ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::SpecialRegisters> {
+                self.vcpus.special_registers(vcpu)
+            }
+
+            #[inline]
+            fn other_registers(
+                &self,
+                vcpu: vmc::VcpuId,
+            ) -> vmc::VcpuResult<<Self::Arch as vmc::Architecture>::OtherRegisters> {
+                self.vcpus.other_registers(vcpu)
+            }
+
+            #[inline]
+            fn register_by_name(&self, vcpu: vmc::VcpuId, name: &str) -> vmc::VcpuResult<u64> {
+                self.vcpus.register_by_name(vcpu, name)
+            }
+        }
+    };
+}
+
+pub(crate) use delegate_vcpus;
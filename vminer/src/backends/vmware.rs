@@ -0,0 +1,357 @@
+//! VMware suspended VMs and snapshots.
+//!
+//! A `.vmss` (suspend) or `.vmsn` (snapshot) state file is a list of groups
+//! of tagged values. The `memory` group describes how guest physical memory
+//! is laid out in the memory image, which is either the `.vmem` file next to
+//! the state file or a block embedded in the state file itself, and the `cpu`
+//! group contains registers of each vCPU.
+//!
+//! Only x86_64 guests are supported.
+
+use super::{
+    read_at,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+use hashbrown::HashMap;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use vmc::{
+    MemoryAccessResult, PhysicalAddress, ResultExt, VmError, VmResult,
+    arch::x86_64::{Segment, Vcpu},
+    mem::{MemRemap, MemoryMap},
+};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+const MAGICS_32: &[u32] = &[0xbad1_bad1, 0xbed2_bed0, 0xbed2_bed2];
+const MAGIC_64: u32 = 0xbed3_bed3;
+
+const GROUP_SIZE: usize = 80;
+const GROUP_NAME_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+enum TagData {
+    /// A small value, stored directly in the tag
+    Value(u64),
+
+    /// A block of data somewhere in the file
+    Block {
+        offset: u64,
+        size: u64,
+        mem_size: u64,
+    },
+}
+
+type TagKey = (String, String, Vec<u32>);
+
+/// Values of all tags of a state file.
+#[derive(Debug)]
+struct Tags(HashMap<TagKey, TagData>);
+
+impl Tags {
+    fn read(file: &fs::File) -> VmResult<Self> {
+        let header = read_at(file, 0, 12)?;
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let group_count = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+
+        // Sizes of data blocks are 64-bit in recent versions
+        let size_len = if magic == MAGIC_64 {
+            8
+        } else if MAGICS_32.contains(&magic) {
+            4
+        } else {
+            return Err(VmError::new("invalid VMware state file magic"));
+        };
+
+        let groups = read_at(file, 12, group_count * GROUP_SIZE)?;
+        let mut tags = HashMap::new();
+
+        for group in groups.chunks_exact(GROUP_SIZE) {
+            let name = &group[..GROUP_NAME_SIZE];
+            let name = &name[..memchr::memchr(0, name).unwrap_or(name.len())];
+            let name = String::from_utf8_lossy(name).into_owned();
+            let tags_offset = u64::from_le_bytes(group[64..72].try_into().unwrap());
+
+            read_group_tags(file, &name, tags_offset, size_len, &mut tags)
+                .with_context(|| format!("failed to read tags of group \"{name}\""))?;
+        }
+
+        Ok(Self(tags))
+    }
+
+    fn get(&self, group: &str, name: &str, indices: &[u32]) -> Option<TagData> {
+        let key = (group.into(), name.into(), indices.to_vec());
+        self.0.get(&key).copied()
+    }
+
+    fn value(&self, group: &str, name: &str, indices: &[u32]) -> Option<u64> {
+        match self.get(group, name, indices)? {
+            TagData::Value(value) => Some(value),
+            TagData::Block { .. } => None,
+        }
+    }
+}
+
+fn read_group_tags(
+    file: &fs::File,
+    group: &str,
+    mut offset: u64,
+    size_len: u64,
+    tags: &mut HashMap<TagKey, TagData>,
+) -> VmResult<()> {
+    loop {
+        let flags = read_at(file, offset, 1)?[0];
+        if flags == 0 {
+            break;
+        }
+        let name_len = read_at(file, offset + 1, 1)?[0] as u64;
+        offset += 2;
+
+        let name = read_at(file, offset, name_len as usize)?;
+        let name = String::from_utf8_lossy(&name).into_owned();
+        offset += name_len;
+
+        let indices_count = (flags >> 6) as usize;
+        let indices = read_at(file, offset, indices_count * 4)?;
+        let indices = indices
+            .chunks_exact(4)
+            .map(|index| u32::from_le_bytes(index.try_into().unwrap()))
+            .collect();
+        offset += indices_count as u64 * 4;
+
+        let data_len = (flags & 0x3f) as u64;
+        let data = if data_len >= 62 {
+            // Large data: the sizes on disk and in memory, then two bytes of
+            // padding, then data.
+            let sizes = read_at(file, offset, 2 * size_len as usize)?;
+            let (size, mem_size) = if size_len == 8 {
+                (
+                    u64::from_le_bytes(sizes[0..8].try_into().unwrap()),
+                    u64::from_le_bytes(sizes[8..16].try_into().unwrap()),
+                )
+            } else {
+                (
+                    u32::from_le_bytes(sizes[0..4].try_into().unwrap()) as u64,
+                    u32::from_le_bytes(sizes[4..8].try_into().unwrap()) as u64,
+                )
+            };
+            offset += 2 * size_len + 2;
+
+            let data = TagData::Block {
+                offset,
+                size,
+                mem_size,
+            };
+            offset += size;
+            data
+        } else {
+            let mut value = [0; 8];
+            let bytes = read_at(file, offset, data_len as usize)?;
+            let len = bytes.len().min(8);
+            value[..len].copy_from_slice(&bytes[..len]);
+            offset += data_len;
+            TagData::Value(u64::from_le_bytes(value))
+        };
+
+        tags.insert((group.into(), name, indices), data);
+    }
+
+    Ok(())
+}
+
+/// Reads registers of all vCPUs from the `cpu` group.
+fn read_vcpus(tags: &Tags) -> Vec<Vcpu> {
+    let mut vcpus = Vec::new();
+
+    for cpu in 0.. {
+        let Some(rip) = tags.value("cpu", "rip", &[cpu]) else {
+            break;
+        };
+
+        let mut missing = Vec::new();
+        let mut get = |name: &'static str, indices: &[u32]| {
+            tags.value("cpu", name, indices).unwrap_or_else(|| {
+                missing.push(name);
+                0
+            })
+        };
+
+        let mut vcpu: Vcpu = bytemuck::Zeroable::zeroed();
+
+        // General purpose registers are stored in encoding order
+        let regs = &mut vcpu.registers;
+        let gprs = [
+            &mut regs.rax,
+            &mut regs.rcx,
+            &mut regs.rdx,
+            &mut regs.rbx,
+            &mut regs.rsp,
+            &mut regs.rbp,
+            &mut regs.rsi,
+            &mut regs.rdi,
+            &mut regs.r8,
+            &mut regs.r9,
+            &mut regs.r10,
+            &mut regs.r11,
+            &mut regs.r12,
+            &mut regs.r13,
+            &mut regs.r14,
+            &mut regs.r15,
+        ];
+        for (i, reg) in (0..).zip(gprs) {
+            *reg = get("gpregs", &[cpu, i]);
+        }
+        regs.rip = rip;
+        regs.rflags = get("eflags", &[cpu]);
+
+        let sregs = &mut vcpu.special_registers;
+        sregs.cr0 = get("CR64", &[cpu, 0]);
+        sregs.cr2 = get("CR64", &[cpu, 2]);
+        sregs.cr3 = get("CR64", &[cpu, 3]);
+        sregs.cr4 = get("CR64", &[cpu, 4]);
+        sregs.efer = get("EFER", &[cpu]);
+
+        // Segments are stored in encoding order too
+        let segments = [
+            &mut sregs.es,
+            &mut sregs.cs,
+            &mut sregs.ss,
+            &mut sregs.ds,
+            &mut sregs.fs,
+            &mut sregs.gs,
+        ];
+        for (i, segment) in (0..).zip(segments) {
+            let selector = get("S", &[cpu, i]) as u16;
+            *segment = Segment {
+                selector,
+                base: get("SBase", &[cpu, i]),
+                dpl: (selector & 3) as u8,
+                present: 1,
+                ..bytemuck::Zeroable::zeroed()
+            };
+        }
+
+        vcpu.other_registers.gs_kernel_base = get("KernelGSBase", &[cpu]);
+        vcpu.other_registers.lstar = get("LSTAR", &[cpu]);
+
+        if !missing.is_empty() {
+            missing.dedup();
+            log::warn!("Missing registers for vCPU {cpu}: {}", missing.join(", "));
+        }
+
+        vcpus.push(vcpu);
+    }
+
+    vcpus
+}
+
+/// A suspended VMware VM or a snapshot.
+#[derive(Debug)]
+pub struct Vmware {
+    mem: MemRemap<vmc::mem::File>,
+    vcpus: Vcpus,
+}
+
+impl Vmware {
+    /// Opens a state file.
+    ///
+    /// If memory is not embedded in the state file, it is read from the
+    /// `.vmem` file with the same name.
+    pub fn read<P: AsRef<Path>>(state: P) -> VmResult<Self> {
+        let state = state.as_ref();
+        Self::read_inner(state, None)
+    }
+
+    /// Opens a state file, with memory in the given `.vmem` file.
+    pub fn with_vmem<P: AsRef<Path>, Q: AsRef<Path>>(state: P, vmem: Q) -> VmResult<Self> {
+        Self::read_inner(state.as_ref(), Some(vmem.as_ref().to_owned()))
+    }
+
+    fn read_inner(state: &Path, vmem: Option<PathBuf>) -> VmResult<Self> {
+        let state_file = fs::File::open(state)?;
+        let tags = Tags::read(&state_file)?;
+
+        // Memory is either in the state file or in the .vmem file
+        let (mem_file, mem_offset, mem_size) = match tags.get("memory", "Memory", &[0, 0]) {
+            Some(TagData::Block {
+                offset,
+                size,
+                mem_size,
+            }) if vmem.is_none() => {
+                if size != mem_size {
+                    return Err(VmError::new("compressed memory is not supported"));
+                }
+                (state_file, offset, size)
+            }
+            _ => {
+                let vmem = vmem.unwrap_or_else(|| state.with_extension("vmem"));
+                let file = fs::File::open(&vmem)
+                    .with_context(|| format!("failed to open {}", vmem.display()))?;
+                let size = file.metadata()?.len();
+                (file, 0, size)
+            }
+        };
+
+        let mut mappings = Vec::new();
+        let mut remap_at = Vec::new();
+
+        match tags.value("memory", "regionsCount", &[]) {
+            Some(count) if count != 0 => {
+                for i in 0..count as u32 {
+                    let region = |name| {
+                        tags.value("memory", name, &[i])
+                            .with_context(|| format!("missing memory region tag: {name}"))
+                    };
+                    let start = region("regionPPN")? * PAGE_SIZE;
+                    let file_offset = region("regionPageNum")? * PAGE_SIZE;
+                    let size = region("regionSize")? * PAGE_SIZE;
+
+                    mappings.push(MemoryMap {
+                        start: PhysicalAddress(start),
+                        end: PhysicalAddress(start + size),
+                    });
+                    remap_at.push(PhysicalAddress(mem_offset + file_offset));
+                }
+            }
+            _ => {
+                mappings.push(MemoryMap {
+                    start: PhysicalAddress(0),
+                    end: PhysicalAddress(mem_size),
+                });
+                remap_at.push(PhysicalAddress(mem_offset));
+            }
+        }
+
+        let file_size = mem_file.metadata()?.len();
+        let mem = vmc::mem::File::new(mem_file, 0, file_size);
+        let mem = MemRemap::new(mem, mappings, remap_at);
+
+        let vcpus = read_vcpus(&tags);
+        if vcpus.is_empty() {
+            log::warn!("No vCPU found in state file");
+        }
+
+        Ok(Self {
+            mem,
+            vcpus: Vcpus::X86_64(vcpus),
+        })
+    }
+}
+
+impl vmc::Memory for Vmware {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+delegate_vcpus!(Vmware);
+
+impl vmc::Backend for Vmware {}
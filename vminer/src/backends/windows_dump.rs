@@ -0,0 +1,392 @@
+//! Windows crash dumps (`MEMORY.DMP`), as written by the kernel on a bug
+//! check or by tools such as WinDbg's `.dump /f`.
+//!
+//! Only 64-bit dumps are supported, with either the full layout (physical
+//! memory runs stored one after the other) or the bitmap layout used by
+//! kernel and "bitmap full" dumps.
+
+use super::{
+    bitmap::Bitmap,
+    read_at,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+use std::{fs, path::Path};
+use vmc::{
+    Architecture, Memory, MemoryAccessError, MemoryAccessResult, PhysicalAddress, VirtualAddress,
+    VmError, VmResult,
+    arch::x86_64::{Registers, Segment, SpecialRegisters, Vcpu},
+    mem::MemoryMap,
+};
+
+const PAGE_SIZE: u64 = 0x1000;
+const HEADER_SIZE: usize = 0x2000;
+
+const IMAGE_FILE_MACHINE_AMD64: u32 = 0x8664;
+
+const DUMP_TYPE_FULL: u32 = 1;
+const DUMP_TYPE_SUMMARY: u32 = 2;
+const DUMP_TYPE_BITMAP_FULL: u32 = 5;
+const DUMP_TYPE_BITMAP_KERNEL: u32 = 6;
+
+/// Offsets of the fields we need in `DUMP_HEADER64`
+mod header {
+    pub const SIGNATURE: usize = 0x000;
+    pub const VALID_DUMP: usize = 0x004;
+    pub const DIRECTORY_TABLE_BASE: usize = 0x010;
+    pub const MACHINE_IMAGE_TYPE: usize = 0x030;
+    pub const NUMBER_PROCESSORS: usize = 0x034;
+    pub const KD_DEBUGGER_DATA_BLOCK: usize = 0x080;
+    pub const PHYSICAL_MEMORY_BLOCK: usize = 0x088;
+    pub const PHYSICAL_MEMORY_BLOCK_SIZE: usize = 700;
+    pub const CONTEXT_RECORD: usize = 0x348;
+    pub const DUMP_TYPE: usize = 0xf98;
+}
+
+/// Offsets of the fields we need in `KDDEBUGGER_DATA64`
+mod kdbg {
+    pub const OWNER_TAG: u64 = 0x10;
+    pub const KERN_BASE: u64 = 0x18;
+    pub const KI_PROCESSOR_BLOCK: u64 = 0x218;
+}
+
+/// Offset of the `Prcb` field in `KPCR`
+const KPCR_PRCB: u64 = 0x180;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Where pages are stored in the file
+#[derive(Debug)]
+enum Pages {
+    /// Each physical memory run is stored contiguously, at the given offset.
+    Runs(Vec<u64>),
+
+    /// Pages set in the bitmap are stored contiguously from `first_page`.
+    Bitmap { first_page: u64, bitmap: Bitmap },
+}
+
+#[derive(Debug)]
+struct DumpMemory {
+    file: vmc::mem::File,
+    runs: Vec<MemoryMap>,
+    pages: Pages,
+}
+
+impl DumpMemory {
+    fn read(file: &fs::File, header: &[u8], dump_type: u32) -> VmResult<Self> {
+        let block = &header[header::PHYSICAL_MEMORY_BLOCK..][..header::PHYSICAL_MEMORY_BLOCK_SIZE];
+        let runs_count = read_u32(block, 0) as usize;
+        if 16 + runs_count * 16 > block.len() {
+            return Err(VmError::new("invalid physical memory descriptor"));
+        }
+
+        let runs: Vec<_> = (0..runs_count)
+            .map(|i| {
+                let base_page = read_u64(block, 16 + i * 16);
+                let page_count = read_u64(block, 16 + i * 16 + 8);
+                MemoryMap {
+                    start: PhysicalAddress(base_page * PAGE_SIZE),
+                    end: PhysicalAddress((base_page + page_count) * PAGE_SIZE),
+                }
+            })
+            .collect();
+
+        let pages = match dump_type {
+            DUMP_TYPE_FULL => {
+                let mut offset = HEADER_SIZE as u64;
+                let offsets = runs
+                    .iter()
+                    .map(|run| {
+                        let current = offset;
+                        offset += run.end.0 - run.start.0;
+                        current
+                    })
+                    .collect();
+                Pages::Runs(offsets)
+            }
+            DUMP_TYPE_SUMMARY | DUMP_TYPE_BITMAP_FULL | DUMP_TYPE_BITMAP_KERNEL => {
+                let bmp_header = read_at(file, HEADER_SIZE as u64, 0x38)?;
+                if !matches!(&bmp_header[..4], b"SDMP" | b"FDMP") || &bmp_header[4..8] != b"DUMP" {
+                    return Err(VmError::new("invalid bitmap header"));
+                }
+                let first_page = read_u64(&bmp_header, 0x20);
+                let pages_count = read_u64(&bmp_header, 0x30);
+
+                let bitmap = read_at(
+                    file,
+                    HEADER_SIZE as u64 + 0x38,
+                    pages_count.div_ceil(8) as usize,
+                )?;
+                Pages::Bitmap {
+                    first_page,
+                    bitmap: Bitmap::new(&bitmap),
+                }
+            }
+            _ => return Err(VmError::new("unsupported dump type")),
+        };
+
+        let size = file.metadata()?.len();
+        let file = vmc::mem::File::new(file.try_clone()?, 0, size);
+
+        Ok(Self { file, runs, pages })
+    }
+
+    /// Returns the offset of a page in the file, if it is present.
+    fn page_offset(&self, pfn: u64) -> Option<u64> {
+        match &self.pages {
+            Pages::Runs(offsets) => {
+                let addr = PhysicalAddress(pfn * PAGE_SIZE);
+                let i = self.runs.partition_point(|run| run.end <= addr);
+                let run = self.runs.get(i).filter(|run| run.start <= addr)?;
+                Some(offsets[i] + (addr.0 - run.start.0))
+            }
+            Pages::Bitmap { first_page, bitmap } => {
+                Some(first_page + bitmap.rank(pfn)? * PAGE_SIZE)
+            }
+        }
+    }
+}
+
+impl Memory for DumpMemory {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        &self.runs
+    }
+
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        let start = addr.0 / PAGE_SIZE;
+        let end = (addr.0 + size as u64).div_ceil(PAGE_SIZE);
+        (start..end).all(|pfn| self.page_offset(pfn).is_some())
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        let mut addr = addr.0;
+        let mut buf = buf;
+
+        while !buf.is_empty() {
+            let offset = addr % PAGE_SIZE;
+            let len = core::cmp::min(buf.len(), (PAGE_SIZE - offset) as usize);
+            let (start, rest) = buf.split_at_mut(len);
+
+            // Pages excluded from the dump are not in the file
+            let page = self
+                .page_offset(addr / PAGE_SIZE)
+                .ok_or(MemoryAccessError::OutOfBounds)?;
+            self.file
+                .read_physical(PhysicalAddress(page + offset), start)?;
+
+            buf = rest;
+            addr += len as u64;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the `CONTEXT` structure saved in the dump header.
+fn read_context(context: &[u8]) -> Vcpu {
+    let gpr = |i: usize| read_u64(context, 0x78 + i * 8);
+
+    let segment = |offset| {
+        let selector = read_u16(context, offset);
+        Segment {
+            selector,
+            dpl: (selector & 3) as u8,
+            present: 1,
+            ..bytemuck::Zeroable::zeroed()
+        }
+    };
+
+    let registers = Registers {
+        rax: gpr(0),
+        rcx: gpr(1),
+        rdx: gpr(2),
+        rbx: gpr(3),
+        rsp: gpr(4),
+        rbp: gpr(5),
+        rsi: gpr(6),
+        rdi: gpr(7),
+        r8: gpr(8),
+        r9: gpr(9),
+        r10: gpr(10),
+        r11: gpr(11),
+        r12: gpr(12),
+        r13: gpr(13),
+        r14: gpr(14),
+        r15: gpr(15),
+        rip: read_u64(context, 0xf8),
+        rflags: read_u32(context, 0x44) as u64,
+    };
+
+    let special_registers = SpecialRegisters {
+        cs: segment(0x38),
+        ds: segment(0x3a),
+        es: segment(0x3c),
+        fs: segment(0x3e),
+        gs: segment(0x40),
+        ss: segment(0x42),
+        ..bytemuck::Zeroable::zeroed()
+    };
+
+    Vcpu {
+        registers,
+        special_registers,
+        other_registers: bytemuck::Zeroable::zeroed(),
+    }
+}
+
+/// A Windows crash dump file.
+///
+/// The dump only contains the context of the processor that triggered the
+/// crash, which is reported as the first vCPU. Other vCPUs only have their
+/// page directory and per-CPU data (`KPCR`) filled in.
+#[derive(Debug)]
+pub struct WindowsDump {
+    mem: DumpMemory,
+    vcpus: Vcpus,
+    kpgd: PhysicalAddress,
+    kernel_base: Option<VirtualAddress>,
+}
+
+impl WindowsDump {
+    pub fn read<P: AsRef<Path>>(path: P) -> VmResult<Self> {
+        let file = fs::File::open(path)?;
+        Self::from_file(file)
+    }
+
+    pub fn from_file(file: fs::File) -> VmResult<Self> {
+        let header = read_at(&file, 0, HEADER_SIZE)?;
+
+        match (
+            &header[header::SIGNATURE..][..4],
+            &header[header::VALID_DUMP..][..4],
+        ) {
+            (b"PAGE", b"DU64") => (),
+            (b"PAGE", b"DUMP") => return Err(VmError::new("32-bit dumps are not supported")),
+            _ => return Err(VmError::new("invalid file signature")),
+        }
+
+        if read_u32(&header, header::MACHINE_IMAGE_TYPE) != IMAGE_FILE_MACHINE_AMD64 {
+            return Err(VmError::new("unsupported architecture"));
+        }
+
+        let dump_type = read_u32(&header, header::DUMP_TYPE);
+        let mem = DumpMemory::read(&file, &header, dump_type)?;
+
+        let kpgd = PhysicalAddress(read_u64(&header, header::DIRECTORY_TABLE_BASE) & !0xfff);
+        let kdbg = VirtualAddress(read_u64(&header, header::KD_DEBUGGER_DATA_BLOCK));
+
+        let read_u64_virtual = |addr: VirtualAddress| -> Option<u64> {
+            let addr = vmc::arch::X86_64
+                .virtual_to_physical(&mem, kpgd, addr)
+                .ok()?;
+            let mut value = [0; 8];
+            mem.read_physical(addr, &mut value).ok()?;
+            Some(u64::from_le_bytes(value))
+        };
+
+        // The debugger data block is stored decoded in crash dumps
+        let kdbg = read_u64_virtual(kdbg + kdbg::OWNER_TAG)
+            .filter(|tag| tag.to_le_bytes()[..4] == *b"KDBG")
+            .map(|_| kdbg);
+        if kdbg.is_none() {
+            log::warn!("Failed to read KdDebuggerDataBlock");
+        }
+
+        let kernel_base = kdbg
+            .and_then(|kdbg| read_u64_virtual(kdbg + kdbg::KERN_BASE))
+            .map(VirtualAddress);
+        let processor_block =
+            kdbg.and_then(|kdbg| read_u64_virtual(kdbg + kdbg::KI_PROCESSOR_BLOCK));
+
+        let vcpus_count = read_u32(&header, header::NUMBER_PROCESSORS) as u64;
+        let context = read_context(&header[header::CONTEXT_RECORD..]);
+
+        let vcpus = (0..vcpus_count)
+            .map(|i| {
+                let mut vcpu = if i == 0 {
+                    context
+                } else {
+                    bytemuck::Zeroable::zeroed()
+                };
+
+                vcpu.special_registers.cr3 = kpgd.0;
+                // Long mode with paging enabled
+                vcpu.special_registers.cr0 |= 0x8000_0001;
+                vcpu.special_registers.cr4 |= 0x20;
+                vcpu.special_registers.efer |= 0x500;
+
+                let kprcb = processor_block
+                    .and_then(|block| read_u64_virtual(VirtualAddress(block) + i * 8));
+                if let Some(kprcb) = kprcb {
+                    vcpu.special_registers.gs.base = kprcb - KPCR_PRCB;
+                }
+
+                vcpu
+            })
+            .collect();
+
+        Ok(Self {
+            mem,
+            vcpus: Vcpus::X86_64(vcpus),
+            kpgd,
+            kernel_base,
+        })
+    }
+
+    /// Returns the base address of the kernel, if the dump has it.
+    #[inline]
+    pub fn kernel_base(&self) -> Option<VirtualAddress> {
+        self.kernel_base
+    }
+
+    /// Returns an `OsBuilder` with the kernel page directory and base address
+    /// found in the dump, so that they don't have to be searched for.
+    #[cfg(feature = "windows")]
+    pub fn os_builder(&self) -> crate::os::OsBuilder {
+        let mut builder = crate::os::OsBuilder::new().with_kpgd(self.kpgd);
+        if let Some(base) = self.kernel_base {
+            builder = builder.with_kaslr(base);
+        }
+        builder
+    }
+}
+
+impl Memory for WindowsDump {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    #[inline]
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        self.mem.is_valid(addr, size)
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+delegate_vcpus!(WindowsDump);
+
+impl vmc::Backend for WindowsDump {
+    /// The kernel page directory is saved in the dump header.
+    fn find_kernel_pgd(
+        &self,
+        _use_per_cpu: bool,
+        _additional: &[VirtualAddress],
+    ) -> VmResult<PhysicalAddress> {
+        Ok(self.kpgd)
+    }
+}
@@ -0,0 +1,341 @@
+//! Core files of Xen guests, produced by `xl dump-core`.
+//!
+//! These are ELF files without program headers. Guest pages are stored in
+//! order in the `.xen_pages` section, and the `.xen_pfn` section gives the
+//! guest frame number of each of them. vCPUs contexts are stored in the
+//! `.xen_prstatus` section as `vcpu_guest_context` structures.
+//!
+//! Only HVM guests are supported: page tables of PV guests reference machine
+//! frames, which are not part of the dump.
+
+use bytemuck::{Pod, Zeroable};
+use object::{
+    LittleEndian as LE, elf,
+    read::elf::{FileHeader, SectionHeader},
+};
+use std::{fs, path::Path};
+use vmc::{
+    PhysicalAddress, ResultExt, VmError, VmResult,
+    arch::x86_64::{OtherRegisters, Registers, Vcpu},
+    mem::MemoryMap,
+};
+
+use super::{
+    elf_core::read_note,
+    read_at,
+    vcpus::{Vcpus, delegate_vcpus},
+};
+
+type FileHeader64 = elf::FileHeader64<LE>;
+type SectionHeader64 = elf::SectionHeader64<LE>;
+
+const XEN_ELFNOTE_DUMPCORE_HEADER: u32 = 0x2000001;
+
+const XC_CORE_MAGIC: u64 = 0xf00febed;
+const XC_CORE_MAGIC_HVM: u64 = 0xf00febee;
+
+/// Frame numbers of pages that could not be dumped
+const INVALID_PFN: u64 = !0;
+
+/// `struct xen_dumpcore_elfnote_header_desc`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct DumpcoreHeader {
+    magic: u64,
+    nr_vcpus: u64,
+    nr_pages: u64,
+    page_size: u64,
+}
+
+/// Offsets of the fields we need in x86_64 `struct vcpu_guest_context`
+mod context {
+    pub const SIZE: usize = 5168;
+    pub const USER_REGS: usize = 520;
+    pub const CTRLREG: usize = 4984;
+    pub const FS_BASE: usize = 5144;
+}
+
+/// x86_64 `struct cpu_user_regs`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct CpuUserRegs {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rax: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    error_code: u32,
+    entry_vector: u32,
+    rip: u64,
+    cs: u16,
+    _pad0: u16,
+    saved_upcall_mask: u8,
+    _pad1: [u8; 3],
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+    es: u64,
+    ds: u64,
+    fs: u64,
+    gs: u64,
+}
+
+/// Segment bases at the end of `struct vcpu_guest_context`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct SegmentBases {
+    fs_base: u64,
+    gs_base_kernel: u64,
+    gs_base_user: u64,
+}
+
+fn read_vcpu(ctxt: &[u8]) -> Vcpu {
+    let regs: CpuUserRegs = read_note(ctxt, context::USER_REGS);
+    let ctrlreg: [u64; 8] = read_note(ctxt, context::CTRLREG);
+    let bases: SegmentBases = read_note(ctxt, context::FS_BASE);
+
+    let mut vcpu = Vcpu::zeroed();
+
+    vcpu.registers = Registers {
+        rax: regs.rax,
+        rbx: regs.rbx,
+        rcx: regs.rcx,
+        rdx: regs.rdx,
+        rsi: regs.rsi,
+        rdi: regs.rdi,
+        rsp: regs.rsp,
+        rbp: regs.rbp,
+        r8: regs.r8,
+        r9: regs.r9,
+        r10: regs.r10,
+        r11: regs.r11,
+        r12: regs.r12,
+        r13: regs.r13,
+        r14: regs.r14,
+        r15: regs.r15,
+        rip: regs.rip,
+        rflags: regs.rflags,
+    };
+
+    let sregs = &mut vcpu.special_registers;
+    sregs.cs.selector = regs.cs;
+    sregs.ds.selector = regs.ds as u16;
+    sregs.es.selector = regs.es as u16;
+    sregs.ss.selector = regs.ss as u16;
+    sregs.fs.selector = regs.fs as u16;
+    sregs.fs.base = bases.fs_base;
+    sregs.gs.selector = regs.gs as u16;
+    sregs.cr0 = ctrlreg[0];
+    sregs.cr2 = ctrlreg[2];
+    sregs.cr3 = ctrlreg[3];
+    sregs.cr4 = ctrlreg[4];
+
+    // Xen stores the kernel and user GS bases instead of the current and the
+    // shadow ones, so we have to swap them back depending on the current ring.
+    let (gs_base, gs_kernel_base) = if regs.cs & 3 == 0 {
+        (bases.gs_base_kernel, bases.gs_base_user)
+    } else {
+        (bases.gs_base_user, bases.gs_base_kernel)
+    };
+    sregs.gs.base = gs_base;
+    vcpu.other_registers = OtherRegisters {
+        lstar: 0,
+        gs_kernel_base,
+    };
+
+    vcpu
+}
+
+/// Builds the physical memory map from the frame number of each page.
+///
+/// Pages are merged when they are contiguous both in the guest and in the
+/// file.
+fn build_mappings(
+    pfns: &[u64],
+    page_size: u64,
+    pages_offset: u64,
+) -> (Vec<MemoryMap>, Vec<PhysicalAddress>) {
+    let mut pages: Vec<(u64, u64)> = (0..)
+        .zip(pfns)
+        .filter(|&(_, &pfn)| pfn != INVALID_PFN)
+        .map(|(i, &pfn)| (pfn, i))
+        .collect();
+    pages.sort_unstable();
+
+    let mut mappings: Vec<MemoryMap> = Vec::new();
+    let mut remap_at: Vec<PhysicalAddress> = Vec::new();
+
+    for (pfn, i) in pages {
+        let start = PhysicalAddress(pfn * page_size);
+        let offset = PhysicalAddress(pages_offset + i * page_size);
+
+        if let (Some(last), Some(last_offset)) = (mappings.last_mut(), remap_at.last())
+            && last.end == start
+            && last_offset.0 + (last.end.0 - last.start.0) == offset.0
+        {
+            last.end = start + page_size;
+            continue;
+        }
+
+        mappings.push(MemoryMap {
+            start,
+            end: start + page_size,
+        });
+        remap_at.push(offset);
+    }
+
+    (mappings, remap_at)
+}
+
+#[derive(Debug)]
+pub struct XenCore {
+    vcpus: Vcpus,
+    mem: vmc::mem::MemRemap<vmc::mem::File>,
+}
+
+impl XenCore {
+    pub fn read<P: AsRef<Path>>(path: P) -> VmResult<Self> {
+        let file = fs::File::open(path)?;
+        Self::from_file(file)
+    }
+
+    pub fn from_file(file: fs::File) -> VmResult<Self> {
+        let header = read_at(&file, 0, size_of::<FileHeader64>())?;
+        let header = object::pod::from_bytes::<FileHeader64>(&header)
+            .map_err(|()| VmError::new("unexpected end of ELF file"))?
+            .0;
+
+        if !header.is_class_64() || !header.is_little_endian() {
+            return Err(VmError::new("only little-endian ELF64 files are supported"));
+        }
+        if header.e_type(LE) != elf::ET_CORE {
+            return Err(VmError::new("not an ELF core file"));
+        }
+        if header.e_machine(LE) != elf::EM_X86_64 {
+            return Err(VmError::new("unsupported architecture"));
+        }
+
+        if header.e_shentsize(LE) as usize != size_of::<SectionHeader64>() {
+            return Err(VmError::new("invalid section header size"));
+        }
+        let shnum = header.e_shnum(LE) as usize;
+        let sections = read_at(
+            &file,
+            header.e_shoff(LE),
+            shnum * size_of::<SectionHeader64>(),
+        )?;
+        let (sections, _) = object::pod::slice_from_bytes::<SectionHeader64>(&sections, shnum)
+            .map_err(|()| VmError::new("failed to read section headers"))?;
+
+        let strtab = sections
+            .get(header.e_shstrndx(LE) as usize)
+            .context("missing section names")?;
+        let strtab = read_at(&file, strtab.sh_offset(LE), strtab.sh_size(LE) as usize)?;
+        let find_section = |name: &[u8]| {
+            sections.iter().find(|section| {
+                let start = section.sh_name(LE) as usize;
+                strtab
+                    .get(start..)
+                    .and_then(|names| names.split(|&c| c == 0).next())
+                    == Some(name)
+            })
+        };
+        let read_section = |name: &str| -> VmResult<Vec<u8>> {
+            let section =
+                find_section(name.as_bytes()).with_context(|| format!("missing {name} section"))?;
+            read_at(&file, section.sh_offset(LE), section.sh_size(LE) as usize)
+                .with_context(|| format!("failed to read {name} section"))
+        };
+
+        // Read the dump header from Xen notes
+        let notes = read_section(".note.Xen")?;
+        let mut notes = object::read::elf::NoteIterator::<FileHeader64>::new(LE, 8, &notes)
+            .map_err(VmError::new)?;
+        let mut dump_header = None;
+        while let Some(note) = notes.next().map_err(VmError::new)? {
+            if note.name() == b"Xen" && note.n_type(LE) == XEN_ELFNOTE_DUMPCORE_HEADER {
+                dump_header = Some(read_note::<DumpcoreHeader>(note.desc(), 0));
+            }
+        }
+        let dump_header = dump_header.context("missing Xen dump header")?;
+
+        match dump_header.magic {
+            XC_CORE_MAGIC_HVM => (),
+            XC_CORE_MAGIC => return Err(VmError::new("PV guests are not supported")),
+            _ => return Err(VmError::new("invalid Xen dump header")),
+        }
+        let page_size = dump_header.page_size;
+        if !page_size.is_power_of_two() {
+            return Err(VmError::new("invalid page size"));
+        }
+
+        // Build the memory map
+        let pfns = read_section(".xen_pfn")?;
+        let pfns: Vec<u64> = pfns
+            .chunks_exact(8)
+            .map(|pfn| u64::from_le_bytes(pfn.try_into().unwrap()))
+            .take(dump_header.nr_pages as usize)
+            .collect();
+        let pages = find_section(b".xen_pages").context("missing .xen_pages section")?;
+        if (pfns.len() as u64) * page_size > pages.sh_size(LE) {
+            return Err(VmError::new(".xen_pages section is too small"));
+        }
+        let (mappings, remap_at) = build_mappings(&pfns, page_size, pages.sh_offset(LE));
+        log::debug!(
+            "Found {} pages in {} memory ranges",
+            pfns.len(),
+            mappings.len()
+        );
+
+        // Read vCPUs contexts
+        let contexts = read_section(".xen_prstatus")?;
+        let nr_vcpus = dump_header.nr_vcpus as usize;
+        if contexts.len() < nr_vcpus * context::SIZE {
+            return Err(VmError::new(".xen_prstatus section is too small"));
+        }
+        let vcpus = contexts
+            .chunks_exact(context::SIZE)
+            .take(nr_vcpus)
+            .map(read_vcpu)
+            .collect();
+
+        let size = file.metadata()?.len();
+        let mem = vmc::mem::MemRemap::new(vmc::mem::File::new(file, 0, size), mappings, remap_at);
+
+        Ok(Self {
+            vcpus: Vcpus::X86_64(vcpus),
+            mem,
+        })
+    }
+}
+
+impl vmc::Memory for XenCore {
+    #[inline]
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    #[inline]
+    fn is_valid(&self, addr: PhysicalAddress, size: usize) -> bool {
+        self.mem.is_valid(addr, size)
+    }
+
+    #[inline]
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> vmc::MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+delegate_vcpus!(XenCore);
+
+impl vmc::Backend for XenCore {}
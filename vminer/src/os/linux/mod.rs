@@ -4,7 +4,7 @@ mod profile;
 use super::pointer::{Context, HasLayout, KernelSpace, Pointer};
 use alloc::{string::String, vec::Vec};
 use core::{fmt, ops::ControlFlow};
-use vmc::{Os, PhysicalAddress, ResultExt, VirtualAddress, VmError, VmResult};
+use vmc::{Architecture, Os, PhysicalAddress, ResultExt, VirtualAddress, VmError, VmResult};
 
 pub use profile::Profile;
 
@@ -134,6 +134,18 @@ impl<B: vmc::Backend> Linux<B> {
         super::OsBuilder::new().with_symbols(symbols).build(backend)
     }
 
+    #[inline]
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Gives mutable access to the backend, eg to restore the state of an
+    /// overlay.
+    #[inline]
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
     fn read_kernel_value<T: bytemuck::Pod>(&self, addr: VirtualAddress) -> VmResult<T> {
         let mut value = bytemuck::Zeroable::zeroed();
         self.read_virtual_memory(self.kpgd, addr, bytemuck::bytes_of_mut(&mut value))?;
@@ -150,6 +162,44 @@ impl<B: vmc::Backend> Linux<B> {
         self.read_kernel_value(per_cpu_offset + 8 * vcpu.0 as u64)
     }
 
+    /// Returns the range of the "direct map", where the kernel maps all
+    /// physical memory.
+    ///
+    /// On aarch64, this assumes the layout of Linux 5.4 and later.
+    pub fn direct_map(&self) -> VmResult<(VirtualAddress, VirtualAddress)> {
+        let symbols = self.profile.syms.require_module("System.map")?;
+
+        match self.backend.arch().into_runtime() {
+            vmc::arch::RuntimeArchitecture::X86_64(_) => {
+                // This is only a variable when the layout is randomized
+                let start = match symbols.get_address("page_offset_base") {
+                    Some(addr) => self.read_kernel_value(addr + self.kaslr)?,
+                    None => VirtualAddress(0xffff_8880_0000_0000),
+                };
+                let size = self
+                    .backend
+                    .memory_mappings()
+                    .iter()
+                    .map(|mapping| mapping.end.0)
+                    .max()
+                    .unwrap_or(0);
+                Ok((start, start + size))
+            }
+            vmc::arch::RuntimeArchitecture::Aarch64(_) => {
+                let va_bits = match symbols.get_address("vabits_actual") {
+                    Some(addr) => self.read_kernel_value(addr + self.kaslr)?,
+                    None => 48u64,
+                };
+                if !(36..=52).contains(&va_bits) {
+                    return Err(VmError::new(format!("invalid VA_BITS: {va_bits}")));
+                }
+                // The linear map covers the lower half of the kernel space
+                let start = VirtualAddress(u64::MAX << va_bits);
+                Ok((start, start + (1 << (va_bits - 1))))
+            }
+        }
+    }
+
     fn process_mm(&self, proc: vmc::Process) -> VmResult<Option<Pointer<profile::MmStruct, Self>>> {
         let proc = self.pointer_of(proc);
         let mut mm = proc.read_pointer_field(|ts| ts.mm)?;
@@ -169,23 +219,47 @@ impl<B: vmc::Backend> Linux<B> {
     }
 }
 
+const BANNER: &[u8] = b"Linux version ";
+
 fn get_banner_addr<B: vmc::Backend>(
     backend: &B,
     mmu_addr: PhysicalAddress,
 ) -> vmc::MemoryAccessResult<Option<VirtualAddress>> {
-    backend.find_in_kernel_memory(mmu_addr, b"Linux version ")
+    backend.find_in_kernel_memory(mmu_addr, BANNER)
+}
+
+/// Finds the kernel PGD and the banner address of a backend without vCPUs,
+/// by looking for a page table that maps the banner.
+fn find_kernel_pgd_without_vcpus<B: vmc::Backend>(
+    backend: &B,
+) -> VmResult<(PhysicalAddress, VirtualAddress)> {
+    backend
+        .find_kernel_pgd_from_content(BANNER)?
+        .context("could not find a page table mapping the banner")
 }
 
 impl<B: vmc::Backend> super::Buildable<B> for Linux<B> {
     fn quick_check(backend: &B) -> Option<super::OsBuilder> {
+        // Without vCPUs, finding the page tables requires to scan the whole
+        // memory, which is left to `build`.
+        if backend.vcpus_count() == 0 {
+            let banners = vmc::mem::find_in_physical_memory(backend, BANNER, 1).ok()?;
+            return (!banners.is_empty()).then(super::OsBuilder::new);
+        }
+
         let kpgd = backend.find_kernel_pgd(true, &[]).ok()?;
         let kaslr = get_banner_addr(backend, kpgd).ok()??;
         Some(super::OsBuilder::new().with_kpgd(kpgd).with_kaslr(kaslr))
     }
 
-    fn build(backend: B, builder: super::OsBuilder) -> VmResult<Self> {
+    fn build(backend: B, mut builder: super::OsBuilder) -> VmResult<Self> {
         let kpgd = match builder.kpgd {
             Some(kpgd) => kpgd,
+            None if backend.vcpus_count() == 0 => {
+                let (kpgd, banner_addr) = find_kernel_pgd_without_vcpus(&backend)?;
+                builder.kaslr.get_or_insert(banner_addr);
+                kpgd
+            }
             None => backend
                 .find_kernel_pgd(true, &[])
                 .context("could not find kernel PGD")?,
@@ -296,6 +370,16 @@ impl<B: vmc::Backend> vmc::Os for Linux<B> {
         Ok(())
     }
 
+    fn write_virtual_memory(
+        &mut self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+        buf: &[u8],
+    ) -> VmResult<()> {
+        self.backend.write_virtual_memory(mmu_addr, addr, buf)?;
+        Ok(())
+    }
+
     fn try_read_virtual_memory(
         &self,
         mmu_addr: PhysicalAddress,
@@ -290,11 +290,38 @@ fn find_kernel<B: vmc::Backend>(
     Ok(None)
 }
 
+/// Reads the PDB id of a kernel whose base address is already known.
+fn kernel_pdb_id<B: vmc::Backend>(
+    backend: &B,
+    kpgd: PhysicalAddress,
+    base_addr: VirtualAddress,
+) -> VmResult<String> {
+    let codeview = pe_get_pdb_guid(base_addr, None, |addr, buf| {
+        vmc::try_read_virtual_memory(addr, buf, |addr, buf| {
+            backend.read_virtual_memory(kpgd, addr, buf)
+        })
+    })?
+    .context("failed to find kernel PDB id")?;
+    Ok(codeview.pdb_id())
+}
+
 impl<B: vmc::Backend> Windows<B> {
     pub fn create(backend: B, symbols: vmc::SymbolsIndexer) -> VmResult<Self> {
         super::os_builder().with_symbols(symbols).build(backend)
     }
 
+    #[inline]
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Gives mutable access to the backend, eg to restore the state of an
+    /// overlay.
+    #[inline]
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
     #[inline]
     fn profile(&self) -> &profile::Profile {
         &self.profile
@@ -387,7 +414,10 @@ impl<B: vmc::Backend> super::Buildable<B> for Windows<B> {
 
         let (pdb_id, base_addr) = match builder.kaslr {
             Some(kaslr) => {
-                let pdb_id = builder.version.context("missing version")?;
+                let pdb_id = match builder.version {
+                    Some(pdb_id) => pdb_id,
+                    None => kernel_pdb_id(&backend, kpgd, kaslr)?,
+                };
                 (pdb_id, kaslr)
             }
             None => find_kernel(&backend, kpgd)
@@ -511,6 +541,16 @@ impl<B: vmc::Backend> vmc::Os for Windows<B> {
         Ok(())
     }
 
+    fn write_virtual_memory(
+        &mut self,
+        mmu_addr: PhysicalAddress,
+        addr: VirtualAddress,
+        buf: &[u8],
+    ) -> VmResult<()> {
+        self.backend.write_virtual_memory(mmu_addr, addr, buf)?;
+        Ok(())
+    }
+
     fn read_process_memory(
         &self,
         proc: vmc::Process,
@@ -0,0 +1,246 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+use vminer::backends::gdb::Gdb;
+use vminer_core::{self as vmc, HasVcpus, Memory, PhysicalAddress, VcpuId, arch::runtime};
+
+const CORE_REGISTERS: &[(&str, usize)] = &[
+    ("rax", 64),
+    ("rbx", 64),
+    ("rcx", 64),
+    ("rdx", 64),
+    ("rsi", 64),
+    ("rdi", 64),
+    ("rbp", 64),
+    ("rsp", 64),
+    ("r8", 64),
+    ("r9", 64),
+    ("r10", 64),
+    ("r11", 64),
+    ("r12", 64),
+    ("r13", 64),
+    ("r14", 64),
+    ("r15", 64),
+    ("rip", 64),
+    ("eflags", 32),
+    ("cs", 32),
+    ("ss", 32),
+    ("ds", 32),
+    ("es", 32),
+    ("fs", 32),
+    ("gs", 32),
+];
+
+/// Registers that are not part of `g` packets, starting at register 40
+const SYSTEM_REGISTERS: &[&str] = &["fs_base", "gs_base", "k_gs_base", "cr0", "cr3", "efer"];
+
+const MTREE: &str = "\
+FlatView #0
+ AS \"memory\", root: system
+ AS \"cpu-memory-0\", root: system
+ Root memory region: system
+  0000000000000000-0000000000000fff (prio 0, ram): pc.ram
+  0000000000001000-0000000000001fff (prio 0, ram): pc.ram @0000000000001000
+  00000000000a0000-00000000000bffff (prio 1, i/o): vga-lowmem
+  0000000000100000-0000000000100fff (prio 0, ram): pc.ram @0000000000002000
+
+FlatView #1
+ AS \"I/O\", root: io
+  0000000000000000-000000000000ffff (prio 0, i/o): io
+";
+
+fn memory_byte(addr: u64) -> Option<u8> {
+    match addr {
+        0..0x2000 => Some(addr as u8 ^ 0x5a),
+        0x100000..0x101000 => Some(0xcc),
+        _ => None,
+    }
+}
+
+fn register_value(thread: u64, regnum: u64) -> u64 {
+    match regnum {
+        // cr3
+        44 => 0x1000 * thread,
+        _ => (thread << 32) | regnum,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+struct Stub {
+    stream: BufReader<TcpStream>,
+    thread: u64,
+}
+
+impl Stub {
+    fn recv(&mut self) -> Option<String> {
+        let mut skipped = Vec::new();
+        self.stream.read_until(b'$', &mut skipped).ok()?;
+        if skipped.last() != Some(&b'$') {
+            return None;
+        }
+
+        let mut packet = Vec::new();
+        self.stream.read_until(b'#', &mut packet).ok()?;
+        packet.pop();
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum).ok()?;
+
+        self.stream.get_mut().write_all(b"+").ok()?;
+        String::from_utf8(packet).ok()
+    }
+
+    fn send(&mut self, packet: &str) {
+        let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${packet}#{checksum:02x}");
+        self.stream.get_mut().write_all(packet.as_bytes()).unwrap();
+
+        let mut ack = [0];
+        self.stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], b'+');
+    }
+
+    fn target_description(annex: &str) -> String {
+        let body = match annex {
+            "target.xml" => String::from(
+                "<architecture>i386:x86-64</architecture>\
+                 <xi:include href=\"core.xml\"/><xi:include href='sys.xml'/>",
+            ),
+            "core.xml" => CORE_REGISTERS
+                .iter()
+                .map(|(name, size)| format!("<reg name=\"{name}\" bitsize=\"{size}\"/>"))
+                .collect(),
+            "sys.xml" => (40..)
+                .zip(SYSTEM_REGISTERS)
+                .map(|(n, name)| format!("<reg name=\"{name}\" bitsize=\"64\" regnum=\"{n}\"/>"))
+                .collect(),
+            _ => return String::from("E00"),
+        };
+        format!("l<?xml version=\"1.0\"?><target version=\"1.0\">{body}</target>")
+    }
+
+    fn handle(&mut self, packet: &str) {
+        let reply = if packet.starts_with("qSupported") {
+            String::from("PacketSize=1000;qXfer:features:read+")
+        } else if packet == "?" {
+            String::from("S05")
+        } else if let Some(rest) = packet.strip_prefix("qXfer:features:read:") {
+            let (annex, range) = rest.split_once(':').unwrap();
+            assert!(range.starts_with("0,"));
+            Self::target_description(annex)
+        } else if packet == "qfThreadInfo" {
+            String::from("m1,2")
+        } else if packet == "qsThreadInfo" {
+            String::from("l")
+        } else if let Some(thread) = packet.strip_prefix("Hg") {
+            self.thread = thread.parse().unwrap();
+            String::from("OK")
+        } else if packet == "g" {
+            let mut regs = Vec::new();
+            for (regnum, (_, size)) in (0..).zip(CORE_REGISTERS) {
+                let value = register_value(self.thread, regnum).to_le_bytes();
+                regs.extend_from_slice(&value[..size / 8]);
+            }
+            hex(&regs)
+        } else if let Some(regnum) = packet.strip_prefix('p') {
+            let regnum = u64::from_str_radix(regnum, 16).unwrap();
+            hex(&register_value(self.thread, regnum).to_le_bytes())
+        } else if packet == "Qqemu.PhyMemMode:1" {
+            String::from("OK")
+        } else if packet.starts_with("qRcmd,") {
+            self.send(&format!("O{}", hex(MTREE.as_bytes())));
+            String::from("OK")
+        } else if let Some(args) = packet.strip_prefix('m') {
+            let (addr, len) = args.split_once(',').unwrap();
+            let addr = u64::from_str_radix(addr, 16).unwrap();
+            let len = u64::from_str_radix(len, 16).unwrap();
+            assert!(len <= 0x1000);
+            match (addr..addr + len)
+                .map(memory_byte)
+                .collect::<Option<Vec<_>>>()
+            {
+                Some(bytes) => hex(&bytes),
+                None => String::from("E14"),
+            }
+        } else {
+            String::new()
+        };
+
+        self.send(&reply);
+    }
+}
+
+fn spawn_stub() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut stub = Stub {
+            stream: BufReader::new(stream),
+            thread: 1,
+        };
+        while let Some(packet) = stub.recv() {
+            stub.handle(&packet);
+        }
+    });
+
+    port
+}
+
+#[test]
+fn mock_stub() {
+    let port = spawn_stub();
+    let gdb = Gdb::connect_tcp(("127.0.0.1", port)).unwrap();
+
+    let mappings: Vec<_> = gdb
+        .memory_mappings()
+        .iter()
+        .map(|m| (m.start.0, m.end.0))
+        .collect();
+    assert_eq!(mappings, [(0, 0x2000), (0x100000, 0x101000)]);
+
+    let mut buf = vec![0; 0x1800];
+    gdb.read_physical(PhysicalAddress(0x400), &mut buf).unwrap();
+    assert!(
+        (0x400..0x1c00)
+            .zip(&buf)
+            .all(|(addr, &b)| Some(b) == memory_byte(addr))
+    );
+    assert!(
+        gdb.read_physical(PhysicalAddress(0x1ff0), &mut buf[..0x20])
+            .is_err()
+    );
+
+    assert_eq!(gdb.vcpus_count(), 2);
+
+    let runtime::Registers::X86_64(regs) = gdb.registers(VcpuId(1)).unwrap() else {
+        panic!("unexpected architecture");
+    };
+    assert_eq!(regs.rax, 2 << 32);
+    assert_eq!(regs.rbp, (2 << 32) | 6);
+    assert_eq!(regs.rip, (2 << 32) | 16);
+    assert_eq!(regs.rflags, 17);
+
+    let runtime::SpecialRegisters::X86_64(sregs) = gdb.special_registers(VcpuId(1)).unwrap() else {
+        panic!("unexpected architecture");
+    };
+    assert_eq!(sregs.cs.selector, 18);
+    assert_eq!(sregs.gs.base, (2 << 32) | 41);
+    assert_eq!(sregs.cr3, 0x2000);
+    assert_eq!(sregs.cr4, 0);
+    assert_eq!(sregs.efer, (2 << 32) | 45);
+
+    let runtime::OtherRegisters::X86_64(oregs) = gdb.other_registers(VcpuId(0)).unwrap() else {
+        panic!("unexpected architecture");
+    };
+    assert_eq!(oregs.gs_kernel_base, (1 << 32) | 42);
+
+    assert_eq!(vmc::HasVcpus::pgd(&gdb, VcpuId(0)).unwrap().0, 0x1000);
+}
@@ -0,0 +1,102 @@
+use std::fs;
+
+use vminer::backends::kdump::Kdump;
+use vminer_core::{Memory, PhysicalAddress};
+
+const BLOCK_SIZE: usize = 0x1000;
+
+const DUMP_DH_COMPRESSED_LZO: u32 = 0x2;
+
+/// A LZO stream of 0x1000 times the byte 'a'
+const LZO_PAGE: &[u8] = b"\x12a\x20\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\xed\0\0\x11\0\0";
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Builds a diskdump file with 8 pages, where pages 4 and 5 are not RAM and
+/// pages 1 and 7 are excluded from the dump.
+fn build_dump(page_3: &[u8]) -> Vec<u8> {
+    let mut file = vec![0; 5 * BLOCK_SIZE];
+
+    // `struct disk_dump_header`
+    file[..8].copy_from_slice(b"KDUMP   ");
+    put_u32(&mut file, 8, 1);
+    file[12 + 4 * 65..][..6].copy_from_slice(b"x86_64");
+    put_u32(&mut file, 428, BLOCK_SIZE as u32);
+    put_u32(&mut file, 432, 1);
+    put_u32(&mut file, 436, 2);
+    put_u32(&mut file, 440, 8);
+
+    // The sub header is in block 1, and bitmaps in blocks 2 and 3
+    file[2 * BLOCK_SIZE] = 0b1100_1111;
+    file[3 * BLOCK_SIZE] = 0b0100_1101;
+
+    // Page descriptors are in block 4, followed by page data
+    let pages: [(&[u8], u32); 4] = [
+        (&[0x11; BLOCK_SIZE], 0),
+        (&[0x22; BLOCK_SIZE], 0),
+        (page_3, DUMP_DH_COMPRESSED_LZO),
+        (LZO_PAGE, DUMP_DH_COMPRESSED_LZO),
+    ];
+    for (i, (data, flags)) in pages.into_iter().enumerate() {
+        let offset = file.len() as u64;
+        let desc = &mut file[4 * BLOCK_SIZE + 24 * i..][..24];
+        desc[..8].copy_from_slice(&offset.to_le_bytes());
+        put_u32(desc, 8, data.len() as u32);
+        put_u32(desc, 12, flags);
+        file.extend_from_slice(data);
+    }
+
+    file
+}
+
+fn read_dump(name: &str, content: &[u8]) -> Kdump {
+    let path = std::env::temp_dir().join(format!("vminer-{name}-{}", std::process::id()));
+    fs::write(&path, content).unwrap();
+    let dump = Kdump::read(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    dump
+}
+
+#[test]
+fn synthetic_dump() {
+    let dump = read_dump("kdump", &build_dump(LZO_PAGE));
+
+    let mappings: Vec<_> = dump
+        .memory_mappings()
+        .iter()
+        .map(|mapping| (mapping.start.0, mapping.end.0))
+        .collect();
+    assert_eq!(mappings, [(0, 0x4000), (0x6000, 0x8000)]);
+
+    let mut buf = [0; 4];
+    for (addr, expected) in [(0, 0x11), (0x2ff0, 0x22), (0x3000, b'a'), (0x6ffc, b'a')] {
+        dump.read_physical(PhysicalAddress(addr), &mut buf).unwrap();
+        assert_eq!(buf, [expected; 4], "at {addr:#x}");
+    }
+
+    // A read that spans two pages
+    dump.read_physical(PhysicalAddress(0xffe), &mut buf)
+        .unwrap_err();
+    dump.read_physical(PhysicalAddress(0x2ffe), &mut buf)
+        .unwrap();
+    assert_eq!(buf, [0x22, 0x22, b'a', b'a']);
+
+    // Excluded pages and pages past the end of memory
+    for addr in [0x1000, 0x4000, 0x7000, 0x100000] {
+        dump.read_physical(PhysicalAddress(addr), &mut buf)
+            .unwrap_err();
+    }
+}
+
+#[test]
+fn corrupt_page() {
+    let dump = read_dump("kdump-corrupt", &build_dump(&LZO_PAGE[..10]));
+
+    let mut buf = [0; 4];
+    dump.read_physical(PhysicalAddress(0x3000), &mut buf)
+        .unwrap_err();
+    dump.read_physical(PhysicalAddress(0x6000), &mut buf)
+        .unwrap();
+}
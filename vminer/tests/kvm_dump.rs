@@ -0,0 +1,210 @@
+use std::fs;
+
+use vminer::backends::kvm_dump::{DumbDump, DumpWriter, Metadata, OsKind};
+use vminer_core::{
+    self as vmc, HasVcpus, Memory, PhysicalAddress, VcpuId, VirtualAddress,
+    arch::{RuntimeArchitecture, runtime, x86_64},
+    mem::{MemRemap, MemoryMap, RawMemory, clamp_to_mappings},
+};
+
+/// A guest with two memory mappings, the second one ending with half a page.
+struct Guest {
+    mem: MemRemap<RawMemory<Vec<u8>>>,
+}
+
+impl Guest {
+    fn new(low: Vec<u8>, high: Vec<u8>) -> Self {
+        let mappings = vec![
+            MemoryMap {
+                start: PhysicalAddress(0),
+                end: PhysicalAddress(low.len() as u64),
+            },
+            MemoryMap {
+                start: PhysicalAddress(0x10000),
+                end: PhysicalAddress(0x10000 + high.len() as u64),
+            },
+        ];
+        let remap_at = vec![PhysicalAddress(0), PhysicalAddress(low.len() as u64)];
+
+        let mem = [low, high].concat();
+        Self {
+            mem: MemRemap::new(RawMemory::new(mem), mappings, remap_at),
+        }
+    }
+}
+
+impl vmc::Memory for Guest {
+    fn memory_mappings(&self) -> &[MemoryMap] {
+        self.mem.memory_mappings()
+    }
+
+    fn read_physical(&self, addr: PhysicalAddress, buf: &mut [u8]) -> vmc::MemoryAccessResult<()> {
+        self.mem.read_physical(addr, buf)
+    }
+}
+
+impl vmc::HasVcpus for Guest {
+    type Arch = RuntimeArchitecture;
+
+    fn arch(&self) -> Self::Arch {
+        RuntimeArchitecture::X86_64(vmc::arch::X86_64)
+    }
+
+    fn vcpus_count(&self) -> usize {
+        1
+    }
+
+    fn registers(&self, _vcpu: VcpuId) -> vmc::VcpuResult<runtime::Registers> {
+        let mut registers: x86_64::Registers = bytemuck::Zeroable::zeroed();
+        registers.rip = 0xffffffff81000000;
+        Ok(runtime::Registers::X86_64(registers))
+    }
+
+    fn special_registers(&self, _vcpu: VcpuId) -> vmc::VcpuResult<runtime::SpecialRegisters> {
+        Ok(runtime::SpecialRegisters::X86_64(
+            bytemuck::Zeroable::zeroed(),
+        ))
+    }
+
+    fn other_registers(&self, _vcpu: VcpuId) -> vmc::VcpuResult<runtime::OtherRegisters> {
+        Ok(runtime::OtherRegisters::X86_64(bytemuck::Zeroable::zeroed()))
+    }
+}
+
+impl vmc::Backend for Guest {}
+
+fn random_page(mut seed: u32) -> Vec<u8> {
+    (0..0x1000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect()
+}
+
+fn range(start: u64, end: u64) -> MemoryMap {
+    MemoryMap {
+        start: PhysicalAddress(start),
+        end: PhysicalAddress(end),
+    }
+}
+
+fn read_all(dump: &impl Memory, mapping: &MemoryMap) -> Vec<u8> {
+    let mut buf = vec![0; (mapping.end - mapping.start) as usize];
+    dump.read_physical(mapping.start, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn v2_round_trip() {
+    let pattern: Vec<u8> = (0..0x1000).map(|i| (i % 251) as u8).collect();
+    let random = random_page(42);
+
+    // A zero page, and pages stored twice
+    let low = [
+        vec![0; 0x1000],
+        pattern.clone(),
+        random.clone(),
+        random.clone(),
+        pattern.clone(),
+    ]
+    .concat();
+    let high = random_page(1337)[..0x800].to_vec();
+    let guest = Guest::new(low.clone(), high.clone());
+
+    let metadata = Metadata {
+        os: Some(OsKind::Linux),
+        kpgd: Some(PhysicalAddress(0x1000)),
+        kaslr: Some(VirtualAddress(0xffffffff82000000)),
+        version: Some("Linux version 6.1.0".into()),
+        timestamp: Some(0x0123_4567_89ab_cdef),
+    };
+
+    let path = std::env::temp_dir().join(format!("vminer-dump-test-{}", std::process::id()));
+    DumpWriter::new(&guest)
+        .with_metadata(metadata.clone())
+        .write(&path)
+        .unwrap();
+
+    // Only one copy of the random page is stored
+    assert!(fs::metadata(&path).unwrap().len() < 0x2000);
+
+    let dump = DumbDump::read(&path).unwrap();
+    let mappings = dump.memory_mappings().to_vec();
+    assert_eq!(mappings.len(), 2);
+    assert_eq!(read_all(&dump, &mappings[0]), low);
+    assert_eq!(read_all(&dump, &mappings[1]), high);
+
+    let mut buf = [0; 4];
+    dump.read_physical(PhysicalAddress(0x10802), &mut buf[..2])
+        .unwrap_err();
+    dump.read_physical(PhysicalAddress(0x107fe), &mut buf[..2])
+        .unwrap();
+    assert_eq!(buf[..2], high[0x7fe..]);
+    dump.read_physical(PhysicalAddress(0x1ffe), &mut buf)
+        .unwrap();
+    assert_eq!(buf, low[0x1ffe..0x2002]);
+
+    assert_eq!(dump.vcpus_count(), 1);
+    assert_eq!(
+        dump.instruction_pointer(VcpuId(0)).unwrap(),
+        VirtualAddress(0xffffffff81000000)
+    );
+    assert_eq!(dump.metadata(), &metadata);
+    drop(dump);
+
+    // Readers skip tags they don't know
+    let mut file = fs::read(&path).unwrap();
+    let mut entry = vec![5, 0, 0, 0, 8, 0, 0, 0];
+    entry.extend_from_slice(&metadata.timestamp.unwrap().to_le_bytes());
+    let pos = file
+        .windows(entry.len())
+        .position(|window| window == entry)
+        .unwrap();
+    file[pos] = 0x7f;
+    fs::write(&path, file).unwrap();
+
+    let dump = DumbDump::read(&path).unwrap();
+    assert_eq!(
+        dump.metadata(),
+        &Metadata {
+            timestamp: None,
+            ..metadata
+        }
+    );
+    assert_eq!(read_all(&dump, &mappings[0]), low);
+
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn sparse_frames() {
+    let mappings = [range(0x10000, 0x20000), range(0, 0x8000)];
+
+    let frames = vec![
+        // Crosses the end of the first mapping and the gap
+        range(0x7000, 0x12000),
+        // Unsorted and overlapping
+        range(0x3000, 0x5000),
+        range(0x1000, 0x2000),
+        range(0x4000, 0x6000),
+        range(0x2000, 0x3000),
+        // Contained in a previous frame
+        range(0x11000, 0x11800),
+        // Ends exactly at the end of memory, then past it
+        range(0x1f000, 0x20000),
+        range(0x1f800, 0x30000),
+        range(0x40000, 0x41000),
+    ];
+
+    assert_eq!(
+        clamp_to_mappings(frames, &mappings),
+        [
+            range(0x1000, 0x6000),
+            range(0x7000, 0x8000),
+            range(0x10000, 0x12000),
+            range(0x1f000, 0x20000),
+        ]
+    );
+    assert!(clamp_to_mappings(Vec::new(), &mappings).is_empty());
+}
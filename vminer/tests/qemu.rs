@@ -0,0 +1,187 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    thread,
+};
+
+use serde_json::{Value, json};
+use vminer::backends::qemu::Qemu;
+use vminer_core::{HasVcpus, Memory, PhysicalAddress, VcpuId, arch::runtime};
+
+const MTREE: &str = "\
+FlatView #0
+ AS \"memory\", root: system
+ AS \"cpu-memory-0\", root: system
+ Root memory region: system
+  0000000000000000-000000000009ffff (prio 0, ram): mem KVM
+  00000000000a0000-00000000000bffff (prio 1, i/o): vga-lowmem
+  00000000000c0000-00000000000dffff (prio 1, rom): pc.rom
+  00000000000e0000-00000000000fffff (prio 0, ram): mem @00000000000e0000 KVM
+  0000000000100000-000000000017ffff (prio 0, ram): mem @0000000000100000 KVM
+  00000000fffc0000-00000000ffffffff (prio 0, romd): pc.bios KVM
+  0000000100000000-000000010000ffff (prio 0, ram): mem @0000000000180000 KVM
+
+FlatView #1
+ AS \"I/O\", root: io
+  0000000000000000-000000000000ffff (prio 0, i/o): io
+";
+
+const REGISTERS: &str = "\n\
+CPU#0
+RAX=0000000000000001 RBX=0000000000000002 RCX=0000000000000003 RDX=0000000000000004
+RSI=0000000000000005 RDI=0000000000000006 RBP=0000000000000007 RSP=ffffc90000013e80
+R8 =0000000000000008 R9 =0000000000000009 R10=000000000000000a R11=000000000000000b
+R12=000000000000000c R13=000000000000000d R14=000000000000000e R15=000000000000000f
+RIP=ffffffff81e3c5fe RFL=00000246 [---Z-P-] CPL=0 II=0 A20=1 SMM=0 HLT=1
+ES =0000 0000000000000000 ffffffff 00c00000
+CS =0010 0000000000000000 ffffffff 00a09b00 DPL=0 CS64 [-RA]
+SS =0018 0000000000000000 ffffffff 00c09300 DPL=0 DS   [-WA]
+DS =0000 0000000000000000 ffffffff 00c00000
+FS =0000 00007f1234567000 ffffffff 00c00000
+GS =0000 ffff88807dc00000 ffffffff 00c00000
+LDT=0000 0000000000000000 ffffffff 00c00000
+TR =0040 fffffe0000003000 00004087 00008b00 DPL=0 TSS64-busy
+GDT=     fffffe0000001000 0000007f
+IDT=     fffffe0000000000 00000fff
+CR0=80050033 CR2=00007f1234560000 CR3=0000000010c8a000 CR4=00350ef0
+DR0=0000000000000000 DR1=0000000000000000 DR2=0000000000000000 DR3=0000000000000000
+DR6=00000000ffff0ff0 DR7=0000000000000400
+EFER=0000000000000d01
+
+CPU#1
+RAX=0000000000000000 RBX=0000000000000000 RCX=0000000000000000 RDX=0000000000000000
+RIP=ffffffff81e3c5fe RFL=00000246 [---Z-P-] CPL=0 II=0 A20=1 SMM=0 HLT=1
+GS =0000 ffff88807dd00000 ffffffff 00c00000
+CR0=80050033 CR2=0000000000000000 CR3=0000000002a0c000 CR4=00350ef0
+EFER=0000000000000d01
+";
+
+fn send(stream: &mut BufReader<UnixStream>, message: Value) {
+    writeln!(stream.get_mut(), "{message}").unwrap();
+}
+
+fn spawn_qmp_server(socket: PathBuf, mem_path: PathBuf) {
+    let listener = UnixListener::bind(&socket).unwrap();
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = BufReader::new(stream);
+        send(
+            &mut stream,
+            json!({ "QMP": { "version": {}, "capabilities": [] } }),
+        );
+
+        let mut line = String::new();
+        while stream.read_line(&mut line).unwrap() != 0 {
+            let request: Value = serde_json::from_str(&line).unwrap();
+            line.clear();
+
+            let result = match request["execute"].as_str().unwrap() {
+                "qmp_capabilities" => json!({}),
+                "query-memdev" => json!([
+                    { "id": "mem", "size": 0x190000, "share": true, "merge": true },
+                    { "id": "private", "size": 0x1000, "share": false, "merge": true },
+                ]),
+                "qom-get" => {
+                    assert_eq!(request["arguments"]["path"], "/objects/mem");
+                    assert_eq!(request["arguments"]["property"], "mem-path");
+                    json!(mem_path.to_str().unwrap())
+                }
+                "query-target" => json!({ "arch": "x86_64" }),
+                "human-monitor-command" => {
+                    // Events may be sent at any time
+                    send(&mut stream, json!({ "event": "RESUME", "data": {} }));
+                    match request["arguments"]["command-line"].as_str().unwrap() {
+                        "info mtree -f" => json!(MTREE),
+                        "info registers -a" => json!(REGISTERS),
+                        command => panic!("unexpected command: {command}"),
+                    }
+                }
+                _ => {
+                    send(
+                        &mut stream,
+                        json!({ "error": { "class": "CommandNotFound", "desc": "nope" } }),
+                    );
+                    continue;
+                }
+            };
+            send(&mut stream, json!({ "return": result }));
+        }
+    });
+}
+
+#[test]
+fn fake_qmp_server() {
+    let dir = std::env::temp_dir().join(format!("vminer-qemu-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+
+    let mem_path = dir.join("mem");
+    let mem: Vec<u8> = (0..0x190000u32).map(|i| (i / 0x1000) as u8).collect();
+    fs::write(&mem_path, &mem).unwrap();
+
+    let socket = dir.join("qmp.sock");
+    spawn_qmp_server(socket.clone(), mem_path);
+    let qemu = Qemu::connect(&socket).unwrap();
+
+    let mappings: Vec<_> = qemu
+        .memory_mappings()
+        .iter()
+        .map(|m| (m.start.0, m.end.0))
+        .collect();
+    assert_eq!(
+        mappings,
+        [
+            (0, 0xa0000),
+            (0xe0000, 0x180000),
+            (0x100000000, 0x100010000)
+        ]
+    );
+
+    let mut buf = [0; 4];
+    qemu.read_physical(PhysicalAddress(0x100000ffe), &mut buf)
+        .unwrap();
+    assert_eq!(buf, [0x80, 0x80, 0x81, 0x81]);
+    qemu.read_physical(PhysicalAddress(0x17fffe), &mut buf[..2])
+        .unwrap();
+    assert_eq!(buf[..2], [0x7f, 0x7f]);
+    assert!(
+        qemu.read_physical(PhysicalAddress(0xa0000), &mut buf)
+            .is_err()
+    );
+
+    assert_eq!(qemu.vcpus_count(), 2);
+
+    let runtime::Registers::X86_64(regs) = qemu.registers(VcpuId(0)).unwrap() else {
+        panic!("unexpected architecture");
+    };
+    assert_eq!(regs.rax, 1);
+    assert_eq!(regs.r8, 8);
+    assert_eq!(regs.rsp, 0xffffc90000013e80);
+    assert_eq!(regs.rip, 0xffffffff81e3c5fe);
+    assert_eq!(regs.rflags, 0x246);
+
+    let runtime::SpecialRegisters::X86_64(sregs) = qemu.special_registers(VcpuId(0)).unwrap()
+    else {
+        panic!("unexpected architecture");
+    };
+    assert_eq!(sregs.cs.selector, 0x10);
+    assert_eq!((sregs.cs.l, sregs.cs.present, sregs.cs.type_), (1, 1, 0xb));
+    assert_eq!(sregs.ds.unusable, 1);
+    assert_eq!(sregs.gs.base, 0xffff88807dc00000);
+    assert_eq!(sregs.gdt.base, 0xfffffe0000001000);
+    assert_eq!(sregs.idt.limit, 0xfff);
+    assert_eq!(sregs.cr3, 0x10c8a000);
+    assert_eq!(sregs.efer, 0xd01);
+
+    let runtime::SpecialRegisters::X86_64(sregs) = qemu.special_registers(VcpuId(1)).unwrap()
+    else {
+        panic!("unexpected architecture");
+    };
+    assert_eq!(sregs.gs.base, 0xffff88807dd00000);
+    assert_eq!(sregs.cr3, 0x2a0c000);
+
+    fs::remove_dir_all(&dir).unwrap();
+}